@@ -1,10 +1,24 @@
-use clap::{Parser, Subcommand};
-use fmd::KeyExpansion;
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand};
 use fmd::fmd2_compact::{CompactSecretKey, MultiFmd2CompactScheme};
+use fmd::{FmdKeyGen, FmdSecretKey, KeyExpansion};
+use kassandra_client::checkpoint::{fetch_checkpoints, find_disagreements};
 use kassandra_client::config::{Config, hash_key};
+use kassandra_client::error::Error;
+use kassandra_client::keystore::Keystore;
+use kassandra_client::lease::renew;
 use kassandra_client::query::query_fmd_key;
-use kassandra_client::register_fmd_key;
-use kassandra_client::{GAMMA, encryption_key, get_host_uuid, init_logging};
+use kassandra_client::services::{
+    rebalance, register_pending, remove_service, repin_service, replace_service,
+};
+use kassandra_client::update::{KeyChanges, update_key};
+use kassandra_client::{Handshake, register_fmd_key};
+use kassandra_client::{check_host_uuid, encryption_key, get_host_uuid, init_logging};
+use rand_core::OsRng;
+use shared::lease::Lease;
+use shared::ratls::{DEFAULT_GAMMA, FmdParams};
+use zeroize::Zeroizing;
 
 #[derive(Parser)]
 #[command(version, about, long_about=None)]
@@ -15,29 +29,81 @@ struct Cli {
         help = "Path the directory storing client related files"
     )]
     base_dir: String,
+    #[arg(
+        long,
+        value_name = "Integer",
+        help = "The number of subkeys of the FMD master key. Defaults to 20."
+    )]
+    gamma: Option<usize>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        global = true,
+        help = "Read the keystore passphrase from this file instead of stdin"
+    )]
+    passphrase_file: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "VAR",
+        global = true,
+        conflicts_with = "passphrase_file",
+        help = "Read the keystore passphrase from this environment variable instead of stdin"
+    )]
+    passphrase_env: Option<String>,
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    #[command(about = "Generate a new FMD secret key and store it in the keystore")]
+    NewAccount {
+        #[arg(short, long, help = "Alias to store the key under")]
+        alias: String,
+    },
+    #[command(about = "Store a JSON encoded FMD secret key in the keystore")]
+    ImportAccount {
+        #[arg(short, long, help = "Alias to store the key under")]
+        alias: String,
+        #[arg(
+            long,
+            value_name = "PATH",
+            help = "Read the key from this file instead of stdin"
+        )]
+        key_file: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "VAR",
+            conflicts_with = "key_file",
+            help = "Read the key from this environment variable instead of stdin"
+        )]
+        key_env: Option<String>,
+    },
+    #[command(about = "List the aliases of the keys in the keystore")]
+    ListAccounts,
+    #[command(about = "Remove a key from the keystore")]
+    RemoveAccount {
+        #[arg(short, long, help = "Alias of the FMD secret key in the keystore")]
+        alias: String,
+    },
     #[command(about = "Register a fuzzy message detection key with configured Kassandra services")]
     RegisterKey {
-        #[arg(short, long, help = "JSON encoded FMD secret key")]
-        key: String,
+        #[arg(short, long, help = "Alias of the FMD secret key in the keystore")]
+        alias: String,
+        #[command(flatten)]
+        registration: RegistrationArgs,
         #[arg(
             long,
-            help = "A block height to start detecting after",
-            value_name = "Integer"
+            help = "Replace the detection key, birthday and lease of an existing registration"
         )]
-        birthday: Option<u64>,
+        replace: bool,
     },
     #[command(
         about = "Add a Kassandra service instance which a fuzzy message detection key will be registered to."
     )]
     AddService {
-        #[arg(short, long, help = "JSON encoded FMD secret key")]
-        key: String,
+        #[arg(short, long, help = "Alias of the FMD secret key in the keystore")]
+        alias: String,
         #[arg(
             short,
             long,
@@ -46,65 +112,516 @@ enum Commands {
         )]
         url: String,
     },
+    #[command(
+        about = "Deregister a fuzzy message detection key from a service and remove it from the config file"
+    )]
+    RemoveService {
+        #[arg(short, long, help = "Alias of the FMD secret key in the keystore")]
+        alias: String,
+        #[arg(
+            short,
+            long,
+            value_name = "URL",
+            help = "URL of the Kassandra service provider to remove"
+        )]
+        url: String,
+        #[arg(long, help = "Remove the service even if deregistering fails")]
+        force: bool,
+    },
+    #[command(
+        about = "Replace a service with a new one holding the same detection key, and register with it"
+    )]
+    ReplaceService {
+        #[arg(short, long, help = "Alias of the FMD secret key in the keystore")]
+        alias: String,
+        #[arg(
+            short,
+            long,
+            value_name = "URL",
+            help = "URL of the Kassandra service provider to replace"
+        )]
+        url: String,
+        #[arg(
+            long,
+            value_name = "URL",
+            help = "URL of the Kassandra service provider to replace it with"
+        )]
+        new_url: String,
+        #[arg(long, help = "Replace the service even if deregistering fails")]
+        force: bool,
+        #[command(flatten)]
+        registration: RegistrationArgs,
+    },
+    #[command(
+        about = "Pin the new UUID of a reinstalled service's host, and register with it again"
+    )]
+    RepinService {
+        #[arg(short, long, help = "Alias of the FMD secret key in the keystore")]
+        alias: String,
+        #[arg(
+            short,
+            long,
+            value_name = "URL",
+            help = "URL of the Kassandra service provider whose host was reinstalled"
+        )]
+        url: String,
+        #[command(flatten)]
+        registration: RegistrationArgs,
+    },
+    #[command(
+        about = "Redistribute detection keys among the configured services and register with any new ones"
+    )]
+    Rebalance {
+        #[arg(short, long, help = "Alias of the FMD secret key in the keystore")]
+        alias: String,
+        #[command(flatten)]
+        registration: RegistrationArgs,
+    },
     #[command(
         about = "Request the indices of MASP transactions that should be trial-decrypted by the provided key"
     )]
     QueryIndices {
-        #[arg(short, long, help = "JSON encoded FMD secret key")]
-        key: String,
+        #[arg(short, long, help = "Alias of the FMD secret key in the keystore")]
+        alias: String,
+    },
+    #[command(
+        about = "Update the registrations of a fuzzy message detection key without registering it again"
+    )]
+    UpdateKey {
+        #[arg(short, long, help = "Alias of the FMD secret key in the keystore")]
+        alias: String,
+        #[arg(
+            long,
+            help = "Replace the encryption key shared with each service. Rotated keys are only stored in the config file and cannot be derived again."
+        )]
+        rotate_enc_key: bool,
+        #[arg(
+            long,
+            value_name = "Integer",
+            help = "Replace the detection keys with ones having this many subkeys, i.e. a false-positive rate of 2^-<Integer>"
+        )]
+        subkeys: Option<usize>,
+        #[arg(
+            long,
+            help = "Discard the indices found so far and detect again from the birthday"
+        )]
+        reset: bool,
+    },
+    #[command(
+        about = "Fetch the checkpoints signed by the enclaves of the services a key is registered with and compare them"
+    )]
+    Checkpoints {
+        #[arg(short, long, help = "Alias of the FMD secret key in the keystore")]
+        alias: String,
+        #[arg(long, value_name = "Integer", help = "The first block height to check")]
+        from: u64,
+        #[arg(long, value_name = "Integer", help = "The last block height to check")]
+        to: u64,
+    },
+    #[command(about = "Renew the lease of a registered fuzzy message detection key")]
+    RenewLease {
+        #[arg(short, long, help = "Alias of the FMD secret key in the keystore")]
+        alias: String,
+        #[arg(
+            long,
+            help = "A block height after which detection stops",
+            value_name = "Integer",
+            required_unless_present = "lease_secs",
+            conflicts_with = "lease_secs"
+        )]
+        lease_height: Option<u64>,
+        #[arg(
+            long,
+            help = "A number of seconds after which detection stops",
+            value_name = "Integer"
+        )]
+        lease_secs: Option<u64>,
     },
 }
 
+/// The arguments for registering a key with services
+#[derive(Args)]
+struct RegistrationArgs {
+    #[arg(
+        long,
+        help = "A block height to start detecting after",
+        value_name = "Integer"
+    )]
+    birthday: Option<u64>,
+    #[arg(
+        long,
+        help = "A block height after which detection stops",
+        value_name = "Integer",
+        conflicts_with = "lease_secs"
+    )]
+    lease_height: Option<u64>,
+    #[arg(
+        long,
+        help = "A number of seconds after which detection stops",
+        value_name = "Integer"
+    )]
+    lease_secs: Option<u64>,
+    #[arg(
+        long,
+        help = "Register over TLS 1.3 with the attestation report in the enclave's certificate"
+    )]
+    tls: bool,
+}
+
+impl RegistrationArgs {
+    fn lease(&self) -> Option<Lease> {
+        lease(self.lease_height, self.lease_secs)
+    }
+
+    fn handshake(&self) -> Handshake {
+        if self.tls {
+            Handshake::Tls
+        } else {
+            Handshake::Bespoke
+        }
+    }
+}
+
+/// Build a lease from the command line arguments, if one was given
+fn lease(height: Option<u64>, secs: Option<u64>) -> Option<Lease> {
+    height.map(Lease::Height).or(secs.map(Lease::Duration))
+}
+
+/// Expand an FMD master secret key into the key detection keys are
+/// extracted from
+fn expand(csk_key: &CompactSecretKey, gamma: usize) -> (FmdSecretKey, FmdParams) {
+    let cpk_key = csk_key.master_public_key();
+    let params = FmdParams {
+        gamma,
+        threshold: cpk_key.threshold(),
+    };
+    let mut scheme = MultiFmd2CompactScheme::new(params.gamma, params.threshold);
+    let (fmd_key, _) = scheme.expand_keypair(csk_key, &cpk_key);
+    (fmd_key, params)
+}
+
+/// Read a secret from a file or an environment variable if given,
+/// otherwise prompt for it on the terminal without echoing it
+fn read_secret(file: Option<&Path>, env: Option<&str>, prompt: &str) -> Result<String, String> {
+    let secret = if let Some(path) = file {
+        std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {e}", path.display()))?
+    } else if let Some(var) = env {
+        std::env::var(var).map_err(|e| format!("Could not read ${var}: {e}"))?
+    } else {
+        rpassword::prompt_password(format!("{prompt}: "))
+            .map_err(|e| format!("Could not read from the terminal: {e}"))?
+    };
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Read the keystore passphrase from where the command line specifies
+fn passphrase(cli: &Cli) -> Zeroizing<String> {
+    match read_secret(
+        cli.passphrase_file.as_deref(),
+        cli.passphrase_env.as_deref(),
+        "Enter the keystore passphrase",
+    ) {
+        Ok(passphrase) => Zeroizing::new(passphrase),
+        Err(e) => {
+            tracing::error!("Error reading the keystore passphrase: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Get the FMD secret key stored under an alias from the keystore
+fn load_key(cli: &Cli, alias: &str) -> CompactSecretKey {
+    let passphrase = passphrase(cli);
+    let keystore = Keystore::load_or_new(&cli.base_dir).unwrap();
+    match keystore.get(alias, &passphrase) {
+        Ok(key) => key,
+        Err(e) => {
+            tracing::error!("{e}");
+            panic!("{e}");
+        }
+    }
+}
+
+/// Load the config and unlock the services of a key
+fn load_config(cli: &Cli, key_hash: &str, csk_key: &CompactSecretKey) -> Config {
+    let mut config = match Config::load_or_new(&cli.base_dir) {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Error getting the associated services from the config file: {e}");
+            panic!("Error getting the associated services from the config file: {e}");
+        }
+    };
+    if let Err(e) = config.unlock(key_hash, csk_key) {
+        tracing::error!("Error getting the associated services from the config file: {e}");
+        panic!("Error getting the associated services from the config file: {e}");
+    }
+    config
+}
+
+/// Store a key in the keystore under a new alias
+fn store_key(cli: &Cli, alias: &str, key: &CompactSecretKey) {
+    let passphrase = passphrase(cli);
+    let mut keystore = Keystore::load_or_new(&cli.base_dir).unwrap();
+    keystore.insert(alias, key, &passphrase).unwrap();
+    keystore.save(&cli.base_dir).unwrap();
+    tracing::info!("Stored key under alias {alias}");
+}
+
 fn main() {
     init_logging();
     let cli = Cli::parse();
+    let gamma = cli.gamma.unwrap_or(DEFAULT_GAMMA);
     match &cli.command {
-        Commands::AddService { key, url } => {
+        Commands::NewAccount { alias } => {
+            let params = FmdParams {
+                gamma,
+                ..FmdParams::default()
+            };
+            let mut scheme = MultiFmd2CompactScheme::new(params.gamma, params.threshold);
+            let (csk_key, _) = scheme.generate_keys(&mut OsRng);
+            store_key(&cli, alias, &csk_key);
+        }
+        Commands::ImportAccount {
+            alias,
+            key_file,
+            key_env,
+        } => {
+            let key = match read_secret(
+                key_file.as_deref(),
+                key_env.as_deref(),
+                "Enter the JSON encoded FMD secret key",
+            ) {
+                Ok(key) => Zeroizing::new(key),
+                Err(e) => {
+                    tracing::error!("Error reading the FMD secret key: {e}");
+                    std::process::exit(1);
+                }
+            };
+            let csk_key: CompactSecretKey = serde_json::from_str(&key).unwrap();
+            store_key(&cli, alias, &csk_key);
+        }
+        Commands::ListAccounts => {
+            let keystore = Keystore::load_or_new(&cli.base_dir).unwrap();
+            for alias in keystore.accounts.keys() {
+                println!("{alias}");
+            }
+        }
+        Commands::RemoveAccount { alias } => {
+            let mut keystore = Keystore::load_or_new(&cli.base_dir).unwrap();
+            if keystore.remove(alias) {
+                keystore.save(&cli.base_dir).unwrap();
+                tracing::info!("Removed key with alias {alias}");
+            } else {
+                tracing::warn!("No key with alias {alias} in the keystore");
+            }
+        }
+        Commands::AddService { alias, url } => {
             tracing::info!("Adding service to the config file...");
             let uuid = get_host_uuid(url).unwrap();
-            let csk_key: CompactSecretKey = serde_json::from_str(key).unwrap();
-            let cpk_key = csk_key.master_public_key();
-            let mut scheme = MultiFmd2CompactScheme::new(GAMMA, 1);
-            let (fmd_key, _) = scheme.expand_keypair(&csk_key, &cpk_key);
+            let csk_key = load_key(&cli, alias);
+            let (fmd_key, _) = expand(&csk_key, gamma);
             let enc_key = encryption_key(&fmd_key, &uuid);
-            let key_hash = hash_key(&csk_key, GAMMA);
-            let mut config = Config::load_or_new(&cli.base_dir).unwrap();
-            config.add_service(key_hash, url, enc_key);
+            let key_hash = hash_key(&csk_key, gamma);
+            let mut config = load_config(&cli, &key_hash, &csk_key);
+            config.add_service(key_hash, url, &uuid, enc_key);
             config.save(&cli.base_dir).unwrap();
         }
-        Commands::RegisterKey { key, birthday } => {
+        Commands::RegisterKey {
+            alias,
+            registration,
+            replace,
+        } => {
             tracing::info!("Registering FMD key...");
-            let config = match Config::load_or_new(&cli.base_dir) {
-                Ok(config) => config,
-                Err(e) => {
-                    tracing::error!(
-                        "Error getting the associated services from the config file: {e}"
-                    );
-                    panic!("Error getting the associated services from the config file: {e}");
-                }
+            let csk_key = load_key(&cli, alias);
+            let key_hash = hash_key(&csk_key, gamma);
+            let mut config = load_config(&cli, &key_hash, &csk_key);
+            let (fmd_key, params) = expand(&csk_key, gamma);
+            let result = register_fmd_key(
+                &mut config,
+                key_hash,
+                &fmd_key,
+                params,
+                registration.birthday,
+                registration.lease(),
+                *replace,
+                registration.handshake(),
+            );
+            config.save(&cli.base_dir).unwrap();
+            result.unwrap();
+        }
+        Commands::RemoveService { alias, url, force } => {
+            tracing::info!("Removing service from the config file...");
+            let csk_key = load_key(&cli, alias);
+            let key_hash = hash_key(&csk_key, gamma);
+            let mut config = load_config(&cli, &key_hash, &csk_key);
+            remove_service(&mut config, &key_hash, url, *force).unwrap();
+            config.save(&cli.base_dir).unwrap();
+            tracing::info!(
+                "Run rebalance to redistribute the detection key of the removed service"
+            );
+        }
+        Commands::ReplaceService {
+            alias,
+            url,
+            new_url,
+            force,
+            registration,
+        } => {
+            tracing::info!("Replacing service...");
+            let uuid = get_host_uuid(new_url).unwrap();
+            let csk_key = load_key(&cli, alias);
+            let key_hash = hash_key(&csk_key, gamma);
+            let mut config = load_config(&cli, &key_hash, &csk_key);
+            let (fmd_key, params) = expand(&csk_key, gamma);
+            let enc_key = encryption_key(&fmd_key, &uuid);
+            replace_service(&mut config, &key_hash, url, new_url, &uuid, enc_key, *force).unwrap();
+            // The replaced service was deregistered from, so it must be
+            // removed from the config even if registering fails
+            let result = register_pending(
+                &mut config,
+                &key_hash,
+                &fmd_key,
+                params,
+                registration.birthday,
+                registration.lease(),
+                registration.handshake(),
+            );
+            config.save(&cli.base_dir).unwrap();
+            result.unwrap();
+        }
+        Commands::RepinService {
+            alias,
+            url,
+            registration,
+        } => {
+            tracing::info!("Pinning the new UUID of the service...");
+            let csk_key = load_key(&cli, alias);
+            let key_hash = hash_key(&csk_key, gamma);
+            let mut config = load_config(&cli, &key_hash, &csk_key);
+            let Some(service) = config
+                .get_services(&key_hash)
+                .into_iter()
+                .find(|s| s.url == *url)
+            else {
+                panic!("{}", Error::UnknownService(url.clone()));
             };
-            let csk_key = serde_json::from_str(key).unwrap();
-            let key_hash = hash_key(&csk_key, GAMMA);
-            let cpk_key = csk_key.master_public_key();
-            let mut scheme = MultiFmd2CompactScheme::new(GAMMA, 1);
-            let (fmd_key, _) = scheme.expand_keypair(&csk_key, &cpk_key);
-            register_fmd_key(&config, key_hash, &fmd_key, *birthday).unwrap();
-        }
-        Commands::QueryIndices { key } => {
-            let csk_key = serde_json::from_str(key).unwrap();
-            let key_hash = hash_key(&csk_key, GAMMA);
-            let config = match Config::load_or_new(&cli.base_dir) {
-                Ok(config) => config,
-                Err(e) => {
-                    tracing::error!(
-                        "Error getting the associated services from the config file: {e}"
-                    );
-                    panic!("Error getting the associated services from the config file: {e}");
+            let uuid = match check_host_uuid(&service) {
+                Ok(_) => {
+                    tracing::info!("The host still reports its pinned UUID, nothing to do");
+                    return;
                 }
+                Err(Error::UuidMismatch { reported, .. }) => reported,
+                Err(e) => panic!("{e}"),
             };
-            let indices = query_fmd_key(&config, &key_hash).unwrap();
+            let (fmd_key, params) = expand(&csk_key, gamma);
+            let enc_key = encryption_key(&fmd_key, &uuid);
+            repin_service(&mut config, &key_hash, url, &uuid, enc_key).unwrap();
+            // The new UUID must be pinned even if registering fails, so
+            // that registering can be retried with register-key
+            let result = register_pending(
+                &mut config,
+                &key_hash,
+                &fmd_key,
+                params,
+                registration.birthday,
+                registration.lease(),
+                registration.handshake(),
+            );
+            config.save(&cli.base_dir).unwrap();
+            result.unwrap();
+        }
+        Commands::Rebalance {
+            alias,
+            registration,
+        } => {
+            tracing::info!("Rebalancing detection keys...");
+            let csk_key = load_key(&cli, alias);
+            let key_hash = hash_key(&csk_key, gamma);
+            let mut config = load_config(&cli, &key_hash, &csk_key);
+            let (fmd_key, params) = expand(&csk_key, gamma);
+            let result = rebalance(&mut config, &key_hash, &fmd_key, params).and_then(|_| {
+                register_pending(
+                    &mut config,
+                    &key_hash,
+                    &fmd_key,
+                    params,
+                    registration.birthday,
+                    registration.lease(),
+                    registration.handshake(),
+                )
+            });
+            // Services updated before any failure must still be persisted
+            config.save(&cli.base_dir).unwrap();
+            result.unwrap();
+        }
+        Commands::QueryIndices { alias } => {
+            let csk_key = load_key(&cli, alias);
+            let key_hash = hash_key(&csk_key, gamma);
+            let mut config = load_config(&cli, &key_hash, &csk_key);
+            let result = query_fmd_key(&mut config, &key_hash);
+            if let Err(Error::UuidMismatch { url, .. }) = &result {
+                tracing::error!(
+                    "If the host of {url} was reinstalled, run repin-service --alias {alias} \
+                     --url {url} to register with it again. Otherwise, the host may be \
+                     misbehaving and should be replaced with replace-service."
+                );
+            }
+            let indices = result.unwrap();
+            config.save(&cli.base_dir).unwrap();
             let result = serde_json::to_string_pretty(&indices).unwrap();
             tracing::info!("{result}");
         }
+        Commands::UpdateKey {
+            alias,
+            rotate_enc_key,
+            subkeys,
+            reset,
+        } => {
+            tracing::info!("Updating FMD key...");
+            let csk_key = load_key(&cli, alias);
+            let key_hash = hash_key(&csk_key, gamma);
+            let mut config = load_config(&cli, &key_hash, &csk_key);
+            let (fmd_key, params) = expand(&csk_key, gamma);
+            let changes = KeyChanges {
+                rotate_enc_key: *rotate_enc_key,
+                subkeys: *subkeys,
+                reset: *reset,
+            };
+            let result = update_key(&mut config, &key_hash, &fmd_key, params, changes);
+            // Keys rotated before any failure must still be persisted
+            config.save(&cli.base_dir).unwrap();
+            result.unwrap();
+        }
+        Commands::Checkpoints { alias, from, to } => {
+            let csk_key = load_key(&cli, alias);
+            let key_hash = hash_key(&csk_key, gamma);
+            let config = load_config(&cli, &key_hash, &csk_key);
+            let checkpoints = fetch_checkpoints(&config, &key_hash, *from, *to).unwrap();
+            for (url, checkpoints) in &checkpoints {
+                tracing::info!(
+                    "Service < {url} >: {} verified checkpoints",
+                    checkpoints.len()
+                );
+            }
+            let disagreements = find_disagreements(&checkpoints);
+            if disagreements.is_empty() {
+                tracing::info!("All services agree on the flags of their checkpoints");
+            } else {
+                tracing::warn!("Services disagree on the flags at heights: {disagreements:?}");
+            }
+        }
+        Commands::RenewLease {
+            alias,
+            lease_height,
+            lease_secs,
+        } => {
+            tracing::info!("Renewing lease...");
+            let csk_key = load_key(&cli, alias);
+            let key_hash = hash_key(&csk_key, gamma);
+            let config = load_config(&cli, &key_hash, &csk_key);
+            let lease = lease(*lease_height, *lease_secs).unwrap();
+            renew(&config, &key_hash, lease).unwrap();
+        }
     }
 }
@@ -0,0 +1,101 @@
+//! Functions for fetching the checkpoints signed by the enclaves of
+//! services and comparing them. Services given the same flags for a
+//! block height produce the same digest, so disagreements reveal a
+//! host withholding or altering txs.
+
+use std::collections::BTreeMap;
+
+use shared::HexBytes;
+use shared::checkpoint::Checkpoint;
+use shared::{ClientMsg, ServerMsg};
+
+use crate::com::OutgoingTcp;
+use crate::config::{Config, Service};
+use crate::error::{self, Error};
+
+/// Fetch and verify the checkpoints of the block heights in the inclusive
+/// range from all services a key is registered with. Returns the checkpoints
+/// of each service by its url.
+pub fn fetch_checkpoints(
+    config: &Config,
+    key_hash: &String,
+    from: u64,
+    to: u64,
+) -> error::Result<BTreeMap<String, Vec<Checkpoint>>> {
+    let mut checkpoints = BTreeMap::new();
+    for Service {
+        url,
+        checkpoint_key,
+        ..
+    } in config.get_services(key_hash)
+    {
+        let Some(checkpoint_key) = checkpoint_key else {
+            tracing::error!(
+                "Service < {url} >: No attested checkpoint key, register the key again"
+            );
+            return Err(Error::Unauthenticated(format!(
+                "Service < {url} >: No attested checkpoint key, register the key again"
+            )));
+        };
+        let fetched = fetch_service_checkpoints(&url, &checkpoint_key, from, to)?;
+        checkpoints.insert(url, fetched);
+    }
+    Ok(checkpoints)
+}
+
+/// Fetch the checkpoints of a particular service and verify them
+/// against its attested checkpoint key.
+pub fn fetch_service_checkpoints(
+    url: &str,
+    checkpoint_key: &HexBytes<32>,
+    from: u64,
+    to: u64,
+) -> error::Result<Vec<Checkpoint>> {
+    let mut stream = OutgoingTcp::new(url)?;
+    stream.write(ClientMsg::RequestCheckpoints { from, to })?;
+    let checkpoints = match stream.read() {
+        Ok(ServerMsg::Checkpoints(checkpoints)) => checkpoints,
+        Ok(ServerMsg::Error(err)) => {
+            tracing::error!("Service < {url} >: Error reported by server: {err}");
+            return Err(Error::ServerError(err));
+        }
+        _ => {
+            tracing::error!("Service < {url} >: Unable to parse response from the service.");
+            return Err(Error::ServerError(format!(
+                "Service < {url} >: Unable to parse response from the service."
+            )));
+        }
+    };
+    for checkpoint in &checkpoints {
+        if !(from..=to).contains(&checkpoint.height) {
+            return Err(Error::ServerError(format!(
+                "Service < {url} >: Returned a checkpoint outside of the requested range"
+            )));
+        }
+        checkpoint.verify(checkpoint_key).map_err(|e| {
+            tracing::error!("Service < {url} >: {e}");
+            Error::Unauthenticated(format!("Service < {url} >: {e}"))
+        })?;
+    }
+    Ok(checkpoints)
+}
+
+/// The block heights at which services signed differing digests
+pub fn find_disagreements(checkpoints: &BTreeMap<String, Vec<Checkpoint>>) -> Vec<u64> {
+    let mut digests = BTreeMap::<u64, HexBytes<32>>::new();
+    let mut disagreements = vec![];
+    for checkpoint in checkpoints.values().flatten() {
+        match digests.get(&checkpoint.height) {
+            None => {
+                digests.insert(checkpoint.height, checkpoint.digest);
+            }
+            Some(digest) if *digest != checkpoint.digest => {
+                disagreements.push(checkpoint.height);
+            }
+            _ => {}
+        }
+    }
+    disagreements.sort();
+    disagreements.dedup();
+    disagreements
+}
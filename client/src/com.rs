@@ -1,35 +1,240 @@
-use std::net::TcpStream;
+//! Connections to services. Requests are written as async functions
+//! over [`AsyncTcp`]. With the `async` feature, these run on tokio, so
+//! that waiting on a service times out and requests to several services
+//! are made concurrently. Otherwise, they run over blocking sockets and
+//! complete when first polled, so that no runtime is needed to drive them.
 
-use crate::error::{self, Error};
-use shared::{ClientMsg, FramedBytes, ReadWriteByte, ServerMsg};
+use std::future::Future;
+use std::time::Duration;
 
-pub(crate) struct OutgoingTcp(shared::tcp::Tcp);
+use shared::{ClientMsg, ServerMsg};
+
+use crate::error;
+
+/// How long to wait on a connection to a service by default
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait on a response from a service by default
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait on a service before giving up
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timeouts {
+    /// How long to wait on establishing a connection
+    pub connect: Duration,
+    /// How long to wait on each response
+    pub read: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: CONNECT_TIMEOUT,
+            read: RESPONSE_TIMEOUT,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+pub(crate) use futures::future::join_all;
+#[cfg(feature = "async")]
+pub(crate) use tokio_tcp::AsyncTcp;
+
+#[cfg(not(feature = "async"))]
+pub(crate) use std_tcp::AsyncTcp;
+
+/// Await each request in turn. Without a runtime, requests cannot be
+/// made concurrently.
+#[cfg(not(feature = "async"))]
+pub(crate) async fn join_all<F: Future>(requests: impl IntoIterator<Item = F>) -> Vec<F::Output> {
+    let mut outputs = vec![];
+    for request in requests {
+        outputs.push(request.await);
+    }
+    outputs
+}
+
+#[cfg(feature = "async")]
+mod tokio_tcp {
+    use futures::{SinkExt, StreamExt};
+    use shared::codec::ClientCodec;
+    use shared::{ClientMsg, DEFAULT_MAX_FRAME_SIZE, MsgError, ServerMsg};
+    use tokio::net::TcpStream;
+    use tokio_util::codec::Framed;
+
+    use super::Timeouts;
+    use crate::error::{self, Error};
+
+    /// An async connection to a service. Dropping a pending read or
+    /// write cancels it.
+    pub(crate) struct AsyncTcp {
+        framed: Framed<TcpStream, ClientCodec>,
+        read_timeout: std::time::Duration,
+    }
+
+    impl AsyncTcp {
+        /// Connect to a service
+        pub async fn new(url: &str, timeouts: &Timeouts) -> error::Result<Self> {
+            let stream = tokio::time::timeout(timeouts.connect, TcpStream::connect(url))
+                .await
+                .map_err(|_| Error::ConnectTimeout(url.to_string()))?
+                .map_err(Error::Io)?;
+            Ok(Self {
+                framed: Framed::new(stream, ClientCodec::new(DEFAULT_MAX_FRAME_SIZE)),
+                read_timeout: timeouts.read,
+            })
+        }
+
+        /// Send a message to a service
+        pub async fn write(&mut self, msg: ClientMsg) -> error::Result<()> {
+            self.framed.send(msg).await.map_err(Error::MsgError)
+        }
+
+        /// Receive a message from a service
+        pub async fn read(&mut self) -> error::Result<ServerMsg> {
+            match tokio::time::timeout(self.read_timeout, self.framed.next()).await {
+                Ok(Some(msg)) => msg.map_err(Error::MsgError),
+                Ok(None) => Err(Error::MsgError(MsgError::Disconnected)),
+                Err(_) => Err(Error::Timeout),
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "async"))]
+mod std_tcp {
+    use std::io::ErrorKind;
+    use std::net::{TcpStream, ToSocketAddrs};
+
+    use shared::tcp::Tcp;
+    use shared::{ClientMsg, FramedBytes, MsgError, ReadWriteByte, ServerMsg};
+
+    use super::Timeouts;
+    use crate::error::{self, Error};
+
+    /// A connection to a service over a blocking socket. Its requests
+    /// complete when first polled. The read timeout bounds each wait
+    /// on the socket.
+    pub(crate) struct AsyncTcp(Tcp);
+
+    impl AsyncTcp {
+        /// Connect to a service, trying each of its addresses in turn
+        pub async fn new(url: &str, timeouts: &Timeouts) -> error::Result<Self> {
+            let mut last_err = None;
+            for addr in url.to_socket_addrs().map_err(Error::Io)? {
+                match TcpStream::connect_timeout(&addr, timeouts.connect) {
+                    Ok(stream) => {
+                        stream
+                            .set_read_timeout(Some(timeouts.read))
+                            .map_err(Error::Io)?;
+                        return Ok(Self(Tcp::new(stream)));
+                    }
+                    Err(e) if e.kind() == ErrorKind::TimedOut => {
+                        return Err(Error::ConnectTimeout(url.to_string()));
+                    }
+                    Err(e) => last_err = Some(e),
+                }
+            }
+            Err(Error::Io(last_err.unwrap_or_else(|| {
+                std::io::Error::new(ErrorKind::NotFound, format!("{url} has no address"))
+            })))
+        }
+
+        /// Send a message to a service
+        pub async fn write(&mut self, msg: ClientMsg) -> error::Result<()> {
+            self.write_frame(&msg).map_err(Error::MsgError)
+        }
+
+        /// Receive a message from a service
+        pub async fn read(&mut self) -> error::Result<ServerMsg> {
+            match self.get_frame().and_then(|frame| frame.deserialize()) {
+                Ok(msg) => Ok(msg),
+                Err(MsgError::Io(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    Err(Error::Timeout)
+                }
+                Err(e) => Err(Error::MsgError(e)),
+            }
+        }
+    }
+
+    impl ReadWriteByte for AsyncTcp {
+        fn read_byte(&mut self) -> Result<u8, MsgError> {
+            self.0.read_byte()
+        }
+
+        fn write_bytes(&mut self, buf: &[u8]) -> Result<(), MsgError> {
+            self.0.write_bytes(buf)
+        }
+    }
+}
+
+/// Drives async requests from blocking code. Connections made on a
+/// runtime must be used on the same runtime.
+pub(crate) struct Runtime {
+    #[cfg(feature = "async")]
+    inner: tokio::runtime::Runtime,
+}
+
+impl Runtime {
+    pub fn new() -> error::Result<Self> {
+        Ok(Self {
+            #[cfg(feature = "async")]
+            inner: tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(error::Error::Io)?,
+        })
+    }
+
+    /// Run a request to completion
+    #[cfg(feature = "async")]
+    pub fn block_on<T>(&self, request: impl Future<Output = T>) -> T {
+        self.inner.block_on(request)
+    }
+
+    /// Run a request to completion. Without a runtime, requests never
+    /// wait on anything but blocking I/O, so they complete when first
+    /// polled.
+    #[cfg(not(feature = "async"))]
+    pub fn block_on<T>(&self, request: impl Future<Output = T>) -> T {
+        use std::task::{Context, Poll, Waker};
+        let mut request = std::pin::pin!(request);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = request.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+}
+
+/// A connection to a service for blocking code
+pub(crate) struct OutgoingTcp {
+    runtime: Runtime,
+    inner: AsyncTcp,
+}
 
 impl OutgoingTcp {
     /// Create a new connection from a stream
     pub fn new(url: &str) -> error::Result<Self> {
-        let stream = TcpStream::connect(url).map_err(Error::Io)?;
-        Ok(Self(shared::tcp::Tcp::new(stream)))
+        let runtime = Runtime::new()?;
+        let inner = runtime.block_on(AsyncTcp::new(url, &Timeouts::default()))?;
+        Ok(Self { runtime, inner })
     }
 
     /// Send a message to a service
-    pub fn write(&mut self, msg: ClientMsg) {
-        self.write_frame(&msg);
+    pub fn write(&mut self, msg: ClientMsg) -> error::Result<()> {
+        self.runtime.block_on(self.inner.write(msg))
     }
 
     /// Receive a message from a service
     pub fn read(&mut self) -> error::Result<ServerMsg> {
-        let frame = self.get_frame().map_err(Error::MsgError)?;
-        frame.deserialize().map_err(Error::MsgError)
+        self.runtime.block_on(self.inner.read())
     }
 }
 
-impl ReadWriteByte for OutgoingTcp {
-    fn read_byte(&mut self) -> u8 {
-        self.0.read_byte()
-    }
-
-    fn write_bytes(&mut self, buf: &[u8]) {
-        self.0.write_bytes(buf)
-    }
+/// Run an async request to completion on a new runtime
+pub(crate) fn block_on<T>(request: impl Future<Output = error::Result<T>>) -> error::Result<T> {
+    Runtime::new()?.block_on(request)
 }
@@ -1,28 +1,125 @@
 //! Module for handling the backing config file of the client. The
 //! purpose of the config is to persist information about which
 //! keys are registered to which services.
+//!
+//! The services of each key hold the encryption keys of its results,
+//! so they are stored encrypted with a key derived from the FMD master
+//! secret key. They must be unlocked with [`Config::unlock`] before use.
+//!
+//! Clients lock the config of a base directory from loading it until it
+//! is dropped, so that concurrent clients do not lose each other's
+//! changes. The file is replaced atomically when saved, keeping a
+//! backup of the previous version unless it holds services in plaintext
+//! that have since been encrypted.
 
 use std::collections::BTreeMap;
 use std::collections::btree_map::Entry;
-use std::io::ErrorKind;
-use std::path::Path;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use fmd::KeyExpansion;
 use fmd::fmd2_compact::{CompactSecretKey, MultiFmd2CompactScheme};
+use fs2::FileExt;
+use hkdf::Hkdf;
+use rand_core::OsRng;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
+use shared::HexBytes;
 use shared::db::EncKey;
+use zeroize::Zeroizing;
 
 use crate::error::{self, Error};
 
 /// The name of the config file
 pub const CLIENT_FILE_NAME: &str = "kassandra-client.toml";
+/// The name of the file locked while a config is in use
+pub const LOCK_FILE_NAME: &str = "kassandra-client.lock";
+/// The suffix of the backup of the previous version of a file
+pub const BACKUP_SUFFIX: &str = ".bak";
+/// The current version of the config file layout
+pub const CONFIG_VERSION: u32 = 1;
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+/// Migrations of the config file, where the migration at index `i`
+/// upgrades a file of version `i` to version `i + 1`
+const MIGRATIONS: [fn(&mut toml::Table); CONFIG_VERSION as usize] = [
+    // Files without a version may hold plaintext services, encrypted
+    // services or both, all of which version 1 reads as they are
+    |_| {},
+];
+
+#[derive(Debug, Default, Clone)]
 pub struct Config {
     /// A map from the hash of FMD secret key to the services
-    /// it is registered with
+    /// it is registered with, for keys that have been unlocked
     pub services: BTreeMap<String, Vec<Service>>,
+    /// The encrypted services of keys that have not been unlocked
+    sealed: BTreeMap<String, SealedServices>,
+    /// The keys the services of unlocked keys are encrypted with
+    unlocked: BTreeMap<String, EncKey>,
+    /// The lock on the config, if it was loaded from a base directory
+    lock: Option<Arc<ConfigLock>>,
+}
+
+/// An advisory lock on the config of a base directory, held until
+/// dropped
+#[derive(Debug)]
+pub struct ConfigLock(File);
+
+impl ConfigLock {
+    /// Lock the config of a base directory, waiting for any other
+    /// client holding it
+    pub fn acquire(path: impl AsRef<Path>) -> error::Result<Self> {
+        Self::lock_file(&path.as_ref().join(LOCK_FILE_NAME))
+    }
+
+    /// Lock a file, creating it if it does not exist, and wait for
+    /// any other client holding it
+    pub(crate) fn lock_file(path: &Path) -> error::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .map_err(Error::Io)?;
+        if file.try_lock_exclusive().is_err() {
+            tracing::info!(
+                "Waiting for another client to release {}...",
+                path.display()
+            );
+            file.lock_exclusive().map_err(Error::Io)?;
+        }
+        Ok(Self(file))
+    }
+}
+
+impl Drop for ConfigLock {
+    fn drop(&mut self) {
+        _ = FileExt::unlock(&self.0);
+    }
+}
+
+/// The layout of the config file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ConfigFile {
+    /// The version of the layout
+    version: u32,
+    /// Services stored in plaintext, by versions of the client
+    /// without encrypted configs
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    services: BTreeMap<String, Vec<Service>>,
+    /// The encrypted services of each key
+    #[serde(default)]
+    sealed: BTreeMap<String, SealedServices>,
+}
+
+/// The services of a key, encrypted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SealedServices {
+    nonce: HexBytes<12>,
+    /// The hex encoded encrypted services
+    ciphertext: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -30,83 +127,621 @@ pub struct Config {
 pub struct Service {
     /// Address of the service
     pub url: String,
+    /// The UUID of the host, pinned when the service was added. The
+    /// encryption key is derived from it.
+    #[serde(default)]
+    pub uuid: Option<String>,
     /// An index indication which share of fmd keys it received
     pub index: usize,
     /// The key used to decrypt responses from the service
     pub enc_key: EncKey,
+    /// The sequence number of the latest response accepted from the
+    /// service. Older responses are rejected.
+    #[serde(default)]
+    pub sequence: u64,
+    /// The key the service's enclave signs checkpoints with, as
+    /// attested to when the key was registered
+    #[serde(default)]
+    pub checkpoint_key: Option<HexBytes<32>>,
+    /// The detection key the service was registered with, if any
+    #[serde(default)]
+    pub share: Option<Share>,
+}
+
+/// Which detection key extracted from an FMD key a service holds. The
+/// detection key with a given index and number of subkeys is the same
+/// however many others are extracted alongside it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Share {
+    /// The index of the detection key
+    pub index: usize,
+    /// The number of subkeys of the detection key
+    pub subkeys: usize,
 }
 
 impl Config {
-    /// Load the config from the specified path
+    /// Load the config from the specified path, migrating it from
+    /// older versions. This does not lock the config.
     pub fn load(path: impl AsRef<Path>) -> error::Result<Self> {
-        toml::from_str(&std::fs::read_to_string(path).map_err(Error::Io)?).map_err(|e| {
+        let path = path.as_ref();
+        let backup = with_suffix(path, BACKUP_SUFFIX);
+        let invalid = |e: String| {
+            let hint = if backup.exists() {
+                format!(
+                    ". A backup of the previous version is at {}",
+                    backup.display()
+                )
+            } else {
+                String::new()
+            };
             Error::Io(std::io::Error::new(
                 ErrorKind::InvalidData,
-                format!("Could not parse client config file: {e}"),
+                format!("Could not parse client config file: {e}{hint}"),
             ))
+        };
+        let mut table: toml::Table =
+            toml::from_str(&std::fs::read_to_string(path).map_err(Error::Io)?)
+                .map_err(|e| invalid(e.to_string()))?;
+        let version = match table.get("version") {
+            None => 0,
+            Some(version) => version
+                .as_integer()
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| invalid("invalid version".to_string()))?,
+        };
+        if version > CONFIG_VERSION {
+            return Err(Error::Io(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Client config file version {version} was written by a newer client, \
+                     this one supports up to version {CONFIG_VERSION}"
+                ),
+            )));
+        }
+        for migrate in &MIGRATIONS[version as usize..] {
+            migrate(&mut table);
+        }
+        table.insert("version".to_string(), CONFIG_VERSION.into());
+        let file: ConfigFile = table.try_into().map_err(|e| invalid(e.to_string()))?;
+        Ok(Self {
+            services: file.services,
+            sealed: file.sealed,
+            unlocked: BTreeMap::new(),
+            lock: None,
         })
     }
 
-    /// Load a config if it exists, otherwise create a new one
+    /// Lock the config of a base directory and load it if it exists,
+    /// otherwise create a new one. The lock is held until the config
+    /// and all of its clones are dropped.
     pub fn load_or_new(path: impl AsRef<Path>) -> error::Result<Self> {
+        let lock = Arc::new(ConfigLock::acquire(&path)?);
         let path = path.as_ref().join(CLIENT_FILE_NAME);
-        if path.exists() {
-            Self::load(path)
+        let mut config = if path.exists() {
+            Self::load(path)?
         } else {
-            Ok(Self::default())
-        }
+            Self::default()
+        };
+        config.lock = Some(lock);
+        Ok(config)
     }
 
-    /// Save the config at the specified path
+    /// Save the config at the specified path. The services of unlocked
+    /// keys are encrypted, those of other keys are left as they are.
+    ///
+    /// The config is locked while saving if it was not loaded with
+    /// [`Self::load_or_new`].
     pub fn save(&mut self, path: impl AsRef<Path>) -> error::Result<()> {
-        for (_, services) in self.services.iter_mut() {
+        let _lock = match self.lock {
+            Some(_) => None,
+            None => Some(ConfigLock::acquire(&path)?),
+        };
+        let mut file = ConfigFile {
+            version: CONFIG_VERSION,
+            services: BTreeMap::new(),
+            sealed: self.sealed.clone(),
+        };
+        for (key_hash, services) in self.services.iter_mut() {
             services.sort_by_key(|s| s.index);
             services.dedup_by_key(|s| s.index);
+            if let Some(key) = self.unlocked.get(key_hash) {
+                let plaintext = Zeroizing::new(
+                    serde_cbor::to_vec(services).expect("This operation should not fail"),
+                );
+                let (nonce, ciphertext) = key.seal(&plaintext, key_hash.as_bytes(), OsRng);
+                file.sealed.insert(
+                    key_hash.clone(),
+                    SealedServices {
+                        nonce: nonce.into(),
+                        ciphertext: hex::encode(ciphertext),
+                    },
+                );
+            } else {
+                tracing::warn!("The services of key {key_hash} are saved unencrypted");
+                file.services.insert(key_hash.clone(), services.clone());
+            }
         }
         let dest = path.as_ref().join(CLIENT_FILE_NAME);
-        std::fs::write(
-            dest,
-            toml::to_string(&self).expect("This operation should not fail"),
+        // A backup of plaintext services that are now encrypted would
+        // leave their encryption keys readable
+        let backup = !plaintext_keys(&dest)
+            .iter()
+            .any(|key_hash| self.unlocked.contains_key(key_hash));
+        write_atomic(
+            &dest,
+            toml::to_string(&file)
+                .expect("This operation should not fail")
+                .as_bytes(),
+            backup,
         )
-        .map_err(Error::Io)
+    }
+
+    /// Decrypt the services of a key, so that they can be used and
+    /// are encrypted when saved. Services stored in plaintext are
+    /// encrypted from then on. If the key has both, they are merged by
+    /// url, preferring the encrypted services.
+    pub fn unlock(&mut self, key_hash: &str, csk_key: &CompactSecretKey) -> error::Result<()> {
+        let key = config_key(csk_key);
+        if let Some(sealed) = self.sealed.get(key_hash) {
+            let ciphertext = hex::decode(&sealed.ciphertext).map_err(|_| {
+                Error::Keystore(format!("The services of key {key_hash} are not valid hex"))
+            })?;
+            let plaintext = key
+                .open(&sealed.nonce.0, &ciphertext, key_hash.as_bytes())
+                .map(Zeroizing::new)
+                .ok_or_else(|| {
+                    Error::Keystore(format!(
+                        "Could not decrypt the services of key {key_hash} with the given key"
+                    ))
+                })?;
+            let mut services: Vec<Service> = serde_cbor::from_slice(&plaintext).map_err(|e| {
+                Error::Keystore(format!(
+                    "Could not parse the services of key {key_hash}: {e}"
+                ))
+            })?;
+            let plaintext = self.services.remove(key_hash).unwrap_or_default();
+            for mut service in plaintext {
+                if services.iter().any(|s| s.url == service.url) {
+                    continue;
+                }
+                // Indices must stay distinct, or the service is dropped
+                // when saving. Its share records which detection key it
+                // actually holds until it is rebalanced.
+                if services.iter().any(|s| s.index == service.index) {
+                    service.index = services.iter().map(|s| s.index).max().unwrap_or_default() + 1;
+                }
+                services.push(service);
+            }
+            self.services.insert(key_hash.to_string(), services);
+            self.sealed.remove(key_hash);
+        }
+        self.unlocked.insert(key_hash.to_string(), key);
+        Ok(())
     }
 
     /// Add a new service which a specified key will be registered to.
-    pub fn add_service(&mut self, key: String, url: &str, enc_key: EncKey) {
+    pub fn add_service(&mut self, key: String, url: &str, uuid: &str, enc_key: EncKey) {
         match self.services.entry(key) {
             Entry::Vacant(e) => {
                 e.insert(vec![Service {
                     url: url.to_string(),
+                    uuid: Some(uuid.to_string()),
                     index: 1,
                     enc_key,
+                    sequence: 0,
+                    checkpoint_key: None,
+                    share: None,
                 }]);
             }
             Entry::Occupied(mut o) => {
                 let ix = o.get().iter().map(|s| s.index).max().unwrap_or_default();
                 o.get_mut().push(Service {
                     url: url.to_string(),
+                    uuid: Some(uuid.to_string()),
                     index: ix + 1,
                     enc_key,
+                    sequence: 0,
+                    checkpoint_key: None,
+                    share: None,
                 });
             }
         }
     }
 
+    /// Remove a service of a key, returning it if it was configured
+    pub fn remove_service(&mut self, key: &String, url: &str) -> Option<Service> {
+        let services = self.services.get_mut(key)?;
+        let position = services.iter().position(|s| s.url == url)?;
+        Some(services.remove(position))
+    }
+
+    /// Replace a service of a key with a new one, which takes its index
+    /// but is not registered yet. Returns the replaced service if it was
+    /// configured.
+    pub fn replace_service(
+        &mut self,
+        key: &String,
+        url: &str,
+        new_url: &str,
+        uuid: &str,
+        enc_key: EncKey,
+    ) -> Option<Service> {
+        let service = self
+            .services
+            .get_mut(key)?
+            .iter_mut()
+            .find(|s| s.url == url)?;
+        let replacement = Service {
+            url: new_url.to_string(),
+            uuid: Some(uuid.to_string()),
+            index: service.index,
+            enc_key,
+            sequence: 0,
+            checkpoint_key: None,
+            share: None,
+        };
+        Some(std::mem::replace(service, replacement))
+    }
+
     /// Get the services that the specified key is configured to be registered to
     pub fn get_services(&self, key: &String) -> Vec<Service> {
         self.services.get(key).cloned().unwrap_or_default()
     }
 }
 
+/// Replace the contents of a file so that a crash leaves either the
+/// old or the new version. If `backup` is set, the previous version is
+/// kept as a backup, otherwise any existing backup is deleted.
+pub(crate) fn write_atomic(dest: &Path, contents: &[u8], backup: bool) -> error::Result<()> {
+    let tmp = with_suffix(dest, ".tmp");
+    let backup_path = with_suffix(dest, BACKUP_SUFFIX);
+    let mut file = File::create(&tmp).map_err(Error::Io)?;
+    file.write_all(contents).map_err(Error::Io)?;
+    file.sync_all().map_err(Error::Io)?;
+    if backup && dest.exists() {
+        std::fs::copy(dest, &backup_path).map_err(Error::Io)?;
+    }
+    std::fs::rename(&tmp, dest).map_err(Error::Io)?;
+    if !backup {
+        match std::fs::remove_file(&backup_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(Error::Io(e)),
+            _ => {}
+        }
+    }
+    // Persist the rename. Directories cannot be opened for syncing
+    // on all platforms, so this is best effort.
+    if let Some(dir) = dest.parent().and_then(|dir| File::open(dir).ok()) {
+        _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// The keys whose services a config file stores in plaintext. Files
+/// that cannot be read or parsed are taken to store none.
+fn plaintext_keys(path: &Path) -> Vec<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| toml::from_str::<toml::Table>(&contents).ok())
+        .and_then(|table| {
+            table
+                .get("services")
+                .and_then(|services| services.as_table())
+                .map(|services| services.keys().cloned().collect())
+        })
+        .unwrap_or_default()
+}
+
+/// Append a suffix to the name of a file
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Derive the key the services of an FMD master secret key are
+/// encrypted with in the config file
+fn config_key(csk_key: &CompactSecretKey) -> EncKey {
+    let ikm = Zeroizing::new(serde_json::to_string(csk_key).unwrap());
+    let hk = Hkdf::<sha2::Sha256>::new(None, ikm.as_bytes());
+    let mut key = [0u8; 32];
+    hk.expand("Client config encryption key".as_bytes(), &mut key)
+        .expect("This operation should not fail.");
+    chacha20poly1305::Key::from(key).into()
+}
+
 /// Get a hash of an FMD key from a Compact secret key and choice of gamma.
 pub fn hash_key(csk_key: &CompactSecretKey, gamma: usize) -> String {
     let mut hasher = sha2::Sha256::new();
 
     let cpk_key = csk_key.master_public_key();
-    let mut scheme = MultiFmd2CompactScheme::new(gamma, 1);
+    let mut scheme = MultiFmd2CompactScheme::new(gamma, cpk_key.threshold());
     let (fmd_key, _) = scheme.expand_keypair(csk_key, &cpk_key);
 
     hasher.update(serde_json::to_string(&fmd_key).unwrap().as_bytes());
     let bytes: [u8; 32] = hasher.finalize().into();
     hex::encode(bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use shared::db::test_enc_key;
+
+    use super::*;
+
+    fn key(byte: u8) -> CompactSecretKey {
+        CompactSecretKey::derive_from_xof_stream(1, |buf| buf.fill(byte))
+    }
+
+    fn service(url: &str, index: usize) -> Service {
+        Service {
+            url: url.to_string(),
+            uuid: None,
+            index,
+            enc_key: test_enc_key(index as u8),
+            sequence: 0,
+            checkpoint_key: None,
+            share: None,
+        }
+    }
+
+    fn urls(config: &Config, key_hash: &String) -> Vec<(String, usize)> {
+        config
+            .get_services(key_hash)
+            .into_iter()
+            .map(|s| (s.url, s.index))
+            .collect()
+    }
+
+    /// Test that the services of an unlocked key are encrypted when
+    /// saved and can only be decrypted with the same key.
+    #[test]
+    fn test_seal_and_unlock() {
+        let dir = tempfile::tempdir().expect("Test failed");
+        let key_hash = "key".to_string();
+        let mut config = Config::load_or_new(dir.path()).expect("Test failed");
+        config.unlock(&key_hash, &key(1)).expect("Test failed");
+        config.add_service(key_hash.clone(), "a", "uuid", test_enc_key(1));
+        config.save(dir.path()).expect("Test failed");
+        drop(config);
+
+        let contents =
+            std::fs::read_to_string(dir.path().join(CLIENT_FILE_NAME)).expect("Test failed");
+        assert!(!contents.contains("uuid"));
+        let mut config = Config::load_or_new(dir.path()).expect("Test failed");
+        assert!(config.get_services(&key_hash).is_empty());
+        assert!(matches!(
+            config.clone().unlock(&key_hash, &key(2)),
+            Err(Error::Keystore(_))
+        ));
+        config.unlock(&key_hash, &key(1)).expect("Test failed");
+        let services = config.get_services(&key_hash);
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].uuid.as_deref(), Some("uuid"));
+    }
+
+    /// Test that plaintext services left by older clients are merged with
+    /// the encrypted ones of the same key instead of being dropped.
+    #[test]
+    fn test_unlock_mixed_services() {
+        let dir = tempfile::tempdir().expect("Test failed");
+        let key_hash = "key".to_string();
+        let mut config = Config::load_or_new(dir.path()).expect("Test failed");
+        config.unlock(&key_hash, &key(1)).expect("Test failed");
+        config
+            .services
+            .insert(key_hash.clone(), vec![service("a", 1)]);
+        config.save(dir.path()).expect("Test failed");
+        drop(config);
+
+        // An older client adds plaintext services without unlocking
+        let mut config = Config::load_or_new(dir.path()).expect("Test failed");
+        config
+            .services
+            .insert(key_hash.clone(), vec![service("a", 2), service("b", 1)]);
+        config.save(dir.path()).expect("Test failed");
+        drop(config);
+
+        let mut config = Config::load_or_new(dir.path()).expect("Test failed");
+        config.unlock(&key_hash, &key(1)).expect("Test failed");
+        assert_eq!(
+            urls(&config, &key_hash),
+            [("a".to_string(), 1), ("b".to_string(), 2)]
+        );
+        config.save(dir.path()).expect("Test failed");
+        drop(config);
+
+        let mut config = Config::load_or_new(dir.path()).expect("Test failed");
+        assert!(config.services.is_empty());
+        config.unlock(&key_hash, &key(1)).expect("Test failed");
+        assert_eq!(
+            urls(&config, &key_hash),
+            [("a".to_string(), 1), ("b".to_string(), 2)]
+        );
+    }
+
+    /// Test that removing a service keeps the indices of the others and
+    /// that new services are appended after the highest index
+    #[test]
+    fn test_remove_service() {
+        let key_hash = "key".to_string();
+        let mut config = Config::default();
+        for url in ["a", "b", "c"] {
+            config.add_service(key_hash.clone(), url, "uuid", test_enc_key(1));
+        }
+        let removed = config.remove_service(&key_hash, "b").expect("Test failed");
+        assert_eq!((removed.url.as_str(), removed.index), ("b", 2));
+        assert!(config.remove_service(&key_hash, "b").is_none());
+        assert!(config.remove_service(&"other".to_string(), "a").is_none());
+        assert_eq!(
+            urls(&config, &key_hash),
+            [("a".to_string(), 1), ("c".to_string(), 3)]
+        );
+        config.add_service(key_hash.clone(), "d", "uuid", test_enc_key(1));
+        assert_eq!(urls(&config, &key_hash)[2], ("d".to_string(), 4));
+    }
+
+    /// Test that a replacement takes the index of the replaced service
+    /// but none of its registration
+    #[test]
+    fn test_replace_service() {
+        let key_hash = "key".to_string();
+        let mut config = Config::default();
+        let mut registered = service("a", 2);
+        registered.uuid = Some("old".to_string());
+        registered.sequence = 7;
+        registered.checkpoint_key = Some([1; 32].into());
+        registered.share = Some(Share {
+            index: 2,
+            subkeys: 1,
+        });
+        config
+            .services
+            .insert(key_hash.clone(), vec![service("b", 1), registered.clone()]);
+        assert!(
+            config
+                .replace_service(&key_hash, "c", "d", "new", test_enc_key(3))
+                .is_none()
+        );
+        let replaced = config
+            .replace_service(&key_hash, "a", "d", "new", test_enc_key(3))
+            .expect("Test failed");
+        assert_eq!(
+            (replaced.url.as_str(), replaced.share),
+            ("a", registered.share)
+        );
+        let replacement = config.get_services(&key_hash).remove(1);
+        assert_eq!(replacement.url, "d");
+        assert_eq!(replacement.uuid.as_deref(), Some("new"));
+        assert_eq!(replacement.index, 2);
+        assert_eq!(replacement.enc_key.hash(), test_enc_key(3).hash());
+        assert_eq!(replacement.sequence, 0);
+        assert_eq!(replacement.checkpoint_key, None);
+        assert_eq!(replacement.share, None);
+    }
+
+    /// Test that configs without a version are migrated and those of a
+    /// newer version are rejected.
+    #[test]
+    fn test_config_versions() {
+        let dir = tempfile::tempdir().expect("Test failed");
+        let path = dir.path().join(CLIENT_FILE_NAME);
+        let legacy = ConfigFile {
+            version: 0,
+            services: BTreeMap::from([("key".to_string(), vec![service("a", 1)])]),
+            sealed: BTreeMap::new(),
+        };
+        let mut table = toml::Table::try_from(&legacy).expect("Test failed");
+        table.remove("version");
+        std::fs::write(&path, toml::to_string(&table).expect("Test failed")).expect("Test failed");
+        let mut config = Config::load(&path).expect("Test failed");
+        assert_eq!(urls(&config, &"key".to_string()), [("a".to_string(), 1)]);
+        config.save(dir.path()).expect("Test failed");
+        let saved: toml::Table =
+            toml::from_str(&std::fs::read_to_string(&path).expect("Test failed"))
+                .expect("Test failed");
+        assert_eq!(saved["version"].as_integer(), Some(CONFIG_VERSION.into()));
+
+        let newer = format!("version = {}\n", CONFIG_VERSION + 1);
+        std::fs::write(&path, newer).expect("Test failed");
+        assert!(matches!(
+            Config::load(&path),
+            Err(Error::Io(e)) if e.kind() == ErrorKind::InvalidData
+        ));
+    }
+
+    /// Test that saving keeps the previous version as a backup
+    #[test]
+    fn test_config_backup() {
+        let dir = tempfile::tempdir().expect("Test failed");
+        let path = dir.path().join(CLIENT_FILE_NAME);
+        let backup = with_suffix(&path, BACKUP_SUFFIX);
+        let mut config = Config::load_or_new(dir.path()).expect("Test failed");
+        config
+            .services
+            .insert("key".to_string(), vec![service("a", 1)]);
+        config.save(dir.path()).expect("Test failed");
+        assert!(!backup.exists());
+        let first = std::fs::read_to_string(&path).expect("Test failed");
+
+        config.add_service("key".to_string(), "b", "uuid", test_enc_key(2));
+        config.save(dir.path()).expect("Test failed");
+        assert_eq!(
+            std::fs::read_to_string(&backup).expect("Test failed"),
+            first
+        );
+        assert_ne!(std::fs::read_to_string(&path).expect("Test failed"), first);
+        assert!(!with_suffix(&path, ".tmp").exists());
+    }
+
+    /// Test that no backup is left of plaintext services once they are
+    /// encrypted, but later saves keep backups again
+    #[test]
+    fn test_config_backup_sealed() {
+        let dir = tempfile::tempdir().expect("Test failed");
+        let path = dir.path().join(CLIENT_FILE_NAME);
+        let backup = with_suffix(&path, BACKUP_SUFFIX);
+        let key_hash = "key".to_string();
+        // Two plaintext saves leave a backup holding plaintext services
+        let mut config = Config::load_or_new(dir.path()).expect("Test failed");
+        config
+            .services
+            .insert(key_hash.clone(), vec![service("a", 1)]);
+        config.save(dir.path()).expect("Test failed");
+        config.save(dir.path()).expect("Test failed");
+        assert!(
+            std::fs::read_to_string(&backup)
+                .expect("Test failed")
+                .contains("enc_key")
+        );
+        drop(config);
+
+        let mut config = Config::load_or_new(dir.path()).expect("Test failed");
+        config.unlock(&key_hash, &key(1)).expect("Test failed");
+        config.save(dir.path()).expect("Test failed");
+        assert!(
+            !std::fs::read_to_string(&path)
+                .expect("Test failed")
+                .contains("enc_key")
+        );
+        assert!(!backup.exists());
+
+        config.add_service(key_hash.clone(), "b", "uuid", test_enc_key(2));
+        config.save(dir.path()).expect("Test failed");
+        assert!(
+            !std::fs::read_to_string(&backup)
+                .expect("Test failed")
+                .contains("enc_key")
+        );
+    }
+
+    /// Test that a loaded config stays locked until it and its clones
+    /// are dropped.
+    #[test]
+    fn test_config_lock() {
+        let dir = tempfile::tempdir().expect("Test failed");
+        let config = Config::load_or_new(dir.path()).expect("Test failed");
+        let clone = config.clone();
+        let lock_file = File::open(dir.path().join(LOCK_FILE_NAME)).expect("Test failed");
+        assert!(lock_file.try_lock_exclusive().is_err());
+        drop(config);
+        assert!(lock_file.try_lock_exclusive().is_err());
+        drop(clone);
+        assert!(lock_file.try_lock_exclusive().is_ok());
+        FileExt::unlock(&lock_file).expect("Test failed");
+
+        // Saving a config that holds no lock waits for the lock
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let held = ConfigLock::acquire(dir.path()).expect("Test failed");
+        let path = dir.path().to_path_buf();
+        let saving = std::thread::spawn(move || {
+            let result = Config::default().save(&path);
+            sender.send(()).expect("Test failed");
+            result
+        });
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(receiver.try_recv().is_err());
+        drop(held);
+        saving.join().expect("Test failed").expect("Test failed");
+        assert!(receiver.try_recv().is_ok());
+    }
+}
@@ -12,6 +12,28 @@ pub enum Error {
     Io(std::io::Error),
     #[error("{0}")]
     MsgError(shared::MsgError),
+    #[error("Timed out waiting for a response from Kassandra service")]
+    Timeout,
+    #[error("Timed out connecting to Kassandra service at {0}")]
+    ConnectTimeout(String),
     #[error("Establishing RA-TLS connection failed: {0}")]
     RATLS(String),
+    #[error("Response from Kassandra service could not be authenticated: {0}")]
+    Unauthenticated(String),
+    #[error("{0}")]
+    Extraction(String),
+    #[error("Host at {url} reports UUID {reported}, but UUID {pinned} is pinned for it")]
+    UuidMismatch {
+        url: String,
+        pinned: String,
+        reported: String,
+    },
+    #[error("No service at {0} is configured for the key")]
+    UnknownService(String),
+    #[error("The key {0} is not registered with any service")]
+    NotRegistered(String),
+    #[error("Keystore error: {0}")]
+    Keystore(String),
+    #[error("The wrapped MASP client must serve commitment trees, note indices and witness maps")]
+    IncompleteMaspClient,
 }
@@ -0,0 +1,249 @@
+//! Module for handling the client's keystore. FMD master secret keys
+//! are stored under an alias, encrypted with a key derived from a
+//! passphrase, so that they need not be passed on the command line.
+
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+
+use chacha20poly1305::Key;
+use fmd::fmd2_compact::CompactSecretKey;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use shared::HexBytes;
+use shared::db::EncKey;
+use zeroize::Zeroizing;
+
+use crate::config::{ConfigLock, write_atomic};
+use crate::error::{self, Error};
+
+/// The name of the keystore file
+pub const KEYSTORE_FILE_NAME: &str = "kassandra-keystore.toml";
+/// The name of the file locked while a keystore is in use
+pub const KEYSTORE_LOCK_FILE_NAME: &str = "kassandra-keystore.lock";
+
+/// The scrypt cost parameter of newly stored keys, as a power of two
+const SCRYPT_LOG_N: u8 = 15;
+/// The scrypt block size of newly stored keys
+const SCRYPT_R: u32 = 8;
+/// The scrypt parallelization of newly stored keys
+const SCRYPT_P: u32 = 1;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Keystore {
+    /// A map from an alias to the encrypted key stored under it
+    pub accounts: BTreeMap<String, StoredKey>,
+    /// The lock on the keystore, if it was loaded from a base directory
+    #[serde(skip)]
+    lock: Option<Arc<ConfigLock>>,
+}
+
+/// An FMD master secret key encrypted with a passphrase
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredKey {
+    /// The salt of the key derived from the passphrase
+    pub salt: HexBytes<32>,
+    /// The scrypt cost parameter, as a power of two
+    pub log_n: u8,
+    /// The scrypt block size
+    pub r: u32,
+    /// The scrypt parallelization
+    pub p: u32,
+    /// The nonce the secret key is encrypted with
+    pub nonce: HexBytes<12>,
+    /// The hex encoded encrypted secret key
+    pub ciphertext: String,
+}
+
+impl Keystore {
+    /// Load the keystore from the specified path. This does not lock
+    /// the keystore.
+    pub fn load(path: impl AsRef<Path>) -> error::Result<Self> {
+        toml::from_str(&std::fs::read_to_string(path).map_err(Error::Io)?).map_err(|e| {
+            Error::Io(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Could not parse client keystore file: {e}"),
+            ))
+        })
+    }
+
+    /// Lock the keystore of a base directory and load it if it exists,
+    /// otherwise create a new one. The lock is held until the keystore
+    /// and all of its clones are dropped, so that concurrent clients do
+    /// not drop each other's keys.
+    pub fn load_or_new(path: impl AsRef<Path>) -> error::Result<Self> {
+        let lock = Arc::new(ConfigLock::lock_file(
+            &path.as_ref().join(KEYSTORE_LOCK_FILE_NAME),
+        )?);
+        let path = path.as_ref().join(KEYSTORE_FILE_NAME);
+        let mut keystore = if path.exists() {
+            Self::load(path)?
+        } else {
+            Self::default()
+        };
+        keystore.lock = Some(lock);
+        Ok(keystore)
+    }
+
+    /// Save the keystore at the specified path, keeping a backup of
+    /// the previous version.
+    ///
+    /// The keystore is locked while saving if it was not loaded with
+    /// [`Self::load_or_new`].
+    pub fn save(&self, path: impl AsRef<Path>) -> error::Result<()> {
+        let _lock = match self.lock {
+            Some(_) => None,
+            None => Some(ConfigLock::lock_file(
+                &path.as_ref().join(KEYSTORE_LOCK_FILE_NAME),
+            )?),
+        };
+        let dest = path.as_ref().join(KEYSTORE_FILE_NAME);
+        write_atomic(
+            &dest,
+            toml::to_string(&self)
+                .expect("This operation should not fail")
+                .as_bytes(),
+            true,
+        )
+    }
+
+    /// Encrypt a key with a passphrase and store it under a new alias
+    pub fn insert(
+        &mut self,
+        alias: &str,
+        key: &CompactSecretKey,
+        passphrase: &str,
+    ) -> error::Result<()> {
+        self.insert_with_cost(alias, key, passphrase, SCRYPT_LOG_N)
+    }
+
+    /// Encrypt a key with a passphrase, deriving the encryption key with
+    /// the given scrypt cost parameter
+    fn insert_with_cost(
+        &mut self,
+        alias: &str,
+        key: &CompactSecretKey,
+        passphrase: &str,
+        log_n: u8,
+    ) -> error::Result<()> {
+        if self.accounts.contains_key(alias) {
+            return Err(Error::Keystore(format!(
+                "An account with alias {alias} already exists"
+            )));
+        }
+        let mut salt = [0u8; 32];
+        OsRng.fill_bytes(&mut salt);
+        let enc_key = derive_key(passphrase, &salt, log_n, SCRYPT_R, SCRYPT_P)?;
+        let plaintext =
+            Zeroizing::new(serde_json::to_vec(key).expect("This operation should not fail"));
+        let (nonce, ciphertext) = enc_key.seal(&plaintext, alias.as_bytes(), OsRng);
+        self.accounts.insert(
+            alias.to_string(),
+            StoredKey {
+                salt: salt.into(),
+                log_n,
+                r: SCRYPT_R,
+                p: SCRYPT_P,
+                nonce: nonce.into(),
+                ciphertext: hex::encode(ciphertext),
+            },
+        );
+        Ok(())
+    }
+
+    /// Decrypt the key stored under an alias with a passphrase
+    pub fn get(&self, alias: &str, passphrase: &str) -> error::Result<CompactSecretKey> {
+        let stored = self
+            .accounts
+            .get(alias)
+            .ok_or_else(|| Error::Keystore(format!("No account with alias {alias}")))?;
+        let ciphertext = hex::decode(&stored.ciphertext)
+            .map_err(|_| Error::Keystore(format!("The key of {alias} is not valid hex")))?;
+        let enc_key = derive_key(passphrase, &stored.salt.0, stored.log_n, stored.r, stored.p)?;
+        let plaintext = enc_key
+            .open(&stored.nonce.0, &ciphertext, alias.as_bytes())
+            .map(Zeroizing::new)
+            .ok_or_else(|| {
+                Error::Keystore(format!(
+                    "Wrong passphrase for {alias}, or the keystore was tampered with"
+                ))
+            })?;
+        serde_json::from_slice(&plaintext)
+            .map_err(|e| Error::Keystore(format!("Could not parse the key of {alias}: {e}")))
+    }
+
+    /// Remove the key stored under an alias, returning whether it existed
+    pub fn remove(&mut self, alias: &str) -> bool {
+        self.accounts.remove(alias).is_some()
+    }
+}
+
+/// Derive an encryption key from a passphrase
+fn derive_key(passphrase: &str, salt: &[u8], log_n: u8, r: u32, p: u32) -> error::Result<EncKey> {
+    let params = scrypt::Params::new(log_n, r, p)
+        .map_err(|e| Error::Keystore(format!("Invalid key derivation parameters: {e}")))?;
+    let mut key = Key::default();
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key)
+        .expect("This operation should not fail");
+    Ok(key.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use fs2::FileExt;
+
+    use super::*;
+
+    /// A cheap scrypt cost, so that tests do not take long
+    const TEST_LOG_N: u8 = 8;
+
+    fn key(byte: u8) -> CompactSecretKey {
+        CompactSecretKey::derive_from_xof_stream(1, |buf| buf.fill(byte))
+    }
+
+    /// Test that stored keys are only returned with the right passphrase
+    /// and survive being saved and loaded.
+    #[test]
+    fn test_keystore_round_trip() {
+        let dir = tempfile::tempdir().expect("Test failed");
+        let mut keystore = Keystore::load_or_new(dir.path()).expect("Test failed");
+        keystore
+            .insert_with_cost("alice", &key(1), "passphrase", TEST_LOG_N)
+            .expect("Test failed");
+        assert!(matches!(
+            keystore.insert_with_cost("alice", &key(2), "passphrase", TEST_LOG_N),
+            Err(Error::Keystore(_))
+        ));
+        keystore.save(dir.path()).expect("Test failed");
+        drop(keystore);
+
+        let keystore = Keystore::load(dir.path().join(KEYSTORE_FILE_NAME)).expect("Test failed");
+        let stored = keystore.get("alice", "passphrase").expect("Test failed");
+        assert_eq!(
+            serde_json::to_string(&stored).expect("Test failed"),
+            serde_json::to_string(&key(1)).expect("Test failed"),
+        );
+        assert!(matches!(
+            keystore.get("alice", "wrong"),
+            Err(Error::Keystore(_))
+        ));
+        assert!(matches!(
+            keystore.get("bob", "passphrase"),
+            Err(Error::Keystore(_))
+        ));
+    }
+
+    /// Test that the keystore stays locked while loaded, so that no other
+    /// client can change it in the meantime.
+    #[test]
+    fn test_keystore_lock() {
+        let dir = tempfile::tempdir().expect("Test failed");
+        let keystore = Keystore::load_or_new(dir.path()).expect("Test failed");
+        let lock_file =
+            std::fs::File::open(dir.path().join(KEYSTORE_LOCK_FILE_NAME)).expect("Test failed");
+        assert!(lock_file.try_lock_exclusive().is_err());
+        drop(keystore);
+        assert!(lock_file.try_lock_exclusive().is_ok());
+    }
+}
@@ -0,0 +1,54 @@
+//! Functions for renewing the leases of registered keys. Renewals are
+//! authenticated with the encryption key shared with each service and
+//! so do not need a new RA-TLS handshake.
+
+use rand_core::OsRng;
+use shared::db::EncKey;
+use shared::lease::{Lease, LeaseRenewal, Renewal};
+use shared::{ClientMsg, ServerMsg};
+
+use crate::com::OutgoingTcp;
+use crate::config::{Config, Service};
+use crate::error::{self, Error};
+use crate::sequence_number;
+
+/// Renew the lease of a key with all services it is registered to.
+pub fn renew(config: &Config, key_hash: &String, lease: Lease) -> error::Result<()> {
+    let services = config.get_services(key_hash);
+    let renewal = Renewal {
+        lease,
+        sequence: sequence_number(),
+    };
+    for Service { url, enc_key, .. } in services {
+        renew_with_service(&url, &enc_key, &renewal)?;
+    }
+    Ok(())
+}
+
+/// Renew the lease of a key with a particular service
+pub(crate) fn renew_with_service(
+    url: &str,
+    enc_key: &EncKey,
+    renewal: &Renewal,
+) -> error::Result<()> {
+    let mut stream = OutgoingTcp::new(url)?;
+    stream.write(ClientMsg::RenewLease(LeaseRenewal::seal(
+        renewal, enc_key, OsRng,
+    )))?;
+    match stream.read() {
+        Ok(ServerMsg::LeaseRenewed) => {
+            tracing::info!("Service < {url} >: Lease renewed");
+            Ok(())
+        }
+        Ok(ServerMsg::Error(err)) => {
+            tracing::error!("Service < {url} >: Lease renewal failed: {err}");
+            Err(Error::ServerError(err))
+        }
+        _ => {
+            tracing::error!("Service < {url} >: Received unexpected message from service");
+            Err(Error::ServerError(
+                "Received unexpected message from service".to_string(),
+            ))
+        }
+    }
+}
@@ -2,40 +2,69 @@ use chacha20poly1305::Key;
 use fmd::FmdSecretKey;
 use hkdf::Hkdf;
 use shared::db::EncKey;
-use shared::{ClientMsg, ServerMsg};
+use shared::lease::Lease;
+use shared::ratls::{DEFAULT_GAMMA, FmdParams};
 use tracing_subscriber::fmt::SubscriberBuilder;
 
-use crate::com::OutgoingTcp;
+use crate::com::{Timeouts, block_on};
 use crate::config::Config;
-use crate::error::Error;
 
 mod ratls;
 
+pub use ratls::{EnclaveSession, Handshake};
+
+pub mod checkpoint;
 pub mod com;
 pub mod config;
 pub mod error;
+pub mod keystore;
+pub mod lease;
+#[cfg(feature = "namada")]
+pub mod masp;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+#[cfg(not(feature = "async"))]
+mod nonblocking;
 pub mod query;
+pub mod services;
 #[cfg(feature = "tdx")]
 pub mod tdx;
 #[cfg(feature = "transparent")]
 pub mod transparent;
+pub mod update;
+
+/// The client of the attestation scheme registrations are made with.
+/// TDX is used if both are enabled.
+#[cfg(feature = "tdx")]
+pub(crate) type Backend = tdx::TdxClient;
+#[cfg(all(feature = "transparent", not(feature = "tdx")))]
+pub(crate) type Backend = transparent::TClient;
 
-pub const GAMMA: usize = 20;
+/// The gamma of keys registered before it could be chosen
+#[deprecated(note = "keys may have any gamma, use `FmdParams::default().gamma` for the default")]
+pub const GAMMA: usize = DEFAULT_GAMMA;
 
 pub fn init_logging() {
     SubscriberBuilder::default().with_ansi(true).init();
 }
 
 pub fn get_host_uuid(url: &str) -> error::Result<String> {
-    let mut stream = OutgoingTcp::new(url)?;
-    stream.write(ClientMsg::RequestUUID);
-    match stream.read() {
-        Ok(ServerMsg::UUID(uuid)) => Ok(uuid),
-        Ok(ServerMsg::Error(err)) => Err(Error::ServerError(err)),
-        _ => Err(Error::ServerError(format!(
-            "Requesting UUID from host at {url} failed. Could not parse response."
-        ))),
-    }
+    block_on(nonblocking::get_host_uuid(url, &Timeouts::default()))
+}
+
+/// Request the UUID of a service's host and check that it is the one
+/// pinned for the service
+pub fn check_host_uuid(service: &config::Service) -> error::Result<String> {
+    block_on(nonblocking::check_host_uuid(service, &Timeouts::default()))
+}
+
+/// A sequence number for messages authenticated with an encryption key.
+/// These must be strictly increasing, so the current time is used.
+pub(crate) fn sequence_number() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
 }
 
 pub fn encryption_key(fmd_key: &FmdSecretKey, salt: &str) -> EncKey {
@@ -50,21 +79,27 @@ pub fn encryption_key(fmd_key: &FmdSecretKey, salt: &str) -> EncKey {
     enc_key.into()
 }
 
-#[cfg(feature = "tdx")]
-pub fn register_fmd_key(
-    config: &Config,
-    key_hash: String,
-    fmd_key: &FmdSecretKey,
-    birthday: Option<u64>,
-) -> error::Result<()> {
-    ratls::register_fmd_key::<tdx::TdxClient>(config, key_hash, fmd_key, birthday)
-}
-#[cfg(feature = "transparent")]
+#[cfg(any(feature = "tdx", feature = "transparent"))]
+#[allow(clippy::too_many_arguments)]
 pub fn register_fmd_key(
-    config: &Config,
+    config: &mut Config,
     key_hash: String,
     fmd_key: &FmdSecretKey,
+    params: FmdParams,
     birthday: Option<u64>,
+    lease: Option<Lease>,
+    replace: bool,
+    handshake: Handshake,
 ) -> error::Result<()> {
-    ratls::register_fmd_key::<transparent::TClient>(config, key_hash, fmd_key, birthday)
+    block_on(nonblocking::register_fmd_key(
+        config,
+        key_hash,
+        fmd_key,
+        params,
+        birthday,
+        lease,
+        replace,
+        handshake,
+        &Timeouts::default(),
+    ))
 }
@@ -0,0 +1,203 @@
+//! A [`MaspClient`] that only fetches the MASP transactions Kassandra
+//! flagged for a key, so that Namada's shielded sync can use the
+//! results of FMD as a data source.
+//!
+//! The wrapped client must serve pre-built commitment trees, note
+//! indices and witness maps (such as Namada's indexer client with
+//! pre-built data enabled), since these can no longer be built from
+//! the filtered transactions.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use namada::chain::BlockHeight;
+use namada::collections::HashMap;
+use namada::masp::utils::{IndexedNoteEntry, MaspClient, MaspClientCapabilities, MaspIndexedTx};
+use namada::masp_primitives::merkle_tree::{CommitmentTree, IncrementalWitness};
+use namada::masp_primitives::sapling::Node;
+use shared::db::{CombinedIndices, Index, IndexList};
+
+use crate::error::{self, Error};
+
+/// Wraps a [`MaspClient`] so that only the candidate transactions of
+/// the combined Kassandra index sets are fetched. Transactions above
+/// the height any service is synced to have not been scanned yet and
+/// are all fetched.
+#[derive(Clone)]
+pub struct KassandraMaspClient<M> {
+    inner: M,
+    indices: Arc<IndexList>,
+    synced_to: u64,
+}
+
+impl<M: MaspClient> KassandraMaspClient<M> {
+    /// Wrap a client with the combined index sets for a key
+    pub fn new(inner: M, indices: &CombinedIndices) -> error::Result<Self> {
+        if inner.capabilities() != MaspClientCapabilities::AllData {
+            return Err(Error::IncompleteMaspClient);
+        }
+        Ok(Self {
+            inner,
+            indices: Arc::new(indices.candidates()),
+            synced_to: indices.synced_to(),
+        })
+    }
+
+    /// The wrapped client
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+}
+
+impl<M> KassandraMaspClient<M> {
+    /// Check if a transaction was flagged, or is yet to be scanned
+    fn is_detected(&self, tx: &MaspIndexedTx) -> bool {
+        let height = tx.indexed_tx.block_height.0;
+        height > self.synced_to
+            || self.indices.contains(&Index {
+                height,
+                tx: tx.indexed_tx.block_index.0,
+            })
+    }
+
+    /// Narrow a range of heights to those that may contain detected
+    /// transactions. Returns `None` if there are none.
+    fn narrow(&self, from: u64, to: u64) -> Option<(u64, u64)> {
+        let mut flagged = self
+            .indices
+            .iter()
+            .map(|ix| ix.height)
+            .filter(|height| (from..=to).contains(height));
+        let first = flagged.next();
+        let last = flagged.next_back().or(first);
+        let from = first.unwrap_or(self.synced_to.saturating_add(1)).max(from);
+        let to = if to > self.synced_to { Some(to) } else { last }?;
+        (from <= to).then_some((from, to))
+    }
+}
+
+impl<M: MaspClient> MaspClient for KassandraMaspClient<M> {
+    type Error = M::Error;
+
+    async fn last_block_height(&self) -> Result<Option<BlockHeight>, Self::Error> {
+        self.inner.last_block_height().await
+    }
+
+    async fn fetch_shielded_transfers(
+        &self,
+        from: BlockHeight,
+        to: BlockHeight,
+    ) -> Result<Vec<IndexedNoteEntry>, Self::Error> {
+        let Some((from, to)) = self.narrow(from.0, to.0) else {
+            return Ok(vec![]);
+        };
+        let mut txs = self
+            .inner
+            .fetch_shielded_transfers(BlockHeight(from), BlockHeight(to))
+            .await?;
+        txs.retain(|(tx, _)| self.is_detected(tx));
+        Ok(txs)
+    }
+
+    fn capabilities(&self) -> MaspClientCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn fetch_commitment_tree(
+        &self,
+        height: BlockHeight,
+    ) -> Result<CommitmentTree<Node>, Self::Error> {
+        self.inner.fetch_commitment_tree(height).await
+    }
+
+    async fn fetch_note_index(
+        &self,
+        height: BlockHeight,
+    ) -> Result<BTreeMap<MaspIndexedTx, usize>, Self::Error> {
+        self.inner.fetch_note_index(height).await
+    }
+
+    async fn fetch_witness_map(
+        &self,
+        height: BlockHeight,
+    ) -> Result<HashMap<usize, IncrementalWitness<Node>>, Self::Error> {
+        self.inner.fetch_witness_map(height).await
+    }
+
+    async fn commitment_anchor_exists(&self, root: &Node) -> Result<bool, Self::Error> {
+        self.inner.commitment_anchor_exists(root).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use namada::masp::utils::MaspTxKind;
+    use namada::storage::TxIndex;
+    use namada::tx::IndexedTx;
+    use shared::db::SyncedIndices;
+
+    use super::*;
+
+    fn indices(indices: &[(u64, u32)], height: u64) -> SyncedIndices {
+        SyncedIndices {
+            indices: indices
+                .iter()
+                .map(|&(height, tx)| Index { height, tx })
+                .collect(),
+            height,
+        }
+    }
+
+    /// Two services, synced to heights 6 and 10. The candidates are
+    /// (2, 0) and (4, 1), flagged by both, and (8, 0) above height 6.
+    fn client() -> KassandraMaspClient<()> {
+        let combined = CombinedIndices::combine([
+            indices(&[(2, 0), (4, 1), (8, 0)], 10),
+            indices(&[(2, 0), (4, 1), (5, 0)], 6),
+        ]);
+        KassandraMaspClient {
+            inner: (),
+            indices: Arc::new(combined.candidates()),
+            synced_to: combined.synced_to(),
+        }
+    }
+
+    fn tx(height: u64, index: u32) -> MaspIndexedTx {
+        MaspIndexedTx {
+            kind: MaspTxKind::Transfer,
+            indexed_tx: IndexedTx {
+                block_height: BlockHeight(height),
+                block_index: TxIndex(index),
+                batch_index: None,
+            },
+        }
+    }
+
+    /// Test that only candidates and transactions above the height any
+    /// service has synced to are detected.
+    #[test]
+    fn test_is_detected() {
+        let client = client();
+        assert!(client.is_detected(&tx(2, 0)));
+        assert!(client.is_detected(&tx(4, 1)));
+        assert!(client.is_detected(&tx(8, 0)));
+        assert!(!client.is_detected(&tx(4, 0)));
+        assert!(!client.is_detected(&tx(5, 0)));
+        assert!(!client.is_detected(&tx(10, 0)));
+        assert!(client.is_detected(&tx(11, 0)));
+    }
+
+    /// Test that ranges are narrowed to the heights that may hold
+    /// detected transactions.
+    #[test]
+    fn test_narrow() {
+        let client = client();
+        assert_eq!(client.narrow(1, 20), Some((2, 20)));
+        assert_eq!(client.narrow(1, 10), Some((2, 8)));
+        assert_eq!(client.narrow(3, 7), Some((4, 4)));
+        assert_eq!(client.narrow(5, 7), None);
+        assert_eq!(client.narrow(9, 10), None);
+        assert_eq!(client.narrow(9, 12), Some((11, 12)));
+        assert_eq!(client.narrow(15, 20), Some((15, 20)));
+    }
+}
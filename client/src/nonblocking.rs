@@ -0,0 +1,353 @@
+//! The async client API, for wallets running on tokio, enabled by the
+//! `async` feature. The blocking functions of this crate drive these on
+//! a runtime of their own. Without the feature, they drive them over
+//! blocking sockets, see [`crate::com`].
+//!
+//! Each request is bounded by [`Timeouts`] on connecting to a service and
+//! on each of its responses. Requests are cancelled by dropping their
+//! futures. Requests to the services a key is registered with are made
+//! concurrently.
+
+use fmd::FmdSecretKey;
+use shared::db::{CombinedIndices, EncKey, SyncedIndices};
+use shared::lease::Lease;
+use shared::ratls::FmdParams;
+use shared::{ClientMsg, ServerMsg};
+
+pub use crate::com::Timeouts;
+use crate::com::{AsyncTcp, join_all};
+use crate::config::{Config, Service};
+use crate::error::{self, Error};
+use crate::query::open_response;
+#[cfg(feature = "async")]
+pub use crate::ratls::AsyncEnclaveSession;
+use crate::ratls::{self, Handshake};
+
+/// Request the UUID of the host at the given url
+pub async fn get_host_uuid(url: &str, timeouts: &Timeouts) -> error::Result<String> {
+    let mut stream = AsyncTcp::new(url, timeouts).await?;
+    stream.write(ClientMsg::RequestUUID).await?;
+    match stream.read().await {
+        Ok(ServerMsg::UUID(uuid)) => Ok(uuid),
+        Ok(ServerMsg::Error(err)) => Err(Error::ServerError(err)),
+        Err(e) => Err(e),
+        _ => Err(Error::ServerError(format!(
+            "Requesting UUID from host at {url} failed. Could not parse response."
+        ))),
+    }
+}
+
+/// Request the UUID of a service's host and check that it is the one
+/// pinned for the service. The UUID of services added before UUIDs
+/// were pinned is not checked.
+pub async fn check_host_uuid(service: &Service, timeouts: &Timeouts) -> error::Result<String> {
+    let reported = get_host_uuid(&service.url, timeouts).await?;
+    verify_uuid(service, reported)
+}
+
+/// Check that the UUID reported by a service's host is the one pinned
+/// for the service, if any
+fn verify_uuid(service: &Service, reported: String) -> error::Result<String> {
+    match &service.uuid {
+        Some(pinned) if *pinned != reported => {
+            tracing::error!(
+                "Service < {} >: Host reports UUID {reported} instead of {pinned}",
+                service.url
+            );
+            Err(Error::UuidMismatch {
+                url: service.url.clone(),
+                pinned: pinned.clone(),
+                reported,
+            })
+        }
+        _ => Ok(reported),
+    }
+}
+
+/// Query all services where a key is registered and combine the results
+/// of those that respond according to the height each has synced to.
+///
+/// Services that cannot be reached or whose responses are rejected are
+/// left out, so that one outage does not block the query. It only fails
+/// if every service failed, with the error of the first. It also fails
+/// with [`Error::UuidMismatch`] if the host of any service no longer
+/// reports its pinned UUID.
+///
+/// The sequence number of each accepted response is recorded in the
+/// config, which the caller should then save. Services without a pinned
+/// UUID have the one reported pinned if they responded. The config is
+/// left untouched if the query fails or is cancelled.
+pub async fn query_fmd_key(
+    config: &mut Config,
+    key_hash: &String,
+    timeouts: &Timeouts,
+) -> error::Result<CombinedIndices> {
+    let Some(services) = config.services.get_mut(key_hash) else {
+        return Ok(CombinedIndices::default());
+    };
+    let responses = join_all(services.iter().map(|service| async move {
+        let uuid = check_host_uuid(service, timeouts).await?;
+        let (synced, sequence) = query_service(
+            &service.url,
+            &service.enc_key,
+            &uuid,
+            service.sequence,
+            timeouts,
+        )
+        .await?;
+        Ok::<_, Error>((synced, sequence, uuid))
+    }))
+    .await;
+    let mut accepted = vec![];
+    let mut first_err = None;
+    for (ix, response) in responses.into_iter().enumerate() {
+        match response {
+            Ok(response) => accepted.push((ix, response)),
+            Err(e @ Error::UuidMismatch { .. }) => return Err(e),
+            Err(e) => {
+                tracing::warn!(
+                    "Service < {} >: Left out of the query: {e}",
+                    services[ix].url
+                );
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+    if let (true, Some(e)) = (accepted.is_empty(), first_err) {
+        return Err(e);
+    }
+    let mut indices = vec![];
+    for (ix, (synced, sequence, uuid)) in accepted {
+        let service = &mut services[ix];
+        service.sequence = sequence;
+        service.uuid.get_or_insert(uuid);
+        indices.push(synced);
+    }
+    Ok(CombinedIndices::combine(indices))
+}
+
+/// Query a particular service for data on a particular registered key.
+///
+/// The response must be authenticated by the encryption key and be
+/// no older than the response with sequence number `last_sequence`.
+/// Returns the indices and the height they are synced to, along with
+/// the sequence number of the response.
+pub async fn query_service(
+    url: &str,
+    enc_key: &EncKey,
+    uuid: &str,
+    last_sequence: u64,
+    timeouts: &Timeouts,
+) -> error::Result<(SyncedIndices, u64)> {
+    let mut stream = AsyncTcp::new(url, timeouts).await?;
+    stream
+        .write(ClientMsg::RequestIndices {
+            key_hash: enc_key.hash(),
+        })
+        .await?;
+
+    let encrypted = match stream.read().await {
+        Ok(ServerMsg::IndicesResponse(resp)) => resp,
+        Ok(ServerMsg::Error(err)) => {
+            tracing::error!("Service < {uuid} >: Error reported by server: {err}");
+            return Err(Error::ServerError(format!(
+                "Service < {uuid} >: Error reported by server: {err}"
+            )));
+        }
+        Err(e) => {
+            tracing::error!("Service < {uuid} >: {e}");
+            return Err(e);
+        }
+        _ => {
+            tracing::error!("Service < {uuid} >: Unable to parse response from the service.");
+            return Err(Error::ServerError(format!(
+                "Service < {uuid} >: Unable to parse response from the service."
+            )));
+        }
+    };
+    open_response(encrypted, enc_key, uuid, last_sequence)
+}
+
+/// Registers an fmd key to each service instance specified in the
+/// config file, concurrently. The attested checkpoint key of each
+/// service registered with is recorded, even if others failed or
+/// the registration was cancelled.
+#[cfg(any(feature = "tdx", feature = "transparent"))]
+#[allow(clippy::too_many_arguments)]
+pub async fn register_fmd_key(
+    config: &mut Config,
+    key_hash: String,
+    fmd_key: &FmdSecretKey,
+    params: FmdParams,
+    birthday: Option<u64>,
+    lease: Option<Lease>,
+    replace: bool,
+    handshake: Handshake,
+    timeouts: &Timeouts,
+) -> error::Result<()> {
+    ratls::register_fmd_key::<crate::Backend>(
+        config, key_hash, fmd_key, params, birthday, lease, replace, handshake, timeouts,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use shared::db::test_enc_key;
+    use shared::tcp::Tcp;
+    use shared::{ClientMsg, FramedBytes};
+
+    use super::*;
+    use crate::com::block_on;
+    use crate::query::tests::response;
+
+    const TIMEOUTS: Timeouts = Timeouts {
+        connect: Duration::from_secs(5),
+        read: Duration::from_millis(100),
+    };
+
+    /// Serve one client after another, answering the first message of
+    /// each with the next of `replies`, or not at all if it is `None`.
+    /// Returns the address of the server.
+    fn serve_all(replies: Vec<Option<ServerMsg>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Test failed");
+        let url = listener.local_addr().expect("Test failed").to_string();
+        std::thread::spawn(move || {
+            for reply in replies {
+                let (stream, _) = listener.accept().expect("Test failed");
+                let mut stream = Tcp::new(stream);
+                let _ = stream
+                    .get_frame()
+                    .and_then(|f| f.deserialize::<ClientMsg>());
+                match reply {
+                    Some(reply) => stream.write_frame(&reply).expect("Test failed"),
+                    None => std::thread::sleep(TIMEOUTS.read * 10),
+                }
+            }
+        });
+        url
+    }
+
+    /// Serve one client, answering its first message with `reply`, if
+    /// any. Returns the address of the server.
+    fn serve(reply: Option<ServerMsg>) -> String {
+        serve_all(vec![reply])
+    }
+
+    fn service(url: &str, uuid: Option<&str>) -> Service {
+        Service {
+            url: url.to_string(),
+            uuid: uuid.map(str::to_string),
+            index: 1,
+            enc_key: test_enc_key(1),
+            sequence: 0,
+            checkpoint_key: None,
+            share: None,
+        }
+    }
+
+    /// Test that only a UUID other than the pinned one is rejected
+    #[test]
+    fn test_verify_uuid() {
+        let reported = verify_uuid(&service("a", Some("uuid")), "uuid".to_string());
+        assert_eq!(reported.expect("Test failed"), "uuid");
+        let reported = verify_uuid(&service("a", None), "uuid".to_string());
+        assert_eq!(reported.expect("Test failed"), "uuid");
+        let mismatch = verify_uuid(&service("a", Some("old")), "new".to_string());
+        assert!(matches!(
+            mismatch,
+            Err(Error::UuidMismatch { url, pinned, reported })
+                if url == "a" && pinned == "old" && reported == "new"
+        ));
+    }
+
+    /// Test that a service of an old config without a pinned UUID has
+    /// the one reported pinned when first queried, and that querying
+    /// fails once its host reports another
+    #[test]
+    fn test_query_pins_uuid() {
+        let key_hash = "key".to_string();
+        let mut legacy = toml::Table::try_from(service("a", None)).expect("Test failed");
+        legacy.remove("uuid");
+        let legacy: Service = legacy.try_into().expect("Test failed");
+        assert_eq!(legacy.uuid, None);
+
+        let url = serve_all(vec![
+            Some(ServerMsg::UUID("uuid".to_string())),
+            Some(ServerMsg::IndicesResponse(response(5, 3))),
+        ]);
+        let mut config = Config::default();
+        config
+            .services
+            .insert(key_hash.clone(), vec![Service { url, ..legacy }]);
+        let result = block_on(query_fmd_key(&mut config, &key_hash, &TIMEOUTS));
+        assert_eq!(result.expect("Test failed").height, 5);
+        let pinned = &config.services[&key_hash][0];
+        assert_eq!(pinned.uuid.as_deref(), Some("uuid"));
+        assert_eq!(pinned.sequence, 3);
+
+        let url = serve(Some(ServerMsg::UUID("other".to_string())));
+        config.services.get_mut(&key_hash).expect("Test failed")[0].url = url;
+        let result = block_on(query_fmd_key(&mut config, &key_hash, &TIMEOUTS));
+        assert!(matches!(result, Err(Error::UuidMismatch { .. })));
+        let service = &config.services[&key_hash][0];
+        assert_eq!(service.uuid.as_deref(), Some("uuid"));
+        assert_eq!(service.sequence, 3);
+    }
+
+    /// Test that services that cannot be reached are left out of a
+    /// query, unless none can be
+    #[test]
+    fn test_query_partial() {
+        let key_hash = "key".to_string();
+        let url = serve_all(vec![
+            Some(ServerMsg::UUID("uuid".to_string())),
+            Some(ServerMsg::IndicesResponse(response(5, 3))),
+        ]);
+        let mut config = Config::default();
+        config.services.insert(
+            key_hash.clone(),
+            vec![service("127.0.0.1:1", None), service(&url, None)],
+        );
+        let result = block_on(query_fmd_key(&mut config, &key_hash, &TIMEOUTS));
+        assert_eq!(result.expect("Test failed").height, 5);
+        let services = &config.services[&key_hash];
+        assert_eq!(
+            (services[0].uuid.as_deref(), services[0].sequence),
+            (None, 0)
+        );
+        assert_eq!(
+            (services[1].uuid.as_deref(), services[1].sequence),
+            (Some("uuid"), 3)
+        );
+
+        let mut config = Config::default();
+        config
+            .services
+            .insert(key_hash.clone(), vec![service("127.0.0.1:1", None)]);
+        let result = block_on(query_fmd_key(&mut config, &key_hash, &TIMEOUTS));
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+
+    /// Test that replies are relayed and errors reported as such
+    #[test]
+    fn test_get_host_uuid() {
+        let url = serve(Some(ServerMsg::UUID("uuid".to_string())));
+        let uuid = block_on(get_host_uuid(&url, &TIMEOUTS));
+        assert_eq!(uuid.expect("Test failed"), "uuid");
+        let url = serve(Some(ServerMsg::Error("unavailable".to_string())));
+        let err = block_on(get_host_uuid(&url, &TIMEOUTS));
+        assert!(matches!(err, Err(Error::ServerError(e)) if e == "unavailable"));
+    }
+
+    /// Test that waiting on a service that does not respond times out
+    #[test]
+    fn test_read_timeout() {
+        let url = serve(None);
+        let result = block_on(get_host_uuid(&url, &TIMEOUTS));
+        assert!(matches!(result, Err(Error::Timeout)));
+    }
+}
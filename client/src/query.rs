@@ -1,66 +1,96 @@
 //! Functions for querying the Kassandra service DB for data
 //! relevant to a particular registered key.
 
-use chacha20poly1305::aead::Aead;
+use chacha20poly1305::aead::{Aead, Payload};
 use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
-use shared::db::{EncKey, IndexList};
-use shared::{ClientMsg, ServerMsg};
+use shared::db::{
+    CombinedIndices, EncKey, EncryptedResponse, IndexList, RESPONSE_VERSION, SyncedIndices,
+};
 
-use crate::com::OutgoingTcp;
-use crate::config::{Config, Service};
+use crate::com::{Timeouts, block_on};
+use crate::config::Config;
 use crate::error::{self, Error};
-use crate::get_host_uuid;
+use crate::nonblocking;
 
-/// Query all services where a key is registered and combine the results.
-pub fn query_fmd_key(config: &Config, key_hash: &String) -> error::Result<Vec<IndexList>> {
-    let services = config.get_services(key_hash);
-    let mut indices = vec![];
-    for Service { url, enc_key, .. } in services {
-        let uuid = get_host_uuid(&url)?;
-        let list = query_service(&url, &enc_key, &uuid)?;
-        indices.push(list);
-    }
-    Ok(indices)
+/// Query all services where a key is registered and combine the results
+/// of those that respond. Fails only if none do, or if the host of a
+/// service no longer reports its pinned UUID.
+///
+/// The sequence number of each accepted response is recorded in the
+/// config, which the caller should then save.
+pub fn query_fmd_key(config: &mut Config, key_hash: &String) -> error::Result<CombinedIndices> {
+    block_on(nonblocking::query_fmd_key(
+        config,
+        key_hash,
+        &Timeouts::default(),
+    ))
 }
 
 /// Query a particular service for data on a particular registered key.
-pub fn query_service(url: &str, enc_key: &EncKey, uuid: &str) -> error::Result<IndexList> {
-    let mut stream = OutgoingTcp::new(url)?;
-    stream.write(ClientMsg::RequestIndices {
-        key_hash: enc_key.hash(),
-    });
-
-    let encrypted = match stream.read() {
-        Ok(ServerMsg::IndicesResponse(resp)) => resp,
-        Ok(ServerMsg::Error(err)) => {
-            tracing::error!("Service < {uuid} >: Error reported by server: {err}");
-            return Err(Error::ServerError(format!(
-                "Service < {uuid} >: Error reported by server: {err}"
-            )));
-        }
-        _ => {
-            tracing::error!("Service < {uuid} >: Unable to parse response from the service.");
-            return Err(Error::ServerError(format!(
-                "Service < {uuid} >: Unable to parse response from the service."
-            )));
-        }
-    };
+///
+/// The response must be authenticated by the encryption key and be
+/// no older than the response with sequence number `last_sequence`.
+/// Returns the indices and the height they are synced to, along with
+/// the sequence number of the response.
+pub fn query_service(
+    url: &str,
+    enc_key: &EncKey,
+    uuid: &str,
+    last_sequence: u64,
+) -> error::Result<(SyncedIndices, u64)> {
+    block_on(nonblocking::query_service(
+        url,
+        enc_key,
+        uuid,
+        last_sequence,
+        &Timeouts::default(),
+    ))
+}
 
+/// Authenticate and decrypt a service's response for a registered key.
+pub(crate) fn open_response(
+    encrypted: EncryptedResponse,
+    enc_key: &EncKey,
+    uuid: &str,
+    last_sequence: u64,
+) -> error::Result<(SyncedIndices, u64)> {
     if encrypted.owner != enc_key.hash() {
         tracing::error!("Service < {uuid} >: Received response for data owned by a different key");
-        return Err(Error::ServerError(format!(
+        return Err(Error::Unauthenticated(format!(
             "Service < {uuid} >: Received response for data owned by a different key"
         )));
     }
+    if encrypted.version != RESPONSE_VERSION {
+        tracing::error!(
+            "Service < {uuid} >: Received response of unsupported version {}",
+            encrypted.version
+        );
+        return Err(Error::Unauthenticated(format!(
+            "Service < {uuid} >: Received response of unsupported version {}",
+            encrypted.version
+        )));
+    }
 
     let cipher = ChaCha20Poly1305::new(enc_key.into());
     let nonce = Nonce::from(encrypted.nonce);
-    let Ok(index_bytes) = cipher.decrypt(&nonce, encrypted.indices.as_ref()) else {
-        tracing::error!("Service < {uuid} >: Failed to decrypt the response from the service");
-        return Err(Error::ServerError(format!(
-            "Service < {uuid} >: Failed to decrypt the response from the service"
+    let payload = Payload {
+        msg: &encrypted.indices,
+        aad: &encrypted.associated_data(),
+    };
+    let Ok(index_bytes) = cipher.decrypt(&nonce, payload) else {
+        tracing::error!(
+            "Service < {uuid} >: Failed to decrypt the response from the service or it was tampered with"
+        );
+        return Err(Error::Unauthenticated(format!(
+            "Service < {uuid} >: Failed to decrypt the response from the service or it was tampered with"
         )));
     };
+    if encrypted.sequence < last_sequence {
+        tracing::error!("Service < {uuid} >: Received response older than the last one accepted");
+        return Err(Error::Unauthenticated(format!(
+            "Service < {uuid} >: Received response older than the last one accepted"
+        )));
+    }
 
     match IndexList::try_from_bytes(&index_bytes) {
         None => {
@@ -71,9 +101,72 @@ pub fn query_service(url: &str, enc_key: &EncKey, uuid: &str) -> error::Result<I
                 "Service < {uuid} >: Could not deserialize decrypted response as MASP indices"
             )))
         }
-        Some(list) => {
+        Some(indices) => {
             tracing::info!("Service < {uuid} >: Synced to height: {}", encrypted.height);
-            Ok(list)
+            Ok((
+                SyncedIndices {
+                    indices,
+                    height: encrypted.height,
+                },
+                encrypted.sequence,
+            ))
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use shared::config::PaddingPolicy;
+    use shared::db::{Index, test_enc_key};
+
+    use super::*;
+
+    /// A response to `test_enc_key(1)` with one index at `height`
+    pub(crate) fn response(height: u64, sequence: u64) -> EncryptedResponse {
+        let enc_key = test_enc_key(1);
+        let mut response = EncryptedResponse {
+            owner: enc_key.hash(),
+            nonce: [2; 12],
+            indices: vec![],
+            height,
+            sequence,
+            version: RESPONSE_VERSION,
+        };
+        let bytes = IndexList::to_padded_bytes(&[Index { height, tx: 0 }], &PaddingPolicy::None);
+        let payload = Payload {
+            msg: &bytes,
+            aad: &response.associated_data(),
+        };
+        response.indices = ChaCha20Poly1305::new((&enc_key).into())
+            .encrypt(&Nonce::from(response.nonce), payload)
+            .expect("Test failed");
+        response
+    }
+
+    /// Test that responses are only accepted if their metadata is
+    /// unaltered and they are no older than the last one accepted.
+    #[test]
+    fn test_open_response() {
+        let (synced, sequence) =
+            open_response(response(5, 3), &test_enc_key(1), "uuid", 3).expect("Test failed");
+        assert_eq!(synced.height, 5);
+        assert_eq!(sequence, 3);
+
+        let mut tampered = response(5, 3);
+        tampered.height = 6;
+        assert!(matches!(
+            open_response(tampered, &test_enc_key(1), "uuid", 0),
+            Err(Error::Unauthenticated(_))
+        ));
+        let mut tampered = response(5, 3);
+        tampered.sequence = 4;
+        assert!(matches!(
+            open_response(tampered, &test_enc_key(1), "uuid", 4),
+            Err(Error::Unauthenticated(_))
+        ));
+        assert!(matches!(
+            open_response(response(5, 3), &test_enc_key(1), "uuid", 4),
+            Err(Error::Unauthenticated(_))
+        ));
+    }
+}
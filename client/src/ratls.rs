@@ -2,115 +2,441 @@
 //! requires establishing trust of the enclave (remote attestation),
 //! and a Diffie-Hellman key exchange for secure communication.
 //!
-//! Currently, the only direct communication between enclaves and
-//! clients is registering clients' FMD detection keys with the
-//! enclave.
-use fmd::fmd2_compact::MultiFmd2CompactScheme;
-use fmd::{DetectionKey, FmdSecretKey, MultiFmdScheme};
+//! Clients register their FMD detection keys with the enclave over
+//! either the bespoke RA-TLS of [`shared::ratls`] or TLS 1.3 (see
+//! [`shared::tls`]). A bespoke [`EnclaveSession`] can carry several
+//! registrations and follow-up requests.
+//!
+//! Requests are made asynchronously, the blocking [`EnclaveSession`]
+//! driving an `AsyncEnclaveSession` on a runtime of its own.
+use fmd::FmdSecretKey;
 use rand_core::{OsRng, RngCore};
-use shared::db::EncKey;
-use shared::ratls::{Connection, FmdKeyRegistration};
+use shared::HexBytes;
+use shared::checkpoint::verify_key_binding;
+use shared::lease::Lease;
+use shared::ratls::{
+    Connection, FmdKeyRegistration, FmdParams, KeyConfirmation, RegistrationReceipt, Role,
+    SecureSession, SessionReply, SessionRequest, Transcript,
+};
 use shared::tee::EnclaveClient;
+use shared::tls::{TlsClient, TlsError};
 use shared::{AckType, ClientMsg, ServerMsg};
 
-use crate::GAMMA;
-use crate::com::OutgoingTcp;
-use crate::config::{Config, Service};
+use crate::com::{AsyncTcp, Runtime, Timeouts, join_all};
+use crate::config::{Config, Service, Share};
 use crate::error::{self, Error};
+use crate::services::{extract, shares};
+
+/// How the secure channel to the enclave is established
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Handshake {
+    /// The bespoke RA-TLS handshake
+    #[default]
+    Bespoke,
+    /// TLS 1.3 with the attestation report in the enclave's certificate
+    Tls,
+}
 
 /// Registers an fmd key to each service instance
-/// specified in the config file.
-pub(crate) fn register_fmd_key<C: EnclaveClient>(
-    config: &Config,
+/// specified in the config file. Services are registered with
+/// concurrently.
+///
+/// A new registration starts counting responses anew, so the
+/// sequence numbers recorded for each service are reset. The
+/// attested checkpoint key of each service is recorded for every
+/// service the key was registered with, even if others failed.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn register_fmd_key<C: EnclaveClient + 'static>(
+    config: &mut Config,
     key_hash: String,
     fmd_key: &FmdSecretKey,
+    params: FmdParams,
     birthday: Option<u64>,
+    lease: Option<Lease>,
+    replace: bool,
+    handshake: Handshake,
+    timeouts: &Timeouts,
 ) -> error::Result<()> {
-    let services = config.get_services(&key_hash);
-    // Get the encryption key
-    let scheme = MultiFmd2CompactScheme::new(GAMMA, 1);
-    let detection_keys = scheme
-        .multi_extract(fmd_key, services.len(), 1, 1, services.len())
-        .unwrap();
-    for Service {
-        url,
-        index,
-        enc_key,
-    } in services
-    {
-        register_fmd_key_to_service::<C>(
-            &url,
-            enc_key,
-            detection_keys[index - 1].clone(),
-            birthday,
-        )?;
+    let registration = Registration {
+        params,
+        subkeys: 1,
+        birthday,
+        lease,
+        replace,
+        handshake,
+    };
+    register_services::<C>(config, &key_hash, fmd_key, registration, |_| true, timeouts).await
+}
+
+/// The parameters of registering a key with services
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Registration {
+    pub params: FmdParams,
+    /// The number of subkeys of each service's detection key
+    pub subkeys: usize,
+    pub birthday: Option<u64>,
+    pub lease: Option<Lease>,
+    pub replace: bool,
+    pub handshake: Handshake,
+}
+
+/// Registers an fmd key to the selected services of the config file,
+/// concurrently. Each service is given the detection key of its index,
+/// which is recorded as its share once registered.
+pub(crate) async fn register_services<C: EnclaveClient + 'static>(
+    config: &mut Config,
+    key_hash: &String,
+    fmd_key: &FmdSecretKey,
+    registration: Registration,
+    select: impl Fn(&Service) -> bool,
+    timeouts: &Timeouts,
+) -> error::Result<()> {
+    let Some(services) = config.services.get_mut(key_hash) else {
+        return Ok(());
+    };
+    let detection_keys = extract(
+        fmd_key,
+        registration.params,
+        shares(services),
+        registration.subkeys,
+    )?;
+    let registrations = services
+        .iter_mut()
+        .filter(|service| select(service))
+        .map(|service| {
+            let key_reg = FmdKeyRegistration {
+                fmd_key: detection_keys[service.index - 1].clone(),
+                params: registration.params,
+                enc_key: service.enc_key.clone(),
+                birthday: registration.birthday,
+                lease: registration.lease,
+                replace: registration.replace,
+            };
+            async move {
+                let (key, sequence) = match registration.handshake {
+                    Handshake::Bespoke => {
+                        register_fmd_key_to_service::<C>(&service.url, key_reg, timeouts).await?
+                    }
+                    Handshake::Tls => {
+                        register_fmd_key_over_tls::<C>(&service.url, key_reg, timeouts).await?
+                    }
+                };
+                // Registering again keeps the enclave's results, so older
+                // responses must still be rejected. Only a registration new
+                // to the enclave starts its results from 0.
+                if let Some(sequence) = sequence {
+                    service.sequence = sequence;
+                }
+                service.checkpoint_key = Some(key);
+                service.share = Some(Share {
+                    index: service.index,
+                    subkeys: registration.subkeys,
+                });
+                Ok(())
+            }
+        });
+    join_all(registrations).await.into_iter().collect()
+}
+
+/// Register an FMD key within a new session with the enclave and
+/// return the checkpoint key bound into its attestation report, along
+/// with the sequence number of the registration's last result.
+async fn register_fmd_key_to_service<C: EnclaveClient>(
+    url: &str,
+    key_reg: FmdKeyRegistration,
+    timeouts: &Timeouts,
+) -> error::Result<(HexBytes<32>, Option<u64>)> {
+    let mut session = AsyncEnclaveSession::open::<C>(url, timeouts).await?;
+    let receipt = session.register(key_reg).await?;
+    tracing::info!(
+        "Key registered successfully, detecting from block height {}",
+        receipt.synced_to
+    );
+    let checkpoint_key = *session.checkpoint_key();
+    session.close().await;
+    Ok((checkpoint_key, Some(receipt.sequence)))
+}
+
+/// A session with the enclave of a service over the bespoke RA-TLS
+/// (handshake version 2). Once the enclave has been attested, any number
+/// of requests can be made and the enclave's replies are encrypted too.
+pub struct EnclaveSession {
+    runtime: Runtime,
+    inner: AsyncEnclaveSession,
+}
+
+impl EnclaveSession {
+    /// Initialize a new session with the enclave.
+    /// See [`AsyncEnclaveSession::open`].
+    pub fn open<C: EnclaveClient>(url: &str) -> error::Result<Self> {
+        let runtime = Runtime::new()?;
+        let inner = runtime.block_on(AsyncEnclaveSession::open::<C>(url, &Timeouts::default()))?;
+        Ok(Self { runtime, inner })
+    }
+
+    /// The checkpoint key bound into the enclave's attestation report
+    pub fn checkpoint_key(&self) -> &HexBytes<32> {
+        self.inner.checkpoint_key()
+    }
+
+    /// Register an FMD key with the enclave
+    pub fn register(&mut self, key_reg: FmdKeyRegistration) -> error::Result<RegistrationReceipt> {
+        self.runtime.block_on(self.inner.register(key_reg))
+    }
+
+    /// Get the state of a registration made earlier in this session
+    pub fn stats(&mut self, owner: String) -> error::Result<RegistrationReceipt> {
+        self.runtime.block_on(self.inner.stats(owner))
     }
-    Ok(())
+
+    /// Leave the session
+    pub fn close(self) {
+        self.runtime.block_on(self.inner.close())
+    }
+}
+
+/// The async version of [`EnclaveSession`]
+pub struct AsyncEnclaveSession {
+    stream: AsyncTcp,
+    session: SecureSession,
+    /// The client's key confirmation, sent along with the first request
+    confirm: Option<HexBytes<32>>,
+    checkpoint_key: HexBytes<32>,
 }
 
-/// Initialize a new TLS connection with the enclave.
-/// The handshake phase establishes a shared key via DHKE.
+impl AsyncEnclaveSession {
+    /// Initialize a new session with the enclave. The handshake phase
+    /// establishes shared keys via DHKE. The keys are bound to the
+    /// transcript and confirmed by both sides.
+    ///
+    /// The client also validates the Remote Attestation report
+    /// provided by the enclave, including the checkpoint key
+    /// bound into it.
+    pub async fn open<C: EnclaveClient>(url: &str, timeouts: &Timeouts) -> error::Result<Self> {
+        let mut rng = OsRng;
+        let mut stream = AsyncTcp::new(url, timeouts).await?;
+        let conn = Connection::new(&mut rng);
+        let client_pk = conn
+            .public_key()
+            .expect("A new connection is in its handshake");
+
+        // create a nonce for replay protection
+        let nonce = rng.next_u64();
+
+        // initiate handshake with enclave
+        stream.write(conn.client_send(nonce).unwrap()).await?;
+
+        // validate remote attestation certificates
+        let (report, checkpoint_key, confirm) = match stream.read().await {
+            Ok(ServerMsg::RATLS {
+                report,
+                checkpoint_key,
+                confirm,
+            }) => (report, checkpoint_key, confirm),
+            Ok(ServerMsg::Error(err)) => {
+                tracing::error!("Error reported by server: {err}");
+                return Err(Error::ServerError(err));
+            }
+            _ => {
+                tracing::error!(
+                    "Establishing RA-TLS connection failed: Could not parse service response as RA report."
+                );
+                return Err(Error::ServerError(
+                    "Establishing RA-TLS connection failed: Could not parse service response as RA report.".to_string()
+                ));
+            }
+        };
+
+        let report_data = match C::verify_quote(&report, nonce) {
+            Ok(report_data) => report_data,
+            Err(e) => return Err(abort_tls(&mut stream, e.to_string()).await),
+        };
+        if let Err(e) = verify_key_binding(&checkpoint_key, &report_data) {
+            return Err(abort_tls(&mut stream, e.to_string()).await);
+        }
+
+        // Extract the signed ephemeral public key
+        let pk_bytes = <[u8; 32]>::try_from(&report_data[0..32]).unwrap();
+        let pk = x25519_dalek::PublicKey::from(pk_bytes);
+
+        // finish the handshake and check that the enclave derived the same keys
+        let Some(confirm) = confirm else {
+            return Err(abort_tls(
+                &mut stream,
+                "The service does not support handshake version 2",
+            )
+            .await);
+        };
+        let transcript = Transcript {
+            client_pk: client_pk.as_bytes(),
+            nonce,
+            enclave_pk: &pk_bytes,
+            report: &report,
+            checkpoint_key: &checkpoint_key,
+        };
+        let confirmed = conn
+            .initialize_with_transcript(pk, &transcript, Role::Client)
+            .and_then(|(session, confirmation)| {
+                KeyConfirmation::check(&confirmation.enclave, &confirm)
+                    .map(|_| (session, confirmation.client))
+            });
+        let (session, confirm) = match confirmed {
+            Ok(confirmed) => confirmed,
+            Err(e) => return Err(abort_tls(&mut stream, e.to_string()).await),
+        };
+
+        Ok(Self {
+            stream,
+            session,
+            confirm: Some(confirm),
+            checkpoint_key,
+        })
+    }
+
+    /// The checkpoint key bound into the enclave's attestation report
+    pub fn checkpoint_key(&self) -> &HexBytes<32> {
+        &self.checkpoint_key
+    }
+
+    /// Register an FMD key with the enclave
+    pub async fn register(
+        &mut self,
+        key_reg: FmdKeyRegistration,
+    ) -> error::Result<RegistrationReceipt> {
+        match self.request(&SessionRequest::Register(key_reg)).await? {
+            SessionReply::Registered(receipt) => Ok(receipt),
+            _ => Err(unexpected_reply()),
+        }
+    }
+
+    /// Get the state of a registration made earlier in this session
+    pub async fn stats(&mut self, owner: String) -> error::Result<RegistrationReceipt> {
+        match self.request(&SessionRequest::Stats { owner }).await? {
+            SessionReply::Stats(receipt) => Ok(receipt),
+            _ => Err(unexpected_reply()),
+        }
+    }
+
+    /// Leave the session
+    pub async fn close(mut self) {
+        // the session is being abandoned either way
+        let _ = match self.confirm {
+            Some(_) => self.stream.write(ClientMsg::RATLSAck(AckType::Fail)).await,
+            None => self.stream.write(ClientMsg::CloseSession).await,
+        };
+    }
+
+    /// Send an encrypted request and wait for the enclave's reply. The
+    /// first request also completes the handshake.
+    async fn request(&mut self, request: &SessionRequest) -> error::Result<SessionReply> {
+        let cipher = self
+            .session
+            .seal(request)
+            .map_err(|e| Error::RATLS(e.to_string()))?;
+        let msg = match self.confirm.take() {
+            Some(confirm) => ClientMsg::RATLSAck(AckType::Confirmed { confirm, cipher }),
+            None => ClientMsg::SessionRequest(cipher),
+        };
+        self.stream.write(msg).await?;
+        let reply = match self.stream.read().await {
+            Ok(ServerMsg::SessionReply(cipher)) => self
+                .session
+                .open::<SessionReply>(&cipher)
+                .map_err(|e| Error::RATLS(e.to_string()))?,
+            Ok(ServerMsg::Error(msg)) => {
+                tracing::error!("Session request failed: {msg}");
+                return Err(Error::ServerError(msg));
+            }
+            _ => return Err(unexpected_reply()),
+        };
+        match reply {
+            SessionReply::Error(msg) => {
+                tracing::error!("Session request failed: {msg}");
+                Err(Error::ServerError(msg))
+            }
+            reply => Ok(reply),
+        }
+    }
+}
+
+fn unexpected_reply() -> Error {
+    tracing::error!("Received unexpected message from service");
+    Error::ServerError("Received unexpected message from service".to_string())
+}
+
+/// Register an FMD key over TLS 1.3. The enclave's certificate must carry
+/// an attestation report over its key and the client's nonce, which is
+/// checked while processing the enclave's handshake records.
 ///
-/// The client also validates the Remote Attestation report
-/// provided by the enclave.
-fn register_fmd_key_to_service<C: EnclaveClient>(
+/// Returns the checkpoint key bound into the report. The acknowledgement
+/// is not authenticated, so no sequence number is returned.
+async fn register_fmd_key_over_tls<C: EnclaveClient + 'static>(
     url: &str,
-    encryption_key: EncKey,
-    detection_key: DetectionKey,
-    birthday: Option<u64>,
-) -> error::Result<()> {
-    let mut rng = OsRng;
-    let mut stream = OutgoingTcp::new(url)?;
-    let conn = Connection::new(&mut rng);
+    key_reg: FmdKeyRegistration,
+    timeouts: &Timeouts,
+) -> error::Result<(HexBytes<32>, Option<u64>)> {
+    let mut stream = AsyncTcp::new(url, timeouts).await?;
 
     // create a nonce for replay protection
-    let nonce = rng.next_u64();
-
-    // initiate handshake with enclave
-    stream.write(conn.client_send(nonce).unwrap());
+    let nonce = OsRng.next_u64();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (mut conn, records) =
+        TlsClient::new_client::<C>(nonce, now).map_err(|e| Error::RATLS(e.to_string()))?;
+    stream.write(ClientMsg::TlsHello { nonce, records }).await?;
 
-    // validate remote attestation certificates
-    let report = match stream.read() {
-        Ok(ServerMsg::RATLS { report }) => report,
+    let (records, checkpoint_key) = match stream.read().await {
+        Ok(ServerMsg::TlsHandshake {
+            records,
+            checkpoint_key,
+        }) => (records, checkpoint_key),
         Ok(ServerMsg::Error(err)) => {
             tracing::error!("Error reported by server: {err}");
             return Err(Error::ServerError(err));
         }
         _ => {
             tracing::error!(
-                "Establishing RA-TLS connection failed: Could not parse service response as RA report."
+                "Establishing TLS connection failed: Could not parse service response as TLS records."
             );
             return Err(Error::ServerError(
-                "Establishing RA-TLS connection failed: Could not parse service response as RA report.".to_string()
+                "Establishing TLS connection failed: Could not parse service response as TLS records.".to_string()
             ));
         }
     };
 
-    let report_data =
-        C::verify_quote(&report, nonce).map_err(|e| abort_tls(&mut stream, e.to_string()))?;
-
-    // Extract the signed ephemeral public key and session id
-    let pk_bytes = <[u8; 32]>::try_from(&report_data[0..32]).unwrap();
-    let pk = x25519_dalek::PublicKey::from(pk_bytes);
+    // the attestation in the enclave's certificate is verified here
+    let established = conn.process(&records).and_then(|progress| {
+        if !progress.established {
+            return Err(TlsError::HandshakeIncomplete);
+        }
+        let report_data = conn.report_data::<C>(nonce)?;
+        Ok((progress, report_data))
+    });
+    let (progress, report_data) = match established {
+        Ok(established) => established,
+        Err(e) => return Err(abort_tls(&mut stream, e.to_string()).await),
+    };
+    if let Err(e) = verify_key_binding(&checkpoint_key, &report_data) {
+        return Err(abort_tls(&mut stream, e.to_string()).await);
+    }
 
-    // finish the handshake and initialize the connection
-    let conn = conn
-        .initialize(pk)
-        .map_err(|e| abort_tls(&mut stream, e.to_string()))?;
+    // finish the handshake along with the encrypted fmd key
+    let mut records = progress.records;
+    match conn.encrypt(&serde_cbor::to_vec(&key_reg).unwrap()) {
+        Ok(encrypted) => records.extend(encrypted),
+        Err(e) => return Err(abort_tls(&mut stream, e.to_string()).await),
+    }
+    stream.write(ClientMsg::TlsRecords(records)).await?;
 
-    // encrypt the fmd key and send it to the enclave
-    let key_reg = FmdKeyRegistration {
-        fmd_key: detection_key,
-        enc_key: encryption_key,
-        birthday,
-    };
-    let cipher = conn
-        .encrypt_msg(&serde_cbor::to_vec(&key_reg).unwrap(), &mut rng)
-        .expect("RA-TLS should already be initialized");
-    stream.write(ClientMsg::RATLSAck(AckType::Success(cipher)));
+    await_registration(&mut stream).await?;
+    Ok((checkpoint_key, None))
+}
 
-    // wait for response from server if entire procedure was successful
-    match stream.read() {
+/// Wait for the service to acknowledge a registration
+async fn await_registration(stream: &mut AsyncTcp) -> error::Result<()> {
+    match stream.read().await {
         Ok(ServerMsg::KeyRegSuccess) => {
             tracing::info!("Key registered successfully");
             Ok(())
@@ -128,9 +454,10 @@ fn register_fmd_key_to_service<C: EnclaveClient>(
     }
 }
 
-fn abort_tls(stream: &mut OutgoingTcp, msg: impl AsRef<str>) -> error::Error {
+async fn abort_tls(stream: &mut AsyncTcp, msg: impl AsRef<str>) -> error::Error {
     let msg = msg.as_ref();
-    stream.write(ClientMsg::RATLSAck(AckType::Fail));
+    // the handshake is being abandoned either way
+    let _ = stream.write(ClientMsg::RATLSAck(AckType::Fail)).await;
     tracing::error!(msg);
     Error::RATLS(msg.to_string())
 }
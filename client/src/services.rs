@@ -0,0 +1,367 @@
+//! Managing the services a key is registered with. Each service holds a
+//! detection key extracted from the FMD key, and the results of a key's
+//! services can only be combined if these are distinct detection keys
+//! of the same extraction. Services are removed, replaced and their
+//! detection keys rebalanced so that this remains the case.
+
+use fmd::fmd2_compact::MultiFmd2CompactScheme;
+use fmd::{DetectionKey, FmdSecretKey, MultiFmdScheme};
+use shared::db::EncKey;
+use shared::lease::{Lease, Renewal};
+use shared::ratls::FmdParams;
+use shared::update::Update;
+
+use crate::config::{Config, Service, Share};
+use crate::error::{self, Error};
+use crate::lease::renew_with_service;
+use crate::sequence_number;
+use crate::update::update_with_service;
+#[cfg(any(feature = "tdx", feature = "transparent"))]
+use crate::{
+    com::{Timeouts, block_on},
+    ratls::{self, Handshake, Registration},
+};
+
+/// The number of detection keys to extract so that each service
+/// has the one of its index
+pub(crate) fn shares(services: &[Service]) -> usize {
+    services.iter().map(|s| s.index).max().unwrap_or_default()
+}
+
+/// The number of subkeys of the detection keys services hold. New
+/// detection keys are given as many, so that they are consistent.
+fn subkeys(services: &[Service]) -> usize {
+    services
+        .iter()
+        .filter_map(|s| s.share.map(|share| share.subkeys))
+        .max()
+        .unwrap_or(1)
+}
+
+/// Extract `count` detection keys with `subkeys` subkeys each
+pub(crate) fn extract(
+    fmd_key: &FmdSecretKey,
+    params: FmdParams,
+    count: usize,
+    subkeys: usize,
+) -> error::Result<Vec<DetectionKey>> {
+    if count == 0 {
+        return Ok(vec![]);
+    }
+    let scheme = MultiFmd2CompactScheme::new(params.gamma, params.threshold);
+    scheme
+        .multi_extract(fmd_key, count, 1, subkeys, subkeys * count)
+        .ok_or_else(|| {
+            Error::Extraction(format!(
+                "Cannot extract detection keys with {subkeys} subkeys for {count} services \
+                 from a key with {} subkeys",
+                params.gamma
+            ))
+        })
+}
+
+/// Stop a service from performing FMD for a key. Its lease is renewed
+/// to run out immediately, after which the service drops the key and
+/// its results.
+pub fn deregister(service: &Service) -> error::Result<()> {
+    let renewal = Renewal {
+        lease: Lease::Duration(0),
+        sequence: sequence_number(),
+    };
+    renew_with_service(&service.url, &service.enc_key, &renewal)?;
+    tracing::info!("Service < {} >: Key deregistered", service.url);
+    Ok(())
+}
+
+/// Deregister a key from a service and remove the service from the
+/// config. If `force` is set, the service is removed even if it could
+/// not be deregistered from, e.g. because it is offline for good.
+///
+/// The detection keys of the remaining services are unaffected, but
+/// [`rebalance`] can be used to redistribute the removed service's
+/// subkeys among them.
+pub fn remove_service(
+    config: &mut Config,
+    key_hash: &String,
+    url: &str,
+    force: bool,
+) -> error::Result<Service> {
+    let service = find_service(config, key_hash, url)?;
+    deregister_unless_forced(&service, force)?;
+    Ok(config
+        .remove_service(key_hash, url)
+        .expect("The service was found above"))
+}
+
+/// Deregister a key from a service and replace it in the config with
+/// a new one, which is to hold the same detection key. The new service
+/// must then be registered with, e.g. with [`register_pending`].
+pub fn replace_service(
+    config: &mut Config,
+    key_hash: &String,
+    url: &str,
+    new_url: &str,
+    uuid: &str,
+    enc_key: EncKey,
+    force: bool,
+) -> error::Result<Service> {
+    let service = find_service(config, key_hash, url)?;
+    deregister_unless_forced(&service, force)?;
+    Ok(config
+        .replace_service(key_hash, url, new_url, uuid, enc_key)
+        .expect("The service was found above"))
+}
+
+/// Pin a new UUID for the host of a service, e.g. after it was
+/// reinstalled, along with the encryption key derived from it. The
+/// service then holds the same detection key, but must be registered
+/// with again, e.g. with [`register_pending`]. It is not deregistered
+/// from, as its host no longer knows the registration.
+pub fn repin_service(
+    config: &mut Config,
+    key_hash: &String,
+    url: &str,
+    uuid: &str,
+    enc_key: EncKey,
+) -> error::Result<Service> {
+    config
+        .replace_service(key_hash, url, url, uuid, enc_key)
+        .ok_or_else(|| Error::UnknownService(url.to_string()))
+}
+
+/// Renumber the services of a key so that their detection keys are
+/// consecutive shares of one extraction, and update the detection key
+/// of each registered service whose share changed. Updated services
+/// detect again from the birthday of their registration. Services that
+/// are not registered yet are left to [`register_pending`]. If an update
+/// fails, it and the services after it keep their indices and shares.
+pub fn rebalance(
+    config: &mut Config,
+    key_hash: &String,
+    fmd_key: &FmdSecretKey,
+    params: FmdParams,
+) -> error::Result<()> {
+    let Some(services) = config.services.get_mut(key_hash) else {
+        return Ok(());
+    };
+    services.sort_by_key(|s| s.index);
+    let subkeys = subkeys(services);
+    let detection_keys = extract(fmd_key, params, services.len(), subkeys)?;
+    let sequence = sequence_number();
+    // A service only takes its new index once it holds the matching
+    // detection key. As new indices never exceed the old ones, the
+    // services left after a failed update keep distinct indices.
+    for (ix, service) in services.iter_mut().enumerate() {
+        let share = Share {
+            index: ix + 1,
+            subkeys,
+        };
+        if service.share.is_some_and(|current| current != share) {
+            let update = Update {
+                enc_key: None,
+                detection_key: Some((detection_keys[ix].clone(), params)),
+                reset: true,
+                sequence,
+            };
+            update_with_service(&service.url, &service.enc_key, &update)?;
+            service.share = Some(share);
+        }
+        service.index = ix + 1;
+    }
+    Ok(())
+}
+
+/// Registers an fmd key with the services of the config file that it
+/// has not been registered with yet, giving them detection keys
+/// consistent with those of the other services.
+#[cfg(any(feature = "tdx", feature = "transparent"))]
+pub fn register_pending(
+    config: &mut Config,
+    key_hash: &String,
+    fmd_key: &FmdSecretKey,
+    params: FmdParams,
+    birthday: Option<u64>,
+    lease: Option<Lease>,
+    handshake: Handshake,
+) -> error::Result<()> {
+    let registration = Registration {
+        params,
+        subkeys: subkeys(&config.get_services(key_hash)),
+        birthday,
+        lease,
+        replace: false,
+        handshake,
+    };
+    block_on(ratls::register_services::<crate::Backend>(
+        config,
+        key_hash,
+        fmd_key,
+        registration,
+        |s| s.share.is_none(),
+        &Timeouts::default(),
+    ))
+}
+
+/// Find a configured service of a key
+fn find_service(config: &Config, key_hash: &String, url: &str) -> error::Result<Service> {
+    config
+        .get_services(key_hash)
+        .into_iter()
+        .find(|s| s.url == url)
+        .ok_or_else(|| Error::UnknownService(url.to_string()))
+}
+
+/// Deregister from a service, only warning of failures if `force` is set
+fn deregister_unless_forced(service: &Service, force: bool) -> error::Result<()> {
+    match deregister(service) {
+        Err(e) if force => {
+            tracing::warn!(
+                "Service < {} >: Could not deregister, removing it anyway: {e}",
+                service.url
+            );
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fmd::KeyExpansion;
+    use fmd::fmd2_compact::CompactSecretKey;
+    use shared::db::test_enc_key;
+
+    use super::*;
+
+    const KEY_HASH: &str = "key";
+
+    fn expand(gamma: usize) -> (FmdSecretKey, FmdParams) {
+        let csk_key = CompactSecretKey::derive_from_xof_stream(1, |buf| buf.fill(1));
+        let cpk_key = csk_key.master_public_key();
+        let params = FmdParams {
+            gamma,
+            threshold: cpk_key.threshold(),
+        };
+        let mut scheme = MultiFmd2CompactScheme::new(params.gamma, params.threshold);
+        let (fmd_key, _) = scheme.expand_keypair(&csk_key, &cpk_key);
+        (fmd_key, params)
+    }
+
+    fn service(url: &str, index: usize, share: Option<usize>) -> Service {
+        Service {
+            url: url.to_string(),
+            uuid: Some(format!("uuid-{url}")),
+            index,
+            enc_key: test_enc_key(index as u8),
+            sequence: 7,
+            checkpoint_key: Some([index as u8; 32].into()),
+            share: share.map(|index| Share { index, subkeys: 1 }),
+        }
+    }
+
+    fn config(services: Vec<Service>) -> Config {
+        let mut config = Config::default();
+        config.services.insert(KEY_HASH.to_string(), services);
+        config
+    }
+
+    fn indices(config: &Config) -> Vec<(usize, Option<usize>)> {
+        config.services[KEY_HASH]
+            .iter()
+            .map(|s| (s.index, s.share.map(|share| share.index)))
+            .collect()
+    }
+
+    /// Test that repinning a service keeps its index, but resets what
+    /// it held of its registration with the old host
+    #[test]
+    fn test_repin_service() {
+        let key_hash = KEY_HASH.to_string();
+        let mut config = config(vec![service("a", 1, Some(1)), service("b", 2, Some(2))]);
+        let old = repin_service(&mut config, &key_hash, "b", "new", test_enc_key(9))
+            .expect("Test failed");
+        assert_eq!(old.uuid.as_deref(), Some("uuid-b"));
+        let repinned = &config.services[KEY_HASH][1];
+        assert_eq!(repinned.url, "b");
+        assert_eq!(repinned.uuid.as_deref(), Some("new"));
+        assert_eq!(repinned.index, 2);
+        assert_eq!(repinned.enc_key.hash(), test_enc_key(9).hash());
+        assert_eq!(repinned.sequence, 0);
+        assert_eq!(repinned.checkpoint_key, None);
+        assert_eq!(repinned.share, None);
+        assert_eq!(config.services[KEY_HASH][0].sequence, 7);
+
+        let unknown = repin_service(&mut config, &key_hash, "c", "new", test_enc_key(9));
+        assert!(matches!(unknown, Err(Error::UnknownService(url)) if url == "c"));
+    }
+
+    /// Test the number of shares and subkeys derived from the services
+    #[test]
+    fn test_shares_and_subkeys() {
+        assert_eq!(shares(&[]), 0);
+        assert_eq!(subkeys(&[]), 1);
+        let mut services = vec![service("a", 1, Some(1)), service("b", 3, None)];
+        assert_eq!(shares(&services), 3);
+        assert_eq!(subkeys(&services), 1);
+        services[0].share = Some(Share {
+            index: 1,
+            subkeys: 2,
+        });
+        assert_eq!(subkeys(&services), 2);
+    }
+
+    /// Test that services holding their shares and unregistered services
+    /// are renumbered without contacting them
+    #[test]
+    fn test_rebalance_renumbers() {
+        let (fmd_key, params) = expand(4);
+        let mut config = config(vec![
+            service("c", 5, None),
+            service("a", 1, Some(1)),
+            service("b", 3, None),
+        ]);
+        rebalance(&mut config, &KEY_HASH.to_string(), &fmd_key, params).expect("Test failed");
+        assert_eq!(indices(&config), [(1, Some(1)), (2, None), (3, None)]);
+        let urls: Vec<_> = config.services[KEY_HASH].iter().map(|s| &s.url).collect();
+        assert_eq!(urls, ["a", "b", "c"]);
+
+        // Keys without services are left alone
+        rebalance(&mut config, &"other".to_string(), &fmd_key, params).expect("Test failed");
+        assert!(!config.services.contains_key("other"));
+    }
+
+    /// Test that failing to extract the detection keys leaves the
+    /// services untouched
+    #[test]
+    fn test_rebalance_extraction_fails() {
+        let (fmd_key, params) = expand(4);
+        let mut services = vec![
+            service("a", 2, Some(2)),
+            service("b", 4, None),
+            service("c", 6, None),
+        ];
+        services[0].share = Some(Share {
+            index: 2,
+            subkeys: 2,
+        });
+        let mut config = config(services);
+        let result = rebalance(&mut config, &KEY_HASH.to_string(), &fmd_key, params);
+        assert!(matches!(result, Err(Error::Extraction(_))));
+        assert_eq!(indices(&config), [(2, Some(2)), (4, None), (6, None)]);
+    }
+
+    /// Test that a service whose update fails keeps its index and share,
+    /// as do the services after it
+    #[test]
+    fn test_rebalance_update_fails() {
+        let (fmd_key, params) = expand(4);
+        let mut config = config(vec![
+            service("b", 2, None),
+            service("127.0.0.1:1", 3, Some(3)),
+            service("c", 5, None),
+        ]);
+        let result = rebalance(&mut config, &KEY_HASH.to_string(), &fmd_key, params);
+        assert!(result.is_err());
+        assert_eq!(indices(&config), [(1, None), (3, Some(3)), (5, None)]);
+    }
+}
@@ -0,0 +1,111 @@
+//! Functions for changing the registrations of a key without
+//! registering it again. Updates are sealed with the encryption key
+//! currently shared with each service.
+
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use fmd::FmdSecretKey;
+use rand_core::OsRng;
+use shared::db::EncKey;
+use shared::ratls::FmdParams;
+use shared::update::{KeyUpdate, Update};
+use shared::{ClientMsg, ServerMsg};
+
+use crate::com::OutgoingTcp;
+use crate::config::{Config, Share};
+use crate::error::{self, Error};
+use crate::sequence_number;
+use crate::services::{extract, shares};
+
+/// The changes to make to the registrations of a key
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeyChanges {
+    /// Replace the encryption key shared with each service. Rotated keys
+    /// are random rather than derived from the FMD key and the host's
+    /// UUID, so they exist only in the config and cannot be recovered
+    /// if it is lost.
+    pub rotate_enc_key: bool,
+    /// Replace the detection keys with ones having this many subkeys
+    /// each, i.e. a false-positive rate of 2^-subkeys.
+    pub subkeys: Option<usize>,
+    /// Discard the index sets computed so far and detect again from
+    /// the birthday of the registration
+    pub reset: bool,
+}
+
+/// Update the registrations of a key with all services it is registered
+/// to. Rotated encryption keys are written to the config, which the caller
+/// should then save. Fails with [`Error::NotRegistered`] if the key has
+/// no services.
+pub fn update_key(
+    config: &mut Config,
+    key_hash: &String,
+    fmd_key: &FmdSecretKey,
+    params: FmdParams,
+    changes: KeyChanges,
+) -> error::Result<()> {
+    let Some(services) = config
+        .services
+        .get_mut(key_hash)
+        .filter(|services| !services.is_empty())
+    else {
+        return Err(Error::NotRegistered(key_hash.clone()));
+    };
+    let detection_keys = changes
+        .subkeys
+        .map(|subkeys| extract(fmd_key, params, shares(services), subkeys))
+        .transpose()?;
+    let sequence = sequence_number();
+    for service in services.iter_mut() {
+        let enc_key: Option<EncKey> = changes
+            .rotate_enc_key
+            .then(|| ChaCha20Poly1305::generate_key(&mut OsRng).into());
+        let detection_key = detection_keys
+            .as_ref()
+            .map(|keys| (keys[service.index - 1].clone(), params));
+        let update = Update {
+            enc_key: enc_key.clone(),
+            detection_key,
+            reset: changes.reset,
+            sequence,
+        };
+        update_with_service(&service.url, &service.enc_key, &update)?;
+        if let Some(enc_key) = enc_key {
+            service.enc_key = enc_key;
+        }
+        if let Some(subkeys) = changes.subkeys {
+            service.share = Some(Share {
+                index: service.index,
+                subkeys,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Send an update to a particular service
+pub(crate) fn update_with_service(
+    url: &str,
+    enc_key: &EncKey,
+    update: &Update,
+) -> error::Result<()> {
+    let mut stream = OutgoingTcp::new(url)?;
+    stream.write(ClientMsg::UpdateKey(KeyUpdate::seal(
+        update, enc_key, OsRng,
+    )))?;
+    match stream.read() {
+        Ok(ServerMsg::KeyUpdated) => {
+            tracing::info!("Service < {url} >: Key updated");
+            Ok(())
+        }
+        Ok(ServerMsg::Error(err)) => {
+            tracing::error!("Service < {url} >: Key update failed: {err}");
+            Err(Error::ServerError(err))
+        }
+        _ => {
+            tracing::error!("Service < {url} >: Received unexpected message from service");
+            Err(Error::ServerError(
+                "Received unexpected message from service".to_string(),
+            ))
+        }
+    }
+}
@@ -1,18 +1,66 @@
 //! The fuzzy message detection logic the enclave must perform
 
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::string::ToString;
 use alloc::vec::Vec;
 
-use chacha20poly1305::aead::Aead;
+use chacha20poly1305::aead::{Aead, Payload};
 use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
 use fmd::MultiFmdScheme;
 use fmd::fmd2_compact::FlagCiphertexts;
 use shared::MsgToHost;
-use shared::db::{EncKey, EncryptedResponse, Index};
+use shared::checkpoint::flags_digest;
+use shared::config::PaddingPolicy;
+use shared::db::{EncKey, EncryptedResponse, Index, IndexList, RESPONSE_VERSION};
+use shared::lease::Lease;
 use shared::ratls::FmdKeyRegistration;
 use shared::tee::{EnclaveComm, EnclaveRNG, RemoteAttestation};
 
 use crate::Ctx;
+use crate::lease::Expiry;
+use crate::registry::Registry;
+use crate::schedule::Assignment;
+
+/// A key registered with the enclave along with the
+/// state of FMD performed for it.
+pub struct RegisteredKey {
+    pub key: FmdKeyRegistration,
+    pub indices: IndexSet,
+    /// When the registration's lease runs out
+    pub(crate) expiry: Expiry,
+    /// The sequence number of the last authenticated message
+    /// accepted from the owner
+    sequence: u64,
+}
+
+impl RegisteredKey {
+    /// Start detecting for a key from its birthday. Keys without a lease
+    /// are granted the default one.
+    pub(crate) fn new(key: FmdKeyRegistration, default_lease: Option<Lease>, now: u64) -> Self {
+        let expiry = Expiry::new(key.lease.or(default_lease), now);
+        Self {
+            indices: IndexSet::from(key.birthday.unwrap_or(1)),
+            key,
+            expiry,
+            sequence: 0,
+        }
+    }
+
+    /// The sequence number of the last message accepted from the owner
+    pub(crate) fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Record the sequence number of a message from the owner, rejecting
+    /// it if it is not newer than the last one accepted.
+    pub(crate) fn advance_sequence(&mut self, sequence: u64) -> Result<(), &'static str> {
+        if sequence <= self.sequence {
+            return Err("message is older than the last one accepted");
+        }
+        self.sequence = sequence;
+        Ok(())
+    }
+}
 
 /// The current status of which MASP txs a user
 /// should trial decrypt
@@ -23,6 +71,8 @@ pub struct IndexSet {
     /// The indexed txs corresponding the MASP txs that
     /// the user should trial decrypt. Stored here opaquely
     pub indices: Vec<Index>,
+    /// The sequence number of the last result produced
+    sequence: u64,
 }
 
 impl Default for IndexSet {
@@ -30,6 +80,7 @@ impl Default for IndexSet {
         Self {
             synced_to: 1,
             indices: Vec::new(),
+            sequence: 0,
         }
     }
 }
@@ -39,11 +90,17 @@ impl From<u64> for IndexSet {
         Self {
             synced_to: value,
             indices: Vec::new(),
+            sequence: 0,
         }
     }
 }
 
 impl IndexSet {
+    /// The sequence number of the last result produced
+    pub(crate) fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
     /// The next block height to perform FMD on.
     pub(crate) fn next(&self) -> u64 {
         self.synced_to + 1
@@ -54,41 +111,73 @@ impl IndexSet {
         self.synced_to += 1;
     }
 
-    /// Add the encryption of `self` to the
-    /// results contained in the response to the host.
-    fn add_result(&self, enc_key: &EncKey, nonce: Nonce, msg: MsgToHost) -> MsgToHost {
+    /// Discard all indices and restart detection from the given height.
+    /// Sequence numbers keep increasing across resets.
+    pub(crate) fn reset(&mut self, synced_to: u64) {
+        self.synced_to = synced_to;
+        self.indices.clear();
+    }
+
+    /// Add the encryption of `self`, padded according to the
+    /// given policy, to the results contained in the response
+    /// to the host.
+    ///
+    /// The owner, height, sequence number and format version of the
+    /// response are authenticated as associated data.
+    fn add_result(
+        &mut self,
+        enc_key: &EncKey,
+        nonce: Nonce,
+        padding: &PaddingPolicy,
+        msg: MsgToHost,
+    ) -> MsgToHost {
+        self.sequence += 1;
+        let mut response = EncryptedResponse {
+            owner: enc_key.hash(),
+            nonce: *nonce.as_ref(),
+            indices: Vec::new(),
+            height: self.synced_to,
+            sequence: self.sequence,
+            version: RESPONSE_VERSION,
+        };
         let cipher = ChaCha20Poly1305::new(enc_key.into());
-        match cipher.encrypt(&nonce, self.index_bytes().as_slice()) {
+        let bytes = IndexList::to_padded_bytes(&self.indices, padding);
+        let payload = Payload {
+            msg: &bytes,
+            aad: &response.associated_data(),
+        };
+        match cipher.encrypt(&nonce, payload) {
             Err(e) => MsgToHost::Error(e.to_string()),
             Ok(indices) => match msg {
                 msg @ MsgToHost::Error(_) => msg,
-                MsgToHost::FmdResults(mut results) => {
-                    results.push(EncryptedResponse {
-                        owner: enc_key.hash(),
-                        nonce: *nonce.as_ref(),
-                        indices,
-                        height: self.synced_to,
-                    });
-                    MsgToHost::FmdResults(results)
+                MsgToHost::FmdResults {
+                    mut results,
+                    checkpoints,
+                } => {
+                    response.indices = indices;
+                    results.push(response);
+                    MsgToHost::FmdResults {
+                        results,
+                        checkpoints,
+                    }
                 }
                 _ => unreachable!(),
             },
         }
     }
-
-    fn index_bytes(&self) -> Vec<u8> {
-        self.indices.iter().flat_map(|ix| ix.as_bytes()).collect()
-    }
 }
 
-/// Check the input flags against all registered keys.
+/// Check the input flags against the keys scheduled for this round.
 ///
-/// On success, add this flag's index to the registered key's data.
-/// Creates a message for the host with encrypted versions of each
-/// key's updated index sets.
+/// Each scheduled key is advanced to its assigned height (but never past
+/// the height the host is synced to). On success, add this flag's index to
+/// the registered key's data. Creates a message for the host with encrypted
+/// versions of each advanced key's updated index sets, along with signed
+/// checkpoints of each block height whose flags were consumed.
 pub fn check_flags<RA, COM, RNG>(
     ctx: &mut Ctx<RA, COM, RNG>,
-    registered_keys: &mut [(FmdKeyRegistration, IndexSet)],
+    registry: &mut Registry,
+    round: &[Assignment],
     synced_to: u64,
     flags: Vec<(Index, Option<FlagCiphertexts>)>,
 ) -> MsgToHost
@@ -97,27 +186,64 @@ where
     COM: EnclaveComm,
     RNG: EnclaveRNG,
 {
-    let mut response = MsgToHost::FmdResults(Vec::new());
-    for (key, indices) in registered_keys
-        .iter_mut()
-        .filter(|(_, ix)| ix.synced_to < synced_to)
-    {
-        for (ix, flag) in flags
-            .iter()
-            .filter(|(ix, _)| ix.height == indices.synced_to + 1)
-        {
-            if match flag {
-                None => true,
-                Some(flag) => ctx.scheme.detect(&key.fmd_key, flag),
-            } {
-                indices.indices.push(*ix);
+    let mut flags_by_height = BTreeMap::<u64, Vec<_>>::new();
+    for (ix, flag) in flags {
+        flags_by_height
+            .entry(ix.height)
+            .or_default()
+            .push((ix, flag));
+    }
+    let mut response = MsgToHost::FmdResults {
+        results: Vec::new(),
+        checkpoints: Vec::new(),
+    };
+    let mut consumed = BTreeSet::new();
+    for (owner, target) in round {
+        let Some(RegisteredKey {
+            key,
+            indices,
+            expiry,
+            ..
+        }) = registry.get_mut(owner)
+        else {
+            continue;
+        };
+        let target = synced_to.min(*target).min(expiry.max_height());
+        if indices.synced_to >= target {
+            continue;
+        }
+        while indices.synced_to < target {
+            consumed.insert(indices.next());
+            for (ix, flag) in flags_by_height
+                .get(&indices.next())
+                .map(Vec::as_slice)
+                .unwrap_or_default()
+            {
+                if match flag {
+                    None => true,
+                    Some(flag) => ctx.scheme(key.params).detect(&key.fmd_key, flag),
+                } {
+                    indices.indices.push(*ix);
+                }
             }
+            indices.advance();
         }
         let mut nonce_bytes = [0u8; 12];
         ctx.rng.fill_bytes(&mut nonce_bytes);
         let nonce = Nonce::from(nonce_bytes);
-        response = indices.add_result(&key.enc_key, nonce, response);
-        indices.advance();
+        response = indices.add_result(&key.enc_key, nonce, &ctx.config.padding, response);
+    }
+    if let MsgToHost::FmdResults { checkpoints, .. } = &mut response {
+        *checkpoints = consumed
+            .into_iter()
+            .map(|height| {
+                let flags = flags_by_height
+                    .get(&height)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                ctx.checkpoint_key.sign(height, flags_digest(height, flags))
+            })
+            .collect();
     }
     response
 }
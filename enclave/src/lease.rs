@@ -0,0 +1,203 @@
+//! Expiring registrations whose lease has run out and renewing
+//! leases on behalf of their owners.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use shared::MsgToHost;
+use shared::lease::{Lease, LeaseRenewal, Renewal};
+use shared::tee::{EnclaveComm, EnclaveRNG, RemoteAttestation};
+
+use crate::Ctx;
+use crate::fmd::RegisteredKey;
+use crate::registry::Registry;
+
+/// The point at which a registration expires
+#[derive(Debug, Default)]
+pub(crate) struct Expiry {
+    /// The block height FMD is performed up to
+    height: Option<u64>,
+    /// The time after which FMD is no longer performed,
+    /// in seconds since the Unix epoch
+    time: Option<u64>,
+}
+
+impl Expiry {
+    /// Grant a lease at the given time. Without a lease, a
+    /// registration never expires.
+    pub fn new(lease: Option<Lease>, now: u64) -> Self {
+        let mut expiry = Self::default();
+        expiry.grant(lease, now);
+        expiry
+    }
+
+    /// Replace the current lease
+    pub fn grant(&mut self, lease: Option<Lease>, now: u64) {
+        (self.height, self.time) = match lease {
+            None => (None, None),
+            Some(Lease::Height(height)) => (Some(height), None),
+            Some(Lease::Duration(secs)) => (None, Some(now.saturating_add(secs))),
+        };
+    }
+
+    /// The block height and time the lease runs out at, if bounded by them
+    pub fn bounds(&self) -> (Option<u64>, Option<u64>) {
+        (self.height, self.time)
+    }
+
+    /// The highest block height the registration may be synced to
+    pub fn max_height(&self) -> u64 {
+        self.height.unwrap_or(u64::MAX)
+    }
+
+    /// Check if the lease has run out
+    pub fn is_expired(&self, synced_to: u64, now: u64) -> bool {
+        self.height.is_some_and(|h| synced_to >= h) || self.time.is_some_and(|t| now >= t)
+    }
+}
+
+/// Drop all registrations whose lease has run out and return their owners
+pub(crate) fn prune_expired(registry: &mut Registry, now: u64) -> Vec<String> {
+    let mut expired = Vec::new();
+    registry.retain(|owner, reg| {
+        if reg.expiry.is_expired(reg.indices.synced_to, now) {
+            expired.push(owner.clone());
+            false
+        } else {
+            true
+        }
+    });
+    expired
+}
+
+/// Renew the lease of a registration if the renewal was authenticated by
+/// its owner. Renewals of expired registrations are rejected.
+pub(crate) fn renew_lease<RA, COM, RNG>(
+    ctx: &mut Ctx<RA, COM, RNG>,
+    registry: &mut Registry,
+    renewal: LeaseRenewal,
+) where
+    RA: RemoteAttestation,
+    COM: EnclaveComm,
+    RNG: EnclaveRNG,
+{
+    let Some(reg) = registry.get_mut(&renewal.owner) else {
+        ctx.reply_client_err("No active registration found for lease renewal");
+        return;
+    };
+    match apply_renewal(reg, &renewal, ctx.now) {
+        Ok(()) => ctx.reply(MsgToHost::LeaseRenewed),
+        Err(e) => ctx.reply_client_err(&e),
+    }
+}
+
+/// Grant the lease of a renewal to a registration, if the renewal is
+/// authenticated by its owner and newer than any message accepted before.
+fn apply_renewal(reg: &mut RegisteredKey, renewal: &LeaseRenewal, now: u64) -> Result<(), String> {
+    let Renewal { lease, sequence } = renewal
+        .open(&reg.key.enc_key)
+        .map_err(|e| format!("Lease renewal rejected: {e}"))?;
+    reg.advance_sequence(sequence)
+        .map_err(|e| format!("Lease renewal rejected: {e}"))?;
+    reg.expiry.grant(Some(lease), now);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use fmd::fmd2_compact::{CompactSecretKey, MultiFmd2CompactScheme};
+    use fmd::{KeyExpansion, MultiFmdScheme};
+    use rand_core::OsRng;
+    use shared::db::{EncKey, test_enc_key};
+    use shared::ratls::{FmdKeyRegistration, FmdParams};
+
+    use super::*;
+
+    fn registered(enc_key: EncKey, lease: Option<Lease>) -> RegisteredKey {
+        let params = FmdParams::default();
+        let csk = CompactSecretKey::derive_from_xof_stream(params.threshold, |buf| buf.fill(7));
+        let mut scheme = MultiFmd2CompactScheme::new(params.gamma, params.threshold);
+        let (sk, _) = scheme.expand_keypair(&csk, &csk.master_public_key());
+        let fmd_key = scheme
+            .multi_extract(&sk, 1, 1, 1, 1)
+            .expect("Test failed")
+            .remove(0);
+        let key = FmdKeyRegistration {
+            fmd_key,
+            params,
+            enc_key,
+            birthday: None,
+            lease,
+            replace: false,
+        };
+        RegisteredKey::new(key, None, 100)
+    }
+
+    /// Test that leases run out at their height or time, and that
+    /// registrations without one never do.
+    #[test]
+    fn test_expiry() {
+        let expiry = Expiry::new(Some(Lease::Height(10)), 100);
+        assert_eq!(expiry.max_height(), 10);
+        assert!(!expiry.is_expired(9, u64::MAX));
+        assert!(expiry.is_expired(10, 0));
+
+        let mut expiry = Expiry::new(Some(Lease::Duration(50)), 100);
+        assert_eq!(expiry.bounds(), (None, Some(150)));
+        assert!(!expiry.is_expired(u64::MAX, 149));
+        assert!(expiry.is_expired(0, 150));
+        expiry.grant(Some(Lease::Duration(u64::MAX)), 100);
+        assert!(!expiry.is_expired(0, u64::MAX - 1));
+
+        let expiry = Expiry::new(None, 100);
+        assert_eq!(expiry.max_height(), u64::MAX);
+        assert!(!expiry.is_expired(u64::MAX, u64::MAX));
+    }
+
+    /// Test that only the registrations whose lease has run out are pruned
+    #[test]
+    fn test_prune_expired() {
+        let mut registry = Registry::new();
+        let mut by_height = registered(test_enc_key(0), Some(Lease::Height(10)));
+        by_height.indices.synced_to = 10;
+        registry.insert("height".into(), by_height);
+        registry.insert(
+            "time".into(),
+            registered(test_enc_key(1), Some(Lease::Duration(50))),
+        );
+        registry.insert("forever".into(), registered(test_enc_key(2), None));
+
+        assert_eq!(prune_expired(&mut registry, 149), ["height"]);
+        assert_eq!(registry.len(), 2);
+        assert_eq!(prune_expired(&mut registry, 150), ["time"]);
+        assert!(registry.contains_key("forever"));
+        assert!(prune_expired(&mut registry, u64::MAX).is_empty());
+    }
+
+    /// Test that renewals are only applied if sealed with the
+    /// registration's key and newer than the last message accepted.
+    #[test]
+    fn test_apply_renewal() {
+        let mut reg = registered(test_enc_key(0), Some(Lease::Height(10)));
+        let renewal = |lease, sequence, key: &EncKey| {
+            LeaseRenewal::seal(&Renewal { lease, sequence }, key, OsRng)
+        };
+
+        let renewed = renewal(Lease::Height(20), 1, &test_enc_key(0));
+        assert!(apply_renewal(&mut reg, &renewed, 100).is_ok());
+        assert_eq!(reg.expiry.max_height(), 20);
+
+        let replayed = renewed.clone();
+        assert!(apply_renewal(&mut reg, &replayed, 100).is_err());
+        let stale = renewal(Lease::Height(30), 1, &test_enc_key(0));
+        assert!(apply_renewal(&mut reg, &stale, 100).is_err());
+        let forged = renewal(Lease::Height(30), 2, &test_enc_key(1));
+        assert!(apply_renewal(&mut reg, &forged, 100).is_err());
+        assert_eq!(reg.expiry.max_height(), 20);
+
+        let renewed = renewal(Lease::Duration(0), 2, &test_enc_key(0));
+        assert!(apply_renewal(&mut reg, &renewed, 100).is_ok());
+        assert!(reg.expiry.is_expired(0, 100));
+    }
+}
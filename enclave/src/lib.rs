@@ -2,14 +2,22 @@
 extern crate alloc;
 
 use ::fmd::fmd2_compact::MultiFmd2CompactScheme;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::string::ToString;
-use alloc::vec::Vec;
+use shared::checkpoint::CheckpointKey;
+use shared::config::EnclaveConfig;
+use shared::ratls::FmdParams;
+use shared::status::{EnclaveStatus, SyncedTo};
 use shared::tee::{EnclaveComm, EnclaveRNG, RemoteAttestation};
-use shared::{MsgFromHost, MsgToHost};
+use shared::{EnclaveReply, HostRequest, MsgFromHost, MsgToHost};
 
-use crate::fmd::{IndexSet, check_flags};
-
-const GAMMA: usize = 20;
+use crate::fmd::check_flags;
+use crate::lease::{prune_expired, renew_lease};
+use crate::ratls::{
+    Sessions, close_session, finish_tls_session, open_session, open_tls_session, session_request,
+};
+use crate::registry::{Registry, register, update_key};
+use crate::schedule::Scheduler;
 
 #[derive(Clone)]
 struct Ctx<RA, COM, RNG>
@@ -21,7 +29,19 @@ where
     ra: RA,
     com: COM,
     rng: RNG,
-    scheme: MultiFmd2CompactScheme,
+    config: EnclaveConfig,
+    /// The latest time reported by the host, in seconds
+    /// since the Unix epoch
+    now: u64,
+    /// An FMD scheme per parameter set of the registered keys. The
+    /// number of distinct sets is bounded when keys are registered,
+    /// and schemes no longer needed are dropped each round.
+    schemes: BTreeMap<FmdParams, MultiFmd2CompactScheme>,
+    /// The key checkpoints are signed with. It never leaves
+    /// the enclave.
+    checkpoint_key: CheckpointKey,
+    /// The id of the request being handled, echoed in its reply
+    request: Option<u64>,
 }
 
 impl<RA, COM, RNG> Ctx<RA, COM, RNG>
@@ -31,17 +51,52 @@ where
     RNG: EnclaveRNG,
 {
     pub fn init() -> Self {
+        let mut rng = RNG::init();
+        let checkpoint_key = CheckpointKey::generate(&mut rng);
         Self {
             ra: RA::init(),
             com: COM::init(),
-            rng: RNG::init(),
-            scheme: MultiFmd2CompactScheme::new(GAMMA, 1),
+            rng,
+            config: EnclaveConfig::default(),
+            now: 0,
+            schemes: BTreeMap::new(),
+            checkpoint_key,
+            request: None,
         }
     }
+
+    /// Reply to the request being handled
+    pub fn reply(&mut self, msg: MsgToHost) {
+        // If the host has disconnected, this is noticed on the next read
+        let _ = self.com.write(&EnclaveReply {
+            id: self.request,
+            msg,
+        });
+    }
+
+    /// Reply to the request being handled with an error for the host
+    pub fn reply_err(&mut self, err: &str) {
+        self.reply(MsgToHost::Error(err.to_string()))
+    }
+
+    /// Reply to the request being handled with an error for the client
+    pub fn reply_client_err(&mut self, err: &str) {
+        self.reply(MsgToHost::ErrorForClient(err.to_string()))
+    }
+
+    /// Get the FMD scheme for the given parameters
+    pub fn scheme(&mut self, params: FmdParams) -> &mut MultiFmd2CompactScheme {
+        self.schemes
+            .entry(params)
+            .or_insert_with(|| MultiFmd2CompactScheme::new(params.gamma, params.threshold))
+    }
 }
 
 mod fmd;
+mod lease;
 pub mod ratls;
+mod registry;
+mod schedule;
 
 pub fn main<RA, COM, RNG>()
 where
@@ -50,36 +105,129 @@ where
     RNG: EnclaveRNG,
 {
     let mut ctx = Ctx::<RA, COM, RNG>::init();
-    let mut registered_keys = Vec::new();
+    let mut registry = Registry::new();
+    let mut sessions = Sessions::default();
+    let mut scheduler = Scheduler::default();
 
     loop {
-        match ctx.com.read() {
-            Ok(msg) => match msg {
-                MsgFromHost::RegisterKey { nonce, pk } => {
-                    if let Some(key) =
-                        ratls::register_key(&mut ctx, x25519_dalek::PublicKey::from(pk.0), nonce)
-                    {
-                        let synced_height = key.birthday.unwrap_or(1);
-                        registered_keys.push((key, IndexSet::from(synced_height)));
-                    }
-                }
-                MsgFromHost::RequestReport { user_data } => {
-                    let quote = ctx.ra.get_quote(user_data.0);
-                    ctx.com.write(&MsgToHost::Report(quote));
+        let msg = match ctx.com.read() {
+            Ok(HostRequest { id, msg }) => {
+                ctx.request = Some(id);
+                msg
+            }
+            Err(e) if e.is_disconnect() => {
+                // wait for the host to reconnect. Handshakes in progress
+                // cannot be completed over the new connection.
+                ctx.com = COM::init();
+                sessions = Sessions::default();
+                continue;
+            }
+            Err(e) => {
+                ctx.request = None;
+                ctx.reply_err(&e.to_string());
+                continue;
+            }
+        };
+        match msg {
+            MsgFromHost::RegisterKey {
+                session_id,
+                nonce,
+                pk,
+                version,
+            } => {
+                open_session(
+                    &mut ctx,
+                    &mut sessions,
+                    session_id,
+                    x25519_dalek::PublicKey::from(pk.0),
+                    nonce,
+                    version,
+                );
+            }
+            MsgFromHost::RATLSAck { session_id, ack } => {
+                if let Some(key) =
+                    close_session(&mut ctx, &mut sessions, &mut registry, session_id, ack)
+                {
+                    register(&mut ctx, &mut registry, key);
                 }
-                MsgFromHost::RequiredBlocks => {
-                    let heights = registered_keys.iter().map(|(_, ixs)| ixs.next()).collect();
-                    ctx.com.write(&MsgToHost::BlockRequests(heights));
+            }
+            MsgFromHost::SessionRequest { session_id, cipher } => {
+                session_request(&mut ctx, &mut sessions, &mut registry, session_id, cipher);
+            }
+            MsgFromHost::CloseSession { session_id } => sessions.close(session_id),
+            MsgFromHost::TlsHello {
+                session_id,
+                nonce,
+                records,
+            } => open_tls_session(&mut ctx, &mut sessions, session_id, nonce, &records),
+            MsgFromHost::TlsRecords {
+                session_id,
+                records,
+            } => {
+                if let Some(key) = finish_tls_session(&mut ctx, &mut sessions, session_id, &records)
+                {
+                    register(&mut ctx, &mut registry, key);
                 }
-                MsgFromHost::RequestedFlags { synced_to, flags } => {
-                    let response = check_flags(&mut ctx, &mut registered_keys, synced_to, flags);
-                    ctx.com.write(&response);
+            }
+            MsgFromHost::RequestReport { user_data } => {
+                let quote = ctx.ra.get_quote(user_data.0);
+                ctx.reply(MsgToHost::Report(quote));
+            }
+            MsgFromHost::Ping(nonce) => ctx.reply(MsgToHost::Pong(nonce)),
+            MsgFromHost::RequestStatus => {
+                ctx.reply(MsgToHost::Status(EnclaveStatus {
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    registered_keys: registry.len(),
+                    synced_to: SyncedTo::from_heights(
+                        registry.values().map(|reg| reg.indices.synced_to),
+                    ),
+                    pending_sessions: sessions.len(),
+                    config: ctx.config.clone(),
+                }));
+            }
+            MsgFromHost::Configure { config, now } => {
+                ctx.now = now;
+                if config.fp_rates.min_subkeys > config.fp_rates.max_subkeys {
+                    ctx.reply_err("Minimum false-positive rate exceeds the maximum");
+                } else if !config.padding.is_valid() {
+                    ctx.reply_err("Padding to a multiple of 0 indices is not possible");
+                } else {
+                    ctx.config = config;
+                    ctx.reply(MsgToHost::Configured);
                 }
-                _ => {}
-            },
-            Err(e) => {
-                ctx.com.write(&MsgToHost::Error(e.to_string()));
             }
+            MsgFromHost::RequiredBlocks { synced_to, now } => {
+                ctx.now = now;
+                sessions.prune(&ctx.config.sessions, now);
+                let expired = prune_expired(&mut registry, now);
+                let params: BTreeSet<_> = registry.values().map(|reg| reg.key.params).collect();
+                ctx.schemes.retain(|p, _| params.contains(p));
+                let heights = scheduler.plan(
+                    &ctx.config.schedule,
+                    registry.iter().map(|(owner, reg)| {
+                        (
+                            owner.as_str(),
+                            reg.indices.synced_to,
+                            reg.expiry.max_height(),
+                        )
+                    }),
+                    synced_to,
+                );
+                ctx.reply(MsgToHost::BlockRequests { heights, expired });
+            }
+            MsgFromHost::RenewLease(renewal) => {
+                renew_lease(&mut ctx, &mut registry, renewal);
+            }
+            MsgFromHost::UpdateKey(update) => {
+                update_key(&mut ctx, &mut registry, update);
+            }
+            MsgFromHost::RequestedFlags { synced_to, flags } => {
+                let response =
+                    check_flags(&mut ctx, &mut registry, scheduler.round(), synced_to, flags);
+                ctx.reply(response);
+            }
+            MsgFromHost::Resync => ctx.reply(MsgToHost::Resynced),
+            _ => {}
         }
         core::hint::spin_loop();
     }
@@ -3,83 +3,412 @@
 //! (remote attestation), and a Diffie-Hellman key exchange for
 //! secure communication.
 //!
-//! Currently, the only direct communication between enclaves and
-//! clients is registering clients' FMD detection keys with the
-//! enclave.
+//! Clients register their FMD detection keys with the enclave either
+//! over the bespoke handshake of [`shared::ratls`] or over TLS 1.3 with
+//! the attestation in the enclave's certificate (see [`shared::tls`]).
+//! From version 2 of the bespoke handshake, the session stays open after
+//! the first request, so that a client can register several keys and
+//! ask about them without attesting the enclave again.
+//!
+//! Sessions are multiplexed: the report is sent to the host and the
+//! enclave goes back to its other work until the client's next message
+//! arrives under the same session id. Sessions that sit idle are
+//! discarded after a timeout, so a slow client cannot stall FMD for
+//! everyone else.
 
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::format;
+use alloc::string::{String, ToString};
 
-use shared::ratls::{Connection, FmdKeyRegistration};
+use shared::config::SessionLimits;
+use shared::ratls::{
+    Connection, FmdKeyRegistration, HandshakeVersion, KeyConfirmation, Role, SecureSession,
+    SessionReply, SessionRequest, TlsCiphertext, Transcript, report_data,
+};
 use shared::tee::{EnclaveComm, EnclaveRNG, RemoteAttestation};
-use shared::{AckType, MsgFromHost, MsgToHost};
+use shared::tls::TlsServer;
+use shared::{AckType, HexBytes, MsgToHost};
 
 use crate::Ctx;
+use crate::registry::{Registry, add_registration, receipt};
+
+/// The enclave's end of a session
+enum Handshake {
+    /// A version 1 handshake awaiting its single message
+    Bespoke(Connection),
+    /// A version 2 handshake awaiting the client's key confirmation
+    Confirming {
+        session: Box<SecureSession>,
+        client_confirm: HexBytes<32>,
+    },
+    /// A confirmed version 2 session
+    Established {
+        session: Box<SecureSession>,
+        /// The owners of the registrations made in this session, which
+        /// the client may ask about
+        owners: BTreeSet<String>,
+    },
+    Tls(Box<TlsServer>),
+}
 
-/// Create a new TLS connection and add it to the list of active
-/// connections.
+/// A session waiting on its client's next message
+pub(crate) struct Session {
+    conn: Handshake,
+    /// When the client last sent a message, in seconds since the
+    /// Unix epoch
+    active: u64,
+}
+
+/// The open sessions indexed by their session id
+#[derive(Default)]
+pub(crate) struct Sessions(BTreeMap<u64, Session>);
+
+impl Sessions {
+    /// The number of open sessions
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Check that a new session with the given id can be opened,
+    /// replying with an error otherwise.
+    fn reserve<RA, COM, RNG>(&mut self, ctx: &mut Ctx<RA, COM, RNG>, session_id: u64) -> bool
+    where
+        RA: RemoteAttestation,
+        COM: EnclaveComm,
+        RNG: EnclaveRNG,
+    {
+        self.prune(&ctx.config.sessions, ctx.now);
+        if self.0.contains_key(&session_id) {
+            ctx.reply_err("Session id is already in use");
+            false
+        } else if self.0.len() >= ctx.config.sessions.max_open {
+            ctx.reply_client_err("Too many open sessions, try again later.");
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Discard the sessions that have been idle for longer than the timeout
+    pub(crate) fn prune(&mut self, limits: &SessionLimits, now: u64) {
+        self.0
+            .retain(|_, session| session.active.saturating_add(limits.timeout) > now);
+    }
+
+    /// Drop a session the client has left
+    pub(crate) fn close(&mut self, session_id: u64) {
+        self.0.remove(&session_id);
+    }
+}
+
+/// Create a new TLS connection and add it to the pending sessions.
 ///
 /// Creates a Remote Attestation report which signs over its ephemeral
-/// public key and a challenge nonce. This is sent to the client for verification.
-/// Upon success, the secure channel is used to send an FMD key to the enclave
-/// to be stored.
-pub(crate) fn register_key<RA, COM, RNG>(
+/// public key, a challenge nonce and a binding of its checkpoint key.
+/// This is sent to the client for verification.
+///
+/// From handshake version 2, the session keys are bound to the transcript
+/// including the report, and the reply carries the enclave's key
+/// confirmation.
+pub(crate) fn open_session<RA, COM, RNG>(
     ctx: &mut Ctx<RA, COM, RNG>,
+    sessions: &mut Sessions,
+    session_id: u64,
     pk: x25519_dalek::PublicKey,
     nonce: u64,
-) -> Option<FmdKeyRegistration>
-where
+    version: HandshakeVersion,
+) where
     RA: RemoteAttestation,
     COM: EnclaveComm,
     RNG: EnclaveRNG,
 {
+    if !sessions.reserve(ctx, session_id) {
+        return;
+    }
+
     // create a new connection and get the public ephemeral key
     let conn = Connection::new(ctx.rng.clone());
-    let enclave_pk = if let Connection::Handshake { ephemeral_key } = &conn {
-        x25519_dalek::PublicKey::from(ephemeral_key)
-    } else {
-        unreachable!()
-    };
+    let enclave_pk = conn
+        .public_key()
+        .expect("A new connection is in its handshake");
+
+    // generate Remote Attestation report
+    let checkpoint_key = ctx.checkpoint_key.verifying_key();
+    let report_data = report_data(&enclave_pk.to_bytes(), nonce, &checkpoint_key);
+    let quote = ctx.ra.get_quote(report_data);
 
     // initialize the connection and compute shared key
-    let conn = if let Ok(conn) = conn.initialize(pk) {
-        conn
-    } else {
-        ctx.com
-            .write_client_err("Failed to initialize TLS connection.");
+    let initialized = match version {
+        HandshakeVersion::V1 => conn
+            .initialize(pk)
+            .map(|conn| (Handshake::Bespoke(conn), None)),
+        HandshakeVersion::V2 => {
+            let transcript = Transcript {
+                client_pk: pk.as_bytes(),
+                nonce,
+                enclave_pk: enclave_pk.as_bytes(),
+                report: &quote,
+                checkpoint_key: &checkpoint_key,
+            };
+            conn.initialize_with_transcript(pk, &transcript, Role::Enclave)
+                .map(|(session, KeyConfirmation { client, enclave })| {
+                    let conn = Handshake::Confirming {
+                        session: Box::new(session),
+                        client_confirm: client,
+                    };
+                    (conn, Some(enclave))
+                })
+        }
+    };
+    let Ok((conn, confirm)) = initialized else {
+        ctx.reply_client_err("Failed to initialize TLS connection.");
+        return;
+    };
+
+    // send the quote to the client for verification
+    sessions.0.insert(
+        session_id,
+        Session {
+            conn,
+            active: ctx.now,
+        },
+    );
+    ctx.reply(MsgToHost::RATLS {
+        session_id,
+        report: quote,
+        checkpoint_key,
+        confirm,
+    });
+}
+
+/// Handle the client's acknowledgement of a handshake.
+///
+/// Upon success of a version 1 handshake, the secure channel is used to
+/// send an FMD key to the enclave to be stored and the session is closed.
+/// Acknowledging the registration is left to the caller.
+///
+/// A version 2 acknowledgement must carry the client's key confirmation
+/// along with its first request. The session then stays open for further
+/// requests. A failed handshake is closed without a reply.
+pub(crate) fn close_session<RA, COM, RNG>(
+    ctx: &mut Ctx<RA, COM, RNG>,
+    sessions: &mut Sessions,
+    registry: &mut Registry,
+    session_id: u64,
+    ack: AckType,
+) -> Option<FmdKeyRegistration>
+where
+    RA: RemoteAttestation,
+    COM: EnclaveComm,
+    RNG: EnclaveRNG,
+{
+    sessions.prune(&ctx.config.sessions, ctx.now);
+    let session = sessions.0.remove(&session_id);
+    let (cipher, confirm) = match ack {
+        AckType::Success(cipher) => (cipher, None),
+        AckType::Confirmed { confirm, cipher } => (cipher, Some(confirm)),
+        AckType::Fail => return None,
+    };
+    let Some(Session { conn, .. }) = session else {
+        ctx.reply_client_err("Unknown or expired session");
         return None;
     };
+    match (conn, confirm) {
+        (Handshake::Bespoke(conn), None) => {
+            let key = conn.decrypt_msg::<FmdKeyRegistration>(&cipher);
+            accept_registration(ctx, key)
+        }
+        (
+            Handshake::Confirming {
+                session,
+                client_confirm,
+            },
+            Some(confirm),
+        ) => {
+            if let Err(e) = KeyConfirmation::check(&client_confirm, &confirm) {
+                ctx.reply_client_err(&e.to_string());
+                return None;
+            }
+            let conn = Handshake::Established {
+                session,
+                owners: BTreeSet::new(),
+            };
+            sessions.0.insert(
+                session_id,
+                Session {
+                    conn,
+                    active: ctx.now,
+                },
+            );
+            session_request(ctx, sessions, registry, session_id, cipher);
+            None
+        }
+        (Handshake::Tls(_), _) => {
+            ctx.reply_client_err("The session expected TLS records");
+            None
+        }
+        _ => {
+            ctx.reply_client_err("The acknowledgement does not match the handshake version");
+            None
+        }
+    }
+}
 
-    // generate Remote Attestation report
-    let mut report_data = [0u8; 64];
-    for (ix, b) in enclave_pk
-        .to_bytes()
-        .into_iter()
-        .chain(nonce.to_le_bytes().into_iter())
-        .enumerate()
-    {
-        report_data[ix] = b;
+/// Serve a request within an established session, replying with the
+/// encrypted outcome. A request that cannot be opened ends the session.
+pub(crate) fn session_request<RA, COM, RNG>(
+    ctx: &mut Ctx<RA, COM, RNG>,
+    sessions: &mut Sessions,
+    registry: &mut Registry,
+    session_id: u64,
+    cipher: TlsCiphertext,
+) where
+    RA: RemoteAttestation,
+    COM: EnclaveComm,
+    RNG: EnclaveRNG,
+{
+    sessions.prune(&ctx.config.sessions, ctx.now);
+    let Some(Session {
+        conn: Handshake::Established { session, owners },
+        active,
+    }) = sessions.0.get_mut(&session_id)
+    else {
+        ctx.reply_client_err("Unknown or expired session");
+        return;
+    };
+    let request = match session.open::<SessionRequest>(&cipher) {
+        Ok(request) => request,
+        Err(e) => {
+            sessions.close(session_id);
+            ctx.reply_client_err(&format!("Error receiving session request: {e}"));
+            return;
+        }
+    };
+    *active = ctx.now;
+    let reply = match request {
+        SessionRequest::Register(key) => ctx
+            .config
+            .validate(&key)
+            .map_err(|e| format!("Key registration rejected: {e}"))
+            .and_then(|_| add_registration(&ctx.config, ctx.now, registry, key))
+            .map(|owner| {
+                let reply = SessionReply::Registered(receipt(&owner, &registry[&owner]));
+                owners.insert(owner);
+                reply
+            }),
+        SessionRequest::Stats { owner } => owners
+            .get(&owner)
+            .and_then(|owner| registry.get(owner).map(|reg| receipt(owner, reg)))
+            .map(SessionReply::Stats)
+            .ok_or_else(|| "No registration from this session found".to_string()),
+    }
+    .unwrap_or_else(SessionReply::Error);
+    match session.seal(&reply) {
+        Ok(cipher) => ctx.reply(MsgToHost::SessionReply { session_id, cipher }),
+        Err(e) => {
+            sessions.close(session_id);
+            ctx.reply_client_err(&e.to_string());
+        }
     }
+}
 
-    // send the quote to the client for verification
-    let quote = ctx.ra.get_quote(report_data);
-    ctx.com.write(&MsgToHost::RATLS { report: quote });
+/// Start a TLS handshake with the client's first records. The reply
+/// holds the enclave's records up to its Finished message, including a
+/// certificate carrying a quote over its key, the client's nonce and
+/// a binding of its checkpoint key.
+pub(crate) fn open_tls_session<RA, COM, RNG>(
+    ctx: &mut Ctx<RA, COM, RNG>,
+    sessions: &mut Sessions,
+    session_id: u64,
+    nonce: u64,
+    records: &[u8],
+) where
+    RA: RemoteAttestation,
+    COM: EnclaveComm,
+    RNG: EnclaveRNG,
+{
+    if !sessions.reserve(ctx, session_id) {
+        return;
+    }
+    let checkpoint_key = ctx.checkpoint_key.verifying_key();
+    let flight = TlsServer::new_enclave(&ctx.ra, &mut ctx.rng, nonce, &checkpoint_key, ctx.now)
+        .and_then(|mut conn| conn.process(records).map(|flight| (conn, flight)));
+    let (conn, flight) = match flight {
+        Ok(flight) => flight,
+        Err(e) => {
+            ctx.reply_client_err(&format!("Failed to initialize TLS connection: {e}"));
+            return;
+        }
+    };
+    sessions.0.insert(
+        session_id,
+        Session {
+            conn: Handshake::Tls(Box::new(conn)),
+            active: ctx.now,
+        },
+    );
+    ctx.reply(MsgToHost::TlsHandshake {
+        session_id,
+        records: flight.records,
+        checkpoint_key,
+    });
+}
 
-    // wait for acknowledgement from the client
-    let Ok(MsgFromHost::RATLSAck(ack)) = ctx.com.read() else {
-        ctx.com.write_err("Received unexpected message");
+/// Finish a TLS handshake with the client's remaining records, which
+/// must also carry its encrypted FMD key. The session is closed either
+/// way. Acknowledging the registration is left to the caller.
+pub(crate) fn finish_tls_session<RA, COM, RNG>(
+    ctx: &mut Ctx<RA, COM, RNG>,
+    sessions: &mut Sessions,
+    session_id: u64,
+    records: &[u8],
+) -> Option<FmdKeyRegistration>
+where
+    RA: RemoteAttestation,
+    COM: EnclaveComm,
+    RNG: EnclaveRNG,
+{
+    sessions.prune(&ctx.config.sessions, ctx.now);
+    let Some(Session { conn, .. }) = sessions.0.remove(&session_id) else {
+        ctx.reply_client_err("Unknown or expired session");
         return None;
     };
-    let AckType::Success(cipher) = ack else {
+    let Handshake::Tls(mut conn) = conn else {
+        ctx.reply_client_err("The session did not expect TLS records");
         return None;
     };
-    match conn.decrypt_msg(&cipher) {
+    let key = match conn.process(records) {
+        Ok(progress) if progress.established => {
+            serde_cbor::from_slice::<FmdKeyRegistration>(&progress.plaintext)
+                .map_err(|e| format!("Failed to deserialize message with: {e}"))
+        }
+        Ok(_) => Err("The TLS handshake did not complete".into()),
+        Err(e) => Err(e.to_string()),
+    };
+    accept_registration(ctx, key)
+}
+
+/// Check a received registration against the enclave's configuration
+fn accept_registration<RA, COM, RNG>(
+    ctx: &mut Ctx<RA, COM, RNG>,
+    key: Result<FmdKeyRegistration, impl core::fmt::Display>,
+) -> Option<FmdKeyRegistration>
+where
+    RA: RemoteAttestation,
+    COM: EnclaveComm,
+    RNG: EnclaveRNG,
+{
+    match key {
         Ok(key) => {
-            ctx.com.write(&MsgToHost::KeyRegSuccess);
+            if let Err(e) = ctx.config.validate(&key) {
+                ctx.reply_client_err(&format!("Key registration rejected: {e}"));
+                return None;
+            }
             Some(key)
         }
         Err(e) => {
-            ctx.com
-                .write_client_err(&format!("Error receiving fmd key: {e}"));
+            ctx.reply_client_err(&format!("Error receiving fmd key: {e}"));
             None
         }
     }
@@ -0,0 +1,345 @@
+//! The keys registered with the enclave. Registrations are keyed by
+//! their owner, the hash of their encryption key, so that a key is
+//! never detected for twice. Owners change their registrations either
+//! by registering again with `replace` set or, without a new RA-TLS
+//! handshake, with an update sealed by their encryption key.
+
+use alloc::collections::BTreeMap;
+use alloc::collections::btree_map::Entry;
+use alloc::format;
+use alloc::string::{String, ToString};
+
+use shared::MsgToHost;
+use shared::config::EnclaveConfig;
+use shared::ratls::{FmdKeyRegistration, RegistrationReceipt};
+use shared::tee::{EnclaveComm, EnclaveRNG, RemoteAttestation};
+use shared::update::{KeyUpdate, Update};
+
+use crate::Ctx;
+use crate::fmd::RegisteredKey;
+use crate::lease::Expiry;
+
+/// The registered keys indexed by their owner
+pub(crate) type Registry = BTreeMap<String, RegisteredKey>;
+
+/// Add a registration received over RA-TLS and acknowledge it.
+pub(crate) fn register<RA, COM, RNG>(
+    ctx: &mut Ctx<RA, COM, RNG>,
+    registry: &mut Registry,
+    key: FmdKeyRegistration,
+) where
+    RA: RemoteAttestation,
+    COM: EnclaveComm,
+    RNG: EnclaveRNG,
+{
+    match add_registration(&ctx.config, ctx.now, registry, key) {
+        Ok(_) => ctx.reply(MsgToHost::KeyRegSuccess),
+        Err(e) => ctx.reply_client_err(&e),
+    }
+}
+
+/// Add a registration, returning its owner.
+///
+/// Registering a key again is idempotent unless it differs from the
+/// existing registration, in which case `replace` must be set. A
+/// replaced detection key or birthday restarts detection from the
+/// new birthday. Keys with parameters other than those of the other
+/// registrations are rejected once the provider's limit on distinct
+/// parameter sets is reached.
+pub(crate) fn add_registration(
+    config: &EnclaveConfig,
+    now: u64,
+    registry: &mut Registry,
+    key: FmdKeyRegistration,
+) -> Result<String, String> {
+    let owner = key.enc_key.hash();
+    config
+        .validate_param_sets(
+            key.params,
+            registry
+                .iter()
+                .filter(|(other, _)| **other != owner)
+                .map(|(_, reg)| reg.key.params),
+        )
+        .map_err(|e| format!("Key registration rejected: {e}"))?;
+    match registry.entry(owner.clone()) {
+        Entry::Vacant(e) => {
+            e.insert(RegisteredKey::new(key, config.default_lease, now));
+        }
+        Entry::Occupied(mut e) => {
+            let reg = e.get_mut();
+            let reset = reg.key.fmd_key != key.fmd_key
+                || reg.key.params != key.params
+                || reg.key.birthday != key.birthday;
+            if reset || reg.key.lease != key.lease {
+                if !key.replace {
+                    return Err("Key is already registered with different parameters, \
+                         it must be replaced to change them"
+                        .to_string());
+                }
+                if reset {
+                    reg.indices.reset(key.birthday.unwrap_or(1));
+                }
+                reg.expiry = Expiry::new(key.lease.or(config.default_lease), now);
+                reg.key = key;
+            }
+        }
+    }
+    Ok(owner)
+}
+
+/// The state of a registration as reported to its owner
+pub(crate) fn receipt(owner: &str, reg: &RegisteredKey) -> RegistrationReceipt {
+    let (expires_at_height, expires_at_time) = reg.expiry.bounds();
+    RegistrationReceipt {
+        owner: owner.to_string(),
+        synced_to: reg.indices.synced_to,
+        detected: reg.indices.indices.len(),
+        expires_at_height,
+        expires_at_time,
+        sequence: reg.indices.sequence(),
+    }
+}
+
+/// Apply an update sealed with the encryption key of an existing
+/// registration.
+pub(crate) fn update_key<RA, COM, RNG>(
+    ctx: &mut Ctx<RA, COM, RNG>,
+    registry: &mut Registry,
+    update: KeyUpdate,
+) where
+    RA: RemoteAttestation,
+    COM: EnclaveComm,
+    RNG: EnclaveRNG,
+{
+    let Some(mut reg) = registry.remove(&update.owner) else {
+        ctx.reply_client_err("No active registration found for key update");
+        return;
+    };
+    let result = update
+        .open(&reg.key.enc_key)
+        .map_err(|e| format!("{e}"))
+        .and_then(|update| apply_update(&ctx.config, registry, &mut reg, update));
+    match result {
+        Ok(stale) => {
+            registry.insert(reg.key.enc_key.hash(), reg);
+            ctx.reply(MsgToHost::KeyUpdated { stale });
+        }
+        Err(e) => {
+            registry.insert(update.owner, reg);
+            ctx.reply_client_err(&format!("Key update rejected: {e}"));
+        }
+    }
+}
+
+/// Apply the changes of an update to a registration, leaving it untouched
+/// if any of them are rejected. Returns the owner whose stored results are
+/// no longer valid, if any.
+fn apply_update(
+    config: &EnclaveConfig,
+    registry: &Registry,
+    reg: &mut RegisteredKey,
+    update: Update,
+) -> Result<Option<String>, String> {
+    let Update {
+        enc_key,
+        detection_key,
+        reset,
+        sequence,
+    } = update;
+    if sequence <= reg.sequence() {
+        return Err("message is older than the last one accepted".into());
+    }
+    if let Some(enc_key) = &enc_key {
+        if registry.contains_key(&enc_key.hash()) {
+            return Err("the new encryption key is already registered".into());
+        }
+    }
+    if let Some((fmd_key, params)) = detection_key {
+        let candidate = FmdKeyRegistration {
+            fmd_key,
+            params,
+            enc_key: reg.key.enc_key.clone(),
+            birthday: reg.key.birthday,
+            lease: reg.key.lease,
+            replace: false,
+        };
+        config.validate(&candidate).map_err(|e| format!("{e}"))?;
+        config
+            .validate_param_sets(params, registry.values().map(|reg| reg.key.params))
+            .map_err(|e| format!("{e}"))?;
+        reg.key.fmd_key = candidate.fmd_key;
+        reg.key.params = candidate.params;
+    }
+    let owner = reg.key.enc_key.hash();
+    let rotated = enc_key.is_some();
+    if let Some(enc_key) = enc_key {
+        reg.key.enc_key = enc_key;
+    }
+    if reset {
+        reg.indices.reset(reg.key.birthday.unwrap_or(1));
+    }
+    reg.advance_sequence(sequence)?;
+    Ok((rotated || reset).then_some(owner))
+}
+
+#[cfg(test)]
+mod tests {
+    use fmd::fmd2_compact::{CompactSecretKey, MultiFmd2CompactScheme};
+    use fmd::{KeyExpansion, MultiFmdScheme};
+    use shared::db::test_enc_key;
+    use shared::lease::Lease;
+    use shared::ratls::FmdParams;
+
+    use super::*;
+
+    fn registration(subkeys: usize) -> FmdKeyRegistration {
+        let params = FmdParams::default();
+        let csk = CompactSecretKey::derive_from_xof_stream(params.threshold, |buf| buf.fill(7));
+        let mut scheme = MultiFmd2CompactScheme::new(params.gamma, params.threshold);
+        let (sk, _) = scheme.expand_keypair(&csk, &csk.master_public_key());
+        let fmd_key = scheme
+            .multi_extract(&sk, 1, 1, subkeys, subkeys)
+            .expect("Test failed")
+            .remove(0);
+        FmdKeyRegistration {
+            fmd_key,
+            params,
+            enc_key: test_enc_key(0),
+            birthday: Some(10),
+            lease: None,
+            replace: false,
+        }
+    }
+
+    /// Test that updates rotate keys and reset index sets as requested,
+    /// and that replayed or invalid updates leave the registration untouched.
+    #[test]
+    fn test_apply_update() {
+        let config = EnclaveConfig::default();
+        let registry = Registry::new();
+        let mut reg = RegisteredKey::new(registration(1), None, 0);
+        reg.indices.synced_to = 20;
+        let owner = reg.key.enc_key.hash();
+
+        let update = Update {
+            enc_key: None,
+            detection_key: Some((registration(2).fmd_key, FmdParams::default())),
+            reset: false,
+            sequence: 1,
+        };
+        let stale = apply_update(&config, &registry, &mut reg, update).expect("Test failed");
+        assert_eq!(stale, None);
+        assert_eq!(reg.key.fmd_key, registration(2).fmd_key);
+        assert_eq!(reg.indices.synced_to, 20);
+
+        let replayed = Update {
+            enc_key: Some(test_enc_key(1)),
+            detection_key: None,
+            reset: true,
+            sequence: 1,
+        };
+        assert!(apply_update(&config, &registry, &mut reg, replayed).is_err());
+        assert_eq!(reg.key.enc_key.hash(), owner);
+
+        let invalid = Update {
+            enc_key: Some(test_enc_key(1)),
+            detection_key: Some((registration(17).fmd_key, FmdParams::default())),
+            reset: true,
+            sequence: 2,
+        };
+        assert!(apply_update(&config, &registry, &mut reg, invalid).is_err());
+        assert_eq!(reg.key.enc_key.hash(), owner);
+        assert_eq!(reg.indices.synced_to, 20);
+
+        let rotation = Update {
+            enc_key: Some(test_enc_key(1)),
+            detection_key: None,
+            reset: true,
+            sequence: 2,
+        };
+        let stale = apply_update(&config, &registry, &mut reg, rotation).expect("Test failed");
+        assert_eq!(stale, Some(owner));
+        assert_eq!(reg.key.enc_key.hash(), test_enc_key(1).hash());
+        assert_eq!(reg.indices.synced_to, 10);
+    }
+
+    /// Test that registering again is idempotent, that changes must be
+    /// marked as replacements and that receipts reflect the registration.
+    #[test]
+    fn test_add_registration() {
+        let config = EnclaveConfig::default();
+        let mut registry = Registry::new();
+        let owner =
+            add_registration(&config, 0, &mut registry, registration(1)).expect("Test failed");
+        assert_eq!(
+            add_registration(&config, 0, &mut registry, registration(1)),
+            Ok(owner.clone())
+        );
+        registry.get_mut(&owner).unwrap().indices.synced_to = 20;
+        assert!(add_registration(&config, 0, &mut registry, registration(2)).is_err());
+
+        let mut replacement = registration(2);
+        replacement.lease = Some(Lease::Height(100));
+        replacement.replace = true;
+        add_registration(&config, 0, &mut registry, replacement).expect("Test failed");
+        assert_eq!(
+            receipt(&owner, &registry[&owner]),
+            RegistrationReceipt {
+                owner: owner.clone(),
+                synced_to: 10,
+                detected: 0,
+                expires_at_height: Some(100),
+                expires_at_time: None,
+                sequence: 0,
+            }
+        );
+    }
+
+    /// Test that keys with parameters unlike those of the other
+    /// registrations are rejected once the limit on distinct parameter
+    /// sets is reached, whether registered or updated to.
+    #[test]
+    fn test_param_set_limit() {
+        let config = EnclaveConfig {
+            max_param_sets: 1,
+            ..Default::default()
+        };
+        let other = FmdParams {
+            gamma: FmdParams::default().gamma + 1,
+            ..Default::default()
+        };
+        let mut registry = Registry::new();
+        let owner =
+            add_registration(&config, 0, &mut registry, registration(1)).expect("Test failed");
+
+        let second = |params| FmdKeyRegistration {
+            enc_key: test_enc_key(1),
+            params,
+            ..registration(1)
+        };
+        assert!(add_registration(&config, 0, &mut registry, second(other)).is_err());
+        add_registration(&config, 0, &mut registry, second(FmdParams::default()))
+            .expect("Test failed");
+
+        let mut reg = registry.remove(&owner).expect("Test failed");
+        let update = || Update {
+            enc_key: None,
+            detection_key: Some((registration(2).fmd_key, other)),
+            reset: true,
+            sequence: 1,
+        };
+        assert!(apply_update(&config, &registry, &mut reg, update()).is_err());
+        assert_eq!(reg.key.params, FmdParams::default());
+        registry.clear();
+        apply_update(&config, &registry, &mut reg, update()).expect("Test failed");
+        assert_eq!(reg.key.params, other);
+
+        // A key may replace its own parameters
+        let mut replacement = registration(1);
+        replacement.params = other;
+        replacement.replace = true;
+        registry.insert(owner, reg);
+        add_registration(&config, 0, &mut registry, replacement).expect("Test failed");
+    }
+}
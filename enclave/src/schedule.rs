@@ -0,0 +1,153 @@
+//! Deciding which registered keys get FMD performed on them in each
+//! round. Keys that are caught up with the chain are kept at the tip
+//! while keys with old birthdays backfill within a bounded budget, so
+//! that a burst of new registrations does not delay everyone else.
+
+use alloc::collections::BTreeSet;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use shared::config::{ScheduleConfig, SchedulePolicy};
+
+/// A key scheduled for this round, identified by its owner,
+/// along with the height it should be synced to by the end
+/// of the round.
+pub(crate) type Assignment = (String, u64);
+
+#[derive(Default)]
+pub(crate) struct Scheduler {
+    /// Where to resume serving catching-up keys next round
+    cursor: usize,
+    /// The assignments of the current round
+    round: Vec<Assignment>,
+}
+
+impl Scheduler {
+    /// The assignments made by the last call to [`Self::plan`]
+    pub fn round(&self) -> &[Assignment] {
+        &self.round
+    }
+
+    /// Plan the next round of FMD given the height the host is
+    /// synced to. Keys are given as their owner, the height they are
+    /// synced to and the height their lease allows them to be synced to.
+    ///
+    /// Returns the sorted block heights whose flags the enclave needs
+    /// to carry out the round.
+    pub fn plan<'a, I>(&mut self, config: &ScheduleConfig, keys: I, synced_to: u64) -> Vec<u64>
+    where
+        I: IntoIterator<Item = (&'a str, u64, u64)>,
+    {
+        let behind = keys
+            .into_iter()
+            .map(|(owner, height, limit)| (owner, (height, limit.min(synced_to))))
+            .filter(|(_, (height, limit))| height < limit);
+        // the keys scheduled this round with their current and target heights
+        let round: Vec<(&str, u64, u64)> = match config.policy {
+            SchedulePolicy::Uniform => behind
+                .map(|(owner, (height, _))| (owner, height, height + 1))
+                .collect(),
+            SchedulePolicy::LivePriority => {
+                let (live, backfill): (Vec<_>, Vec<_>) =
+                    behind.partition(|(_, (height, _))| synced_to - height <= config.live_window);
+                let mut round: Vec<_> = live
+                    .into_iter()
+                    .map(|(owner, (height, limit))| (owner, height, limit))
+                    .collect();
+                if !backfill.is_empty() {
+                    let start = self.cursor % backfill.len();
+                    let take = config.backfill_keys.min(backfill.len());
+                    round.extend(backfill.iter().cycle().skip(start).take(take).map(
+                        |(owner, (height, limit))| {
+                            let target = height.saturating_add(config.backfill_blocks);
+                            (*owner, *height, target.min(*limit))
+                        },
+                    ));
+                    self.cursor = start + take;
+                }
+                round
+            }
+        };
+        let heights: BTreeSet<u64> = round
+            .iter()
+            .flat_map(|(_, height, target)| height + 1..=*target)
+            .collect();
+        self.round = round
+            .into_iter()
+            .map(|(owner, _, target)| (owner.to_string(), target))
+            .collect();
+        heights.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round(assignments: &[(&str, u64)]) -> Vec<Assignment> {
+        assignments
+            .iter()
+            .map(|(owner, target)| (owner.to_string(), *target))
+            .collect()
+    }
+
+    fn config(policy: SchedulePolicy) -> ScheduleConfig {
+        ScheduleConfig {
+            policy,
+            live_window: 2,
+            backfill_keys: 1,
+            backfill_blocks: 3,
+        }
+    }
+
+    #[test]
+    fn test_uniform_schedule() {
+        let keys = [
+            ("a", 1, u64::MAX),
+            ("b", 5, u64::MAX),
+            ("c", 10, u64::MAX),
+            ("d", 3, 3),
+        ];
+        let config = config(SchedulePolicy::Uniform);
+        let mut scheduler = Scheduler::default();
+        let heights = scheduler.plan(&config, keys, 10);
+        assert_eq!(heights, [2, 6]);
+        assert_eq!(scheduler.round(), round(&[("a", 2), ("b", 6)]));
+    }
+
+    /// Test that live keys are always brought to the tip while catching-up
+    /// keys are served round-robin within their budget.
+    #[test]
+    fn test_live_priority_schedule() {
+        let keys = [
+            ("a", 1, u64::MAX),
+            ("b", 8, u64::MAX),
+            ("c", 20, u64::MAX),
+            ("d", 30, u64::MAX),
+        ];
+        let config = config(SchedulePolicy::LivePriority);
+        let mut scheduler = Scheduler::default();
+        let heights = scheduler.plan(&config, keys, 30);
+        assert_eq!(heights, [2, 3, 4]);
+        assert_eq!(scheduler.round(), round(&[("a", 4)]));
+
+        let heights = scheduler.plan(&config, keys, 30);
+        assert_eq!(heights, [9, 10, 11]);
+        assert_eq!(scheduler.round(), round(&[("b", 11)]));
+
+        let heights = scheduler.plan(&config, keys, 31);
+        assert_eq!(heights, [21, 22, 23, 31]);
+        assert_eq!(scheduler.round(), round(&[("d", 31), ("c", 23)]));
+    }
+
+    /// Test that keys are not scheduled past the end of their lease
+    #[test]
+    fn test_schedule_within_lease() {
+        let keys = [("a", 1, 3), ("b", 28, 29), ("c", 29, 29)];
+        let config = config(SchedulePolicy::LivePriority);
+        let mut scheduler = Scheduler::default();
+        let heights = scheduler.plan(&config, keys, 30);
+        assert_eq!(heights, [2, 3, 29]);
+        assert_eq!(scheduler.round(), round(&[("b", 29), ("a", 3)]));
+    }
+}
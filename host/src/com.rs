@@ -1,111 +1,157 @@
 //! Communication primitives for talking with enclavees and clients
 
 use std::io;
-use std::io::prelude::*;
 use std::net::TcpStream;
 use std::time::Duration;
 
-use shared::{ClientMsg, FramedBytes, MsgError, MsgFromHost, MsgToHost, ReadWriteByte, ServerMsg};
-
+use futures::{SinkExt, StreamExt};
+use shared::codec::HostCodec;
+use shared::{
+    CLIENT_MAX_FRAME_SIZE, ClientMsg, ENCLAVE_MAX_FRAME_SIZE, EnclaveReply, FramedBytes,
+    HostRequest, MsgError, MsgFromHost, MsgToHost, ReadWriteByte, ServerMsg,
+};
+use tokio_util::codec::Framed;
+use tracing::warn;
+
+/// A connection to the enclave. Each request is tagged with an id that
+/// the enclave echoes in its reply, so that replies to abandoned requests
+/// are recognized and discarded rather than mistaken for the reply to the
+/// current one.
 pub(crate) struct Tcp {
-    pub raw: TcpStream,
-    buffered: Vec<u8>,
+    raw: shared::tcp::Tcp,
+    /// The id of the last request sent
+    request: u64,
+    /// Whether the enclave is still connected
+    connected: bool,
 }
 
 impl Tcp {
     /// Create a new stream
     pub fn new(url: &str) -> io::Result<Self> {
         Ok(Self {
-            raw: TcpStream::connect(url)?,
-            buffered: Default::default(),
+            raw: shared::tcp::Tcp::new(TcpStream::connect(url)?),
+            request: 0,
+            connected: true,
         })
     }
 
-    /// Send a [`MsgFromHost`] into the enclave
+    /// Whether the enclave is still connected
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Send a [`MsgFromHost`] into the enclave. Failures are reported
+    /// when reading the reply.
     pub fn write(&mut self, msg: MsgFromHost) {
-        self.write_frame(&msg);
+        self.request += 1;
+        if let Err(e) = self.write_frame(&HostRequest {
+            id: self.request,
+            msg,
+        }) {
+            warn!("Failed to send message to enclave: {e}");
+        }
     }
 
-    /// Read a message sent from the enclave
+    /// Read the reply to the last request sent to the enclave. Replies to
+    /// earlier requests are discarded.
     pub fn read(&mut self) -> Result<MsgToHost, MsgError> {
-        let frame = self.get_frame()?;
-        frame.deserialize()
+        loop {
+            let EnclaveReply { id, msg } = self.get_frame()?.deserialize()?;
+            match id {
+                Some(id) if id < self.request => {
+                    warn!("Discarding orphaned reply to enclave request {id}: {msg:?}");
+                }
+                Some(id) if id > self.request => {
+                    return Err(MsgError::OutOfOrder {
+                        expected: self.request,
+                        received: id,
+                    });
+                }
+                // The enclave could not read a request. Since only one
+                // request is outstanding, the error is taken to be its reply.
+                _ => return Ok(msg),
+            }
+        }
+    }
+
+    /// Discard any replies still in flight so that the next reply read
+    /// answers the next request sent.
+    pub fn resync(&mut self) -> Result<(), MsgError> {
+        self.write(MsgFromHost::Resync);
+        loop {
+            match self.read()? {
+                MsgToHost::Resynced => return Ok(()),
+                msg => warn!("Discarding reply from enclave while resyncing: {msg:?}"),
+            }
+        }
     }
 
-    /// Read data from the stream into an internal buffer.
-    /// The buffer is a stack, so the bytes are stored in
-    /// reverse order that they are received.
-    fn buffered_read(&mut self) -> io::Result<()> {
-        let mut buffered = vec![0; 10];
-        let len = self.raw.read(&mut buffered)?;
-        buffered.truncate(len);
-        self.buffered = buffered;
-        Ok(())
+    /// Record that the enclave has disconnected if the error says so
+    fn check_disconnect(&mut self, err: MsgError) -> MsgError {
+        if err.is_disconnect() {
+            self.connected = false;
+        }
+        err
     }
 }
 
 impl ReadWriteByte for Tcp {
-    fn read_byte(&mut self) -> u8 {
-        // block until data is read into
-        // internal buffer
-        while self.buffered.is_empty() {
-            self.buffered_read().unwrap();
-            core::hint::spin_loop();
+    const MAX_FRAME_SIZE: usize = ENCLAVE_MAX_FRAME_SIZE;
+
+    fn read_byte(&mut self) -> Result<u8, MsgError> {
+        if !self.connected {
+            return Err(MsgError::Disconnected);
         }
-        self.buffered.remove(0)
+        self.raw.read_byte().map_err(|e| self.check_disconnect(e))
     }
 
-    fn write_bytes(&mut self, buf: &[u8]) {
-        self.raw.write_all(buf).unwrap();
-        self.raw.flush().unwrap();
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<(), MsgError> {
+        if !self.connected {
+            return Err(MsgError::Disconnected);
+        }
+        self.raw
+            .write_bytes(buf)
+            .map_err(|e| self.check_disconnect(e))
     }
 }
 
+/// A connection from a client, framed with the async codec so that
+/// reads can time out without blocking the runtime.
 pub(crate) struct IncomingTcp {
-    raw: shared::tcp::Tcp,
+    framed: Framed<tokio::net::TcpStream, HostCodec>,
     timeout: Duration,
 }
 
 impl IncomingTcp {
     /// Create a new connection from a stream
-    pub fn new(stream: TcpStream, timeout: Duration) -> Self {
+    pub fn new(stream: tokio::net::TcpStream, timeout: Duration) -> Self {
         Self {
-            raw: shared::tcp::Tcp::new(stream),
+            framed: Framed::new(stream, HostCodec::new(CLIENT_MAX_FRAME_SIZE)),
             timeout,
         }
     }
 
-    /// Send a [`MsgFromHost`] into the enclave
-    pub fn write(&mut self, msg: ServerMsg) {
-        self.write_frame(&msg);
-    }
-
-    /// Read a message sent from the enclave
-    pub fn read(&mut self) -> Result<ClientMsg, MsgError> {
-        let frame = self.get_frame()?;
-        frame.deserialize()
+    /// Send a [`ServerMsg`] to the client
+    pub async fn write(&mut self, msg: ServerMsg) {
+        if let Err(e) = self.framed.send(msg).await {
+            warn!("Failed to send message to client: {e}");
+        }
     }
 
     /// Try to read from a connection to a client. Times out if message is not
-    /// received within time.
+    /// received within time. Returns `None` on a timeout or if the client
+    /// closed the connection.
     pub async fn timed_read(&mut self) -> Option<Result<ClientMsg, MsgError>> {
-        tokio_scoped::scope(|scope| {
-            scope.block_on(async {
-                tokio::select! {
-                    _ = tokio::time::sleep(self.timeout) => None,
-                    val = async { self.read() } => Some(val),
-                }
-            })
-        })
-    }
-}
-
-impl ReadWriteByte for IncomingTcp {
-    fn read_byte(&mut self) -> u8 {
-        self.raw.read_byte()
-    }
-
-    fn write_bytes(&mut self, buf: &[u8]) {
-        self.raw.write_bytes(buf)
+        match tokio::time::timeout(self.timeout, self.framed.next()).await {
+            Ok(Some(msg)) => Some(msg),
+            Ok(None) => {
+                warn!("Client closed the connection");
+                None
+            }
+            Err(_) => {
+                warn!("Timed out waiting on client");
+                None
+            }
+        }
     }
 }
@@ -6,6 +6,8 @@ use std::time::Duration;
 
 use serde::de::{Error, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use shared::config::EnclaveConfig;
+use shared::lease::Lease;
 
 use crate::{BASE_DIR, Cli};
 
@@ -22,6 +24,8 @@ pub struct Config {
     pub listen_url: String,
     pub listen_timeout: Duration,
     pub db: DbConfig,
+    #[serde(default)]
+    pub enclave: EnclaveConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -46,6 +50,8 @@ impl Config {
 
     /// Parse a config from CLI arguments
     pub fn init(cli: Cli) -> Option<Self> {
+        let mut enclave = EnclaveConfig::default();
+        apply_enclave_args(&mut enclave, &cli);
         cli.indexer_url.as_ref().map(|ix_url| Self {
             enclave_url: cli.enclave.unwrap_or_else(|| ENCLAVE_ADDRESS.to_string()),
             listen_url: cli.listen.unwrap_or_else(|| LISTENING_ADDRESS.to_string()),
@@ -57,6 +63,7 @@ impl Config {
                 indexer_url: reqwest::Url::from_str(ix_url).unwrap(),
                 max_wal_size: cli.max_wal_size.unwrap_or(MAX_WAL_SIZE),
             },
+            enclave,
         })
     }
 
@@ -68,6 +75,7 @@ impl Config {
     pub fn load_or_init(cli: Cli) -> Self {
         match Self::load() {
             Ok(mut conf) => {
+                apply_enclave_args(&mut conf.enclave, &cli);
                 if let Some(e) = cli.enclave {
                     conf.enclave_url = e;
                 }
@@ -110,6 +118,46 @@ impl Config {
     }
 }
 
+/// Overwrite the enclave parameters with any CLI args present
+fn apply_enclave_args(enclave: &mut EnclaveConfig, cli: &Cli) {
+    if let Some(policy) = cli.schedule_policy {
+        enclave.schedule.policy = policy;
+    }
+    if let Some(window) = cli.live_window {
+        enclave.schedule.live_window = window;
+    }
+    if let Some(keys) = cli.backfill_keys {
+        enclave.schedule.backfill_keys = keys;
+    }
+    if let Some(blocks) = cli.backfill_blocks {
+        enclave.schedule.backfill_blocks = blocks;
+    }
+    if let Some(min) = cli.min_subkeys {
+        enclave.fp_rates.min_subkeys = min;
+    }
+    if let Some(max) = cli.max_subkeys {
+        enclave.fp_rates.max_subkeys = max;
+    }
+    if let Some(max) = cli.max_gamma {
+        enclave.max_gamma = max;
+    }
+    if let Some(max) = cli.max_param_sets {
+        enclave.max_param_sets = max;
+    }
+    if let Some(secs) = cli.default_lease {
+        enclave.default_lease = Some(Lease::Duration(secs));
+    }
+    if let Some(padding) = cli.padding {
+        enclave.padding = padding;
+    }
+    if let Some(secs) = cli.session_timeout {
+        enclave.sessions.timeout = secs;
+    }
+    if let Some(max) = cli.max_sessions {
+        enclave.sessions.max_open = max;
+    }
+}
+
 pub fn kassandra_dir() -> PathBuf {
     BASE_DIR
         .get()
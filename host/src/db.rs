@@ -10,6 +10,8 @@ use eyre::WrapErr;
 use fmd::fmd2_compact::FlagCiphertexts;
 use namada::tx::IndexedTx;
 use rusqlite::Connection;
+use shared::HexBytes;
+use shared::checkpoint::Checkpoint;
 use shared::db::{EncryptedResponse, Index};
 pub use utils::InterruptFlag;
 use uuid::Uuid;
@@ -65,7 +67,9 @@ impl DB {
                 owner TEXT NOT NULL PRIMARY KEY,
                 nonce BLOB NOT NULL,
                 idx_set BLOB NOT NULL,
-                height INTEGER NOT NULL
+                height INTEGER NOT NULL,
+                sequence INTEGER NOT NULL DEFAULT 0,
+                version INTEGER NOT NULL DEFAULT 0
             )",
                 (),
             )
@@ -79,12 +83,14 @@ impl DB {
                 (),
             )
             .wrap_err("Failed to creat FMD DB table")?;
+            migrate(&fmd)?;
             let uuid = Uuid::new_v4();
             fmd.execute("INSERT INTO UUID (uuid) VALUES (?1)", (&uuid.to_string(),))
                 .wrap_err("Could not insert UUID into DB")?;
             (fmd, uuid)
         } else {
             let fmd = Connection::open(fmd_db_path).wrap_err("Failed to creat FMD DB table")?;
+            migrate(&fmd)?;
             let uuid = fmd
                 .query_row::<String, _, _>("SELECT uuid FROM UUID LIMIT 1", [], |row| row.get(0))
                 .wrap_err("Could not  retrieve UUID from DB")?;
@@ -145,29 +151,140 @@ impl DB {
     pub fn update_indices(&mut self, new_indices: Vec<EncryptedResponse>) -> eyre::Result<()> {
         let mut stmt = self
             .fmd
-            .prepare("INSERT OR REPLACE INTO Indices(nonce, idx_set, owner, height) VALUES (?1, ?2, ?3, ?4)")
+            .prepare("INSERT OR REPLACE INTO Indices(nonce, idx_set, owner, height, sequence, version) VALUES (?1, ?2, ?3, ?4, ?5, ?6)")
             .unwrap();
         for EncryptedResponse {
             owner,
             nonce,
             indices,
             height,
+            sequence,
+            version,
         } in new_indices
         {
-            stmt.execute((nonce, indices, owner, height))
+            stmt.execute((nonce, indices, owner, height, sequence, version))
                 .wrap_err("Could not update FMD db")?;
         }
         Ok(())
     }
 
+    /// Store the checkpoints signed by the enclave. A checkpoint never
+    /// replaces an earlier one of the same height, so that a conflicting
+    /// digest stays visible to clients. Conflicts are reported as an error
+    /// once all other checkpoints are stored.
+    pub fn update_checkpoints(&mut self, checkpoints: Vec<Checkpoint>) -> eyre::Result<()> {
+        let mut insert = self
+            .fmd
+            .prepare(
+                "INSERT OR IGNORE INTO Checkpoints(height, digest, signature) VALUES (?1, ?2, ?3)",
+            )
+            .unwrap();
+        let mut stored = self
+            .fmd
+            .prepare("SELECT digest FROM Checkpoints WHERE height = ?1")
+            .unwrap();
+        let mut conflicts = vec![];
+        for Checkpoint {
+            height,
+            digest,
+            signature,
+        } in checkpoints
+        {
+            let inserted = insert
+                .execute((height, digest.0, signature.0.to_vec()))
+                .wrap_err("Could not update checkpoints")?;
+            if inserted == 0 {
+                let existing: Vec<u8> = stored
+                    .query_row([height], |row| row.get(0))
+                    .wrap_err("Could not query checkpoints")?;
+                if existing != digest.0 {
+                    conflicts.push(height);
+                }
+            }
+        }
+        if conflicts.is_empty() {
+            Ok(())
+        } else {
+            Err(eyre::eyre!(
+                "The enclave signed checkpoints conflicting with stored ones at heights \
+                 {conflicts:?}, the stored ones were kept"
+            ))
+        }
+    }
+
+    /// Get the checkpoints of block heights in the inclusive range,
+    /// returning at most `limit` of them.
+    pub fn fetch_checkpoints(
+        &self,
+        from: u64,
+        to: u64,
+        limit: usize,
+    ) -> eyre::Result<Vec<Checkpoint>> {
+        let mut stmt = self
+            .fmd
+            .prepare(
+                "SELECT height, digest, signature FROM Checkpoints WHERE height BETWEEN ?1 AND ?2 ORDER BY height LIMIT ?3",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map((from, to, limit), |row| {
+                Ok((
+                    row.get::<_, u64>(0)?,
+                    row.get::<_, Vec<u8>>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                ))
+            })
+            .wrap_err("Could not query checkpoints")?;
+        let mut checkpoints = vec![];
+        for row in rows {
+            let (height, digest, signature) = row.wrap_err("Could not read checkpoint")?;
+            checkpoints.push(Checkpoint {
+                height,
+                digest: HexBytes(
+                    digest
+                        .try_into()
+                        .map_err(|_| eyre::eyre!("Malformed checkpoint digest in DB"))?,
+                ),
+                signature: HexBytes(
+                    signature
+                        .try_into()
+                        .map_err(|_| eyre::eyre!("Malformed checkpoint signature in DB"))?,
+                ),
+            });
+        }
+        Ok(checkpoints)
+    }
+
+    /// Delete the index sets of registrations that are no longer active
+    pub fn remove_indices(&mut self, owners: &[String]) -> eyre::Result<()> {
+        let mut stmt = self
+            .fmd
+            .prepare("DELETE FROM Indices WHERE owner=?1")
+            .unwrap();
+        for owner in owners {
+            stmt.execute([owner])
+                .wrap_err("Could not remove expired indices from FMD db")?;
+        }
+        Ok(())
+    }
+
     /// Get the encrypted index set belonging to a registered key
     pub fn fetch_indices(&self, user: &str) -> eyre::Result<EncryptedResponse> {
-        let (owner, n, indices, height) = self
+        let (owner, n, indices, height, sequence, version) = self
             .fmd
-            .query_row::<(String, Vec<u8>, Vec<u8>, u64), _, _>(
-                "SELECT owner, nonce, idx_set, height FROM Indices WHERE owner=?1",
+            .query_row::<(String, Vec<u8>, Vec<u8>, u64, u64, u8), _, _>(
+                "SELECT owner, nonce, idx_set, height, sequence, version FROM Indices WHERE owner=?1",
                 rusqlite::params![user],
-                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                    ))
+                },
             )
             .wrap_err("Could not find user's key hash in the DB")?;
         Ok(EncryptedResponse {
@@ -175,6 +292,8 @@ impl DB {
             nonce: n.try_into().unwrap(),
             indices,
             height,
+            sequence,
+            version,
         })
     }
 
@@ -217,3 +336,25 @@ impl DB {
         }
     }
 }
+
+/// Bring FMD DBs created by earlier versions up to date. Adds the
+/// columns authenticating responses and the table of checkpoints.
+fn migrate(fmd: &Connection) -> eyre::Result<()> {
+    if fmd.prepare("SELECT sequence FROM Indices LIMIT 1").is_err() {
+        fmd.execute_batch(
+            "ALTER TABLE Indices ADD COLUMN sequence INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE Indices ADD COLUMN version INTEGER NOT NULL DEFAULT 0;",
+        )
+        .wrap_err("Failed to migrate FMD DB table")?;
+    }
+    fmd.execute(
+        "CREATE TABLE IF NOT EXISTS Checkpoints (
+            height INTEGER NOT NULL PRIMARY KEY,
+            digest BLOB NOT NULL,
+            signature BLOB NOT NULL
+        )",
+        (),
+    )
+    .wrap_err("Failed to create checkpoints table")?;
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Periodic checks that the enclave is still responsive. The host pings
+//! the enclave between rounds of FMD and logs its status. Since reads
+//! from the enclave block, a hung enclave is detected by a watchdog task
+//! that notices when no heartbeat has been answered for too long.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use shared::{MsgFromHost, MsgToHost};
+use tracing::{error, info, warn};
+
+use crate::com::Tcp;
+use crate::{resync, unix_time};
+
+/// How often the enclave is pinged
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// How many intervals may pass without an answered heartbeat before
+/// the enclave is reported as hung
+const MISSED_HEARTBEATS: u64 = 3;
+
+/// Tracks when the enclave was last pinged and when it last answered
+pub struct Heartbeat {
+    last_ping: Option<Instant>,
+    nonce: u64,
+    /// When the enclave last answered a ping, in seconds since the
+    /// Unix epoch
+    last_pong: Arc<AtomicU64>,
+}
+
+impl Heartbeat {
+    /// Create a new heartbeat and spawn its watchdog
+    pub fn start() -> Self {
+        let last_pong = Arc::new(AtomicU64::new(unix_time()));
+        let watched = last_pong.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                let silent = unix_time().saturating_sub(watched.load(Ordering::Relaxed));
+                if silent > MISSED_HEARTBEATS * HEARTBEAT_INTERVAL.as_secs() {
+                    error!("Enclave has not answered a heartbeat in {silent}s; it may be hung");
+                }
+            }
+        });
+        Self {
+            last_ping: None,
+            nonce: 0,
+            last_pong,
+        }
+    }
+
+    /// Ping the enclave and log its status if a heartbeat is due
+    pub fn tick(&mut self, enclave_conn: &mut Tcp) {
+        if self
+            .last_ping
+            .is_some_and(|last| last.elapsed() < HEARTBEAT_INTERVAL)
+        {
+            return;
+        }
+        self.last_ping = Some(Instant::now());
+        self.nonce = self.nonce.wrapping_add(1);
+        enclave_conn.write(MsgFromHost::Ping(self.nonce));
+        match enclave_conn.read() {
+            Ok(MsgToHost::Pong(nonce)) if nonce == self.nonce => {
+                self.last_pong.store(unix_time(), Ordering::Relaxed);
+            }
+            Ok(_) => {
+                warn!("Received an unexpected message from enclave in response to `Ping`");
+                return;
+            }
+            Err(e) => {
+                resync(enclave_conn, e);
+                return;
+            }
+        }
+        enclave_conn.write(MsgFromHost::RequestStatus);
+        match enclave_conn.read() {
+            Ok(MsgToHost::Status(status)) => info!("Enclave status: {status:?}"),
+            Ok(_) => {
+                warn!("Received an unexpected message from enclave in response to `RequestStatus`")
+            }
+            Err(e) => resync(enclave_conn, e),
+        }
+    }
+}
@@ -2,25 +2,37 @@
 mod com;
 mod config;
 mod db;
+mod heartbeat;
 mod scheduler;
 
 use clap::Parser;
 use eyre::WrapErr;
 use once_cell::sync::OnceCell;
-use shared::{AckType, ClientMsg, MsgFromHost, MsgToHost, ServerMsg};
+use shared::config::{EnclaveConfig, PaddingPolicy, SchedulePolicy};
+use shared::{AckType, ClientMsg, MsgError, MsgFromHost, MsgToHost, ServerMsg};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::net::TcpListener;
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
 use tracing::{error, info};
 use uuid::Uuid;
 
 use crate::com::{IncomingTcp, Tcp};
 use crate::config::Config;
 use crate::db::{DB, InterruptFlag};
+use crate::heartbeat::Heartbeat;
 use crate::scheduler::{EventScheduler, NextEvent};
 
 /// The UUID for this host instances
 static HOST_UUID: OnceCell<Uuid> = OnceCell::new();
 
+/// The most checkpoints returned for a single request
+const MAX_CHECKPOINTS: usize = 1000;
+
+/// The id of the next RA-TLS handshake with the enclave
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(0);
+
 /// Location of the base directory
 static BASE_DIR: OnceCell<PathBuf> = OnceCell::new();
 
@@ -61,6 +73,80 @@ struct Cli {
         help = "Maximum number of entries in the fetching write-ahead log before flushing to disk."
     )]
     max_wal_size: Option<usize>,
+    #[arg(
+        long,
+        value_name = "Policy",
+        value_parser = SchedulePolicy::from_str,
+        help = "How FMD work is scheduled between keys, one of [ uniform, live-priority ]. Defaults to live-priority."
+    )]
+    schedule_policy: Option<SchedulePolicy>,
+    #[arg(
+        long,
+        value_name = "Blocks",
+        help = "Keys synced to within this many blocks of the latest fetched block are treated as live."
+    )]
+    live_window: Option<u64>,
+    #[arg(
+        long,
+        value_name = "Size",
+        help = "Maximum number of catching-up keys processed per round of FMD."
+    )]
+    backfill_keys: Option<usize>,
+    #[arg(
+        long,
+        value_name = "Blocks",
+        help = "Maximum number of blocks a catching-up key advances per round of FMD."
+    )]
+    backfill_blocks: Option<u64>,
+    #[arg(
+        long,
+        value_name = "Size",
+        help = "Reject detection keys with fewer subkeys, i.e. a false-positive rate above 2^-<Size>."
+    )]
+    min_subkeys: Option<usize>,
+    #[arg(
+        long,
+        value_name = "Size",
+        help = "Reject detection keys with more subkeys, i.e. a false-positive rate below 2^-<Size>."
+    )]
+    max_subkeys: Option<usize>,
+    #[arg(
+        long,
+        value_name = "Size",
+        help = "Reject detection keys with more than this many subkeys in total. Defaults to 64."
+    )]
+    max_gamma: Option<usize>,
+    #[arg(
+        long,
+        value_name = "Count",
+        help = "Reject keys with FMD parameters unlike those of any registered key once keys with this many distinct parameters are registered. Defaults to 16."
+    )]
+    max_param_sets: Option<usize>,
+    #[arg(
+        long,
+        value_name = "Seconds",
+        help = "How long registrations that do not request a lease remain active unless renewed."
+    )]
+    default_lease: Option<u64>,
+    #[arg(
+        long,
+        value_name = "Policy",
+        value_parser = PaddingPolicy::from_str,
+        help = "How encrypted results are padded to hide their size, one of [ none, multiple:<n>, power-of-two[:<min>] ]. Defaults to power-of-two:16."
+    )]
+    padding: Option<PaddingPolicy>,
+    #[arg(
+        long,
+        value_name = "Seconds",
+        help = "How long the enclave keeps an idle client session open. Defaults to 60."
+    )]
+    session_timeout: Option<u64>,
+    #[arg(
+        long,
+        value_name = "Size",
+        help = "Maximum number of client sessions open at once. Defaults to 64."
+    )]
+    max_sessions: Option<usize>,
 }
 
 #[tokio::main]
@@ -89,10 +175,16 @@ async fn main() -> eyre::Result<()> {
     let mut enclave_connection =
         Tcp::new(&config.enclave_url).wrap_err("Could not establish connection to the enclave")?;
     info!("Connected to enclave");
+    enclave_connection
+        .resync()
+        .wrap_err("Could not sync with the enclave")?;
+    configure_enclave(&mut enclave_connection, &config.enclave)?;
+    let mut heartbeat = Heartbeat::start();
     let listener = TcpListener::bind(&config.listen_url)
         .await
         .wrap_err("Could not bind to port to listen for incoming connections")?;
-    let mut events = EventScheduler::new(listener, interrupt_flag);
+    let (acks_tx, acks_rx) = unbounded_channel();
+    let mut events = EventScheduler::new(listener, interrupt_flag, acks_rx);
     loop {
         match events.next_query().await {
             NextEvent::Interrupt => {
@@ -101,17 +193,31 @@ async fn main() -> eyre::Result<()> {
             }
             NextEvent::Accept(stream) => {
                 info!("Received connection...");
-                let incoming = IncomingTcp::new(stream.into_std().unwrap(), config.listen_timeout);
-                handle_connection(incoming, &mut enclave_connection, &db).await;
+                let incoming = IncomingTcp::new(stream, config.listen_timeout);
+                handle_connection(incoming, &mut enclave_connection, &mut db, &acks_tx).await;
+            }
+            NextEvent::Ack(ack) => handle_ack(ack, &mut enclave_connection, &acks_tx).await,
+            NextEvent::PerformFmd => {
+                heartbeat.tick(&mut enclave_connection);
+                handle_fmd(&mut enclave_connection, &mut db)
             }
-            NextEvent::PerformFmd => handle_fmd(&mut enclave_connection, &mut db),
+        }
+        if !enclave_connection.is_connected() {
+            error!("Lost connection to the enclave, shutting down.");
+            db.close().await;
+            return Err(eyre::eyre!("Lost connection to the enclave"));
         }
         core::hint::spin_loop()
     }
 }
 
 /// Handle a client request and issue a response.
-async fn handle_connection(mut client_conn: IncomingTcp, enclave_conn: &mut Tcp, db: &DB) {
+async fn handle_connection(
+    mut client_conn: IncomingTcp,
+    enclave_conn: &mut Tcp,
+    db: &mut DB,
+    acks: &UnboundedSender<PendingAck>,
+) {
     let req = match client_conn.timed_read().await {
         Some(Ok(req)) => req,
         Some(Err(e)) => {
@@ -122,36 +228,127 @@ async fn handle_connection(mut client_conn: IncomingTcp, enclave_conn: &mut Tcp,
     };
 
     match &req {
-        msg @ ClientMsg::RegisterKey { .. } => {
-            handle_key_registration(
-                client_conn,
-                enclave_conn,
-                MsgFromHost::try_from(msg).unwrap(),
-            )
-            .await;
+        ClientMsg::RegisterKey { nonce, pk, version } => {
+            let session_id = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+            let open = MsgFromHost::RegisterKey {
+                session_id,
+                nonce: *nonce,
+                pk: *pk,
+                version: *version,
+            };
+            handle_key_registration(client_conn, enclave_conn, session_id, open, acks).await;
+        }
+        ClientMsg::TlsHello { nonce, records } => {
+            let session_id = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+            let open = MsgFromHost::TlsHello {
+                session_id,
+                nonce: *nonce,
+                records: records.clone(),
+            };
+            handle_key_registration(client_conn, enclave_conn, session_id, open, acks).await;
+        }
+        msg @ ClientMsg::RenewLease(_) => {
+            enclave_conn.write(MsgFromHost::try_from(msg).unwrap());
+            match enclave_conn.read() {
+                Ok(msg) => match ServerMsg::try_from(msg) {
+                    Ok(resp) => client_conn.write(resp).await,
+                    Err(_) => {
+                        error!("Received an unexpected message from the enclave");
+                        client_conn
+                            .write(ServerMsg::Error("Failed to renew lease".to_string()))
+                            .await;
+                    }
+                },
+                Err(e) => {
+                    error!("Error receiving message from enclave: {e}");
+                    client_conn
+                        .write(ServerMsg::Error("Failed to renew lease".to_string()))
+                        .await;
+                }
+            }
+        }
+        msg @ ClientMsg::UpdateKey(_) => {
+            enclave_conn.write(MsgFromHost::try_from(msg).unwrap());
+            match enclave_conn.read() {
+                Ok(msg) => {
+                    if let MsgToHost::KeyUpdated { stale: Some(owner) } = &msg {
+                        info!("Removing stale results of an updated registration");
+                        if let Err(e) = db.remove_indices(std::slice::from_ref(owner)) {
+                            error!("{e}");
+                        }
+                    }
+                    match ServerMsg::try_from(msg) {
+                        Ok(resp) => client_conn.write(resp).await,
+                        Err(_) => {
+                            error!("Received an unexpected message from the enclave");
+                            client_conn
+                                .write(ServerMsg::Error("Failed to update key".to_string()))
+                                .await;
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Error receiving message from enclave: {e}");
+                    client_conn
+                        .write(ServerMsg::Error("Failed to update key".to_string()))
+                        .await;
+                }
+            }
         }
-        ClientMsg::RequestReport { .. } | ClientMsg::RATLSAck(_) => {
+        ClientMsg::RequestReport { .. }
+        | ClientMsg::RATLSAck(_)
+        | ClientMsg::TlsRecords(_)
+        | ClientMsg::SessionRequest(_)
+        | ClientMsg::CloseSession => {
             // These messages should have been preceded by a `RegisterKey`
-            // call and then these would be handled inside the
+            // or `TlsHello` call and then these would be handled inside the
             // `handle_key_registration` function.
             error!("Unexpect message from client, ignoring...");
         }
         ClientMsg::RequestUUID => {
-            client_conn.write(ServerMsg::UUID(HOST_UUID.get().unwrap().to_string()));
+            client_conn
+                .write(ServerMsg::UUID(HOST_UUID.get().unwrap().to_string()))
+                .await;
+        }
+        ClientMsg::RequestCheckpoints { from, to } => {
+            info!("Querying DB for checkpoints from {from} to {to}");
+            match db.fetch_checkpoints(*from, *to, MAX_CHECKPOINTS) {
+                Ok(resp) => client_conn.write(ServerMsg::Checkpoints(resp)).await,
+                Err(err) => {
+                    error!("{err}");
+                    client_conn
+                        .write(ServerMsg::Error(format!(
+                            "Failed to get checkpoints: {err}"
+                        )))
+                        .await;
+                }
+            }
         }
         ClientMsg::RequestIndices { key_hash } => {
             info!("Querying DB for key hash: {key_hash}");
             match db.fetch_indices(key_hash) {
-                Ok(resp) => client_conn.write(ServerMsg::IndicesResponse(resp)),
+                Ok(resp) => client_conn.write(ServerMsg::IndicesResponse(resp)).await,
                 Err(err) => {
                     error!("{err}");
-                    client_conn.write(ServerMsg::Error(format!("Failed to get indices: {err}")));
+                    client_conn
+                        .write(ServerMsg::Error(format!("Failed to get indices: {err}")))
+                        .await;
                 }
             }
         }
     }
 }
 
+/// The client's next message in an RA-TLS session, either its reply
+/// to the attestation report or a further request
+pub(crate) struct PendingAck {
+    session_id: u64,
+    client_conn: IncomingTcp,
+    /// Whether the handshake has been acknowledged
+    established: bool,
+    reply: Option<Result<ClientMsg, MsgError>>,
+}
+
 /// A simplified TLS designed to send an encrypted secret FMD detection key from
 /// a client to the enclave. It is a multi-round protocol as follows:
 ///
@@ -161,80 +358,190 @@ async fn handle_connection(mut client_conn: IncomingTcp, enclave_conn: &mut Tcp,
 /// * The client verifies the report and sends back an FMD key encrypted with the shared
 ///   key
 /// * The enclave sends and acknowledgement of receipt
+///
+/// From version 2 of the handshake, the enclave's reply is encrypted and
+/// the client may keep sending encrypted requests in the same session
+/// until it closes it.
+///
+/// Clients may instead run a TLS 1.3 handshake, whose records the host
+/// relays in the same two rounds. The attestation report is then in the
+/// enclave's certificate.
+///
+/// Each session is given an id. The client's messages are awaited in
+/// the background and handled by [`handle_ack`] once they arrive, so
+/// that a slow client does not block other requests or FMD.
 async fn handle_key_registration(
     mut client_conn: IncomingTcp,
     enclave_conn: &mut Tcp,
-    msg: MsgFromHost,
+    session_id: u64,
+    open: MsgFromHost,
+    acks: &UnboundedSender<PendingAck>,
 ) {
-    // if we cannot complete the TLS setup for any reason, send a
-    // failing acknowledgement to the enclave so that it can drop the
-    // connection.
-    macro_rules! abort_tls {
-        () => {
-            error!("Encountered unexpected error, aborting TLS connection setup.");
-            enclave_conn.write(MsgFromHost::RATLSAck(AckType::Fail));
-            return
-        };
-    }
     // The first communication round (RA and DHKE)
-    enclave_conn.write(msg);
-    match enclave_conn.read() {
-        Ok(msg) => {
-            info!("Received message: {:?}", msg);
-            // This should be the attestation report or an enclave error
-            // intended for the client.
-            if let Ok(resp) = ServerMsg::try_from(msg) {
-                client_conn.write(resp);
-            } else {
-                error!("Received an unexpected message from the enclave");
-                abort_tls!();
-            }
+    enclave_conn.write(open);
+    let msg = match enclave_conn.read() {
+        Ok(msg) => msg,
+        Err(e) => {
+            error!("Error receiving message from enclave: {e}");
+            return;
+        }
+    };
+    info!("Received message: {:?}", msg);
+    let started = matches!(
+        msg,
+        MsgToHost::RATLS { .. } | MsgToHost::TlsHandshake { .. }
+    );
+    // This should be the attestation report or an enclave error
+    // intended for the client.
+    match ServerMsg::try_from(msg) {
+        Ok(resp) => client_conn.write(resp).await,
+        Err(_) => error!("Received an unexpected message from the enclave"),
+    }
+    if !started {
+        return;
+    }
 
-            // read the client's response
-            let req = match client_conn.timed_read().await {
-                Some(Ok(req)) => req,
-                Some(Err(e)) => {
-                    error!("Error receiving message from client: {e}");
-                    abort_tls!();
-                }
-                None => {
-                    abort_tls!();
-                }
-            };
+    await_client(client_conn, session_id, false, acks);
+}
 
-            // send an acknowledgement back to the enclave
-            if let ClientMsg::RATLSAck(val) = req {
-                enclave_conn.write(MsgFromHost::RATLSAck(val));
-            } else {
-                error!("Received an unexpected message from the client");
-                abort_tls!();
+/// Read the client's next message of a session in the background
+fn await_client(
+    mut client_conn: IncomingTcp,
+    session_id: u64,
+    established: bool,
+    acks: &UnboundedSender<PendingAck>,
+) {
+    let acks = acks.clone();
+    tokio::spawn(async move {
+        let reply = client_conn.timed_read().await;
+        let _ = acks.send(PendingAck {
+            session_id,
+            client_conn,
+            established,
+            reply,
+        });
+    });
+}
+
+/// Forward the client's next message of a session to the enclave and
+/// relay the enclave's reply. If the message is missing or malformed, the
+/// enclave is told to drop the session: a failing acknowledgement is sent
+/// for a pending handshake.
+async fn handle_ack(ack: PendingAck, enclave_conn: &mut Tcp, acks: &UnboundedSender<PendingAck>) {
+    let PendingAck {
+        session_id,
+        client_conn,
+        established,
+        reply,
+    } = ack;
+    let msg = match reply {
+        Some(Ok(ClientMsg::RATLSAck(ack))) if !established => {
+            let failed = matches!(ack, AckType::Fail);
+            enclave_conn.write(MsgFromHost::RATLSAck { session_id, ack });
+            if failed {
+                error!("Encountered unexpected error, aborting TLS connection setup.");
+                return;
             }
+            return relay_registration(client_conn, enclave_conn, session_id, acks).await;
         }
-        Err(e) => error!("Error receiving message from enclave: {e}"),
-    }
-    // Handle the final acknowledgement round
+        Some(Ok(ClientMsg::TlsRecords(records))) if !established => MsgFromHost::TlsRecords {
+            session_id,
+            records,
+        },
+        Some(Ok(ClientMsg::SessionRequest(cipher))) if established => {
+            MsgFromHost::SessionRequest { session_id, cipher }
+        }
+        Some(Ok(ClientMsg::CloseSession)) if established => {
+            enclave_conn.write(MsgFromHost::CloseSession { session_id });
+            return;
+        }
+        reply => {
+            match reply {
+                Some(Ok(_)) => error!("Received an unexpected message from the client"),
+                Some(Err(e)) => error!("Error receiving message from client: {e}"),
+                None => {}
+            }
+            enclave_conn.write(if established {
+                MsgFromHost::CloseSession { session_id }
+            } else {
+                MsgFromHost::RATLSAck {
+                    session_id,
+                    ack: AckType::Fail,
+                }
+            });
+            return;
+        }
+    };
+    enclave_conn.write(msg);
+    relay_registration(client_conn, enclave_conn, session_id, acks).await;
+}
+
+/// Relay the enclave's reply to a message of a session. If the session
+/// remains open, the client's next message is awaited.
+async fn relay_registration(
+    mut client_conn: IncomingTcp,
+    enclave_conn: &mut Tcp,
+    session_id: u64,
+    acks: &UnboundedSender<PendingAck>,
+) {
     match enclave_conn.read() {
         Ok(msg) => {
             info!("Received message: {:?}", msg);
-            // This should be a success message or an enclave error
-            // intended for the client.
-            if let Ok(resp) = ServerMsg::try_from(msg) {
-                client_conn.write(resp);
-            } else {
-                error!("Received an unexpected message from the enclave");
-                abort_tls!();
+            let open = matches!(msg, MsgToHost::SessionReply { .. });
+            // This should be a success message, an encrypted reply or
+            // an enclave error intended for the client.
+            match ServerMsg::try_from(msg) {
+                Ok(resp) => client_conn.write(resp).await,
+                Err(_) => error!("Received an unexpected message from the enclave"),
+            }
+            if open {
+                await_client(client_conn, session_id, true, acks);
             }
         }
         Err(e) => error!("Error receiving message from enclave: {e}"),
     }
 }
 
+/// Pass the runtime parameters from the config to the enclave.
+fn configure_enclave(enclave_conn: &mut Tcp, config: &EnclaveConfig) -> eyre::Result<()> {
+    enclave_conn.write(MsgFromHost::Configure {
+        config: config.clone(),
+        now: unix_time(),
+    });
+    match enclave_conn.read() {
+        Ok(MsgToHost::Configured) => Ok(()),
+        Ok(MsgToHost::Error(e)) => Err(eyre::eyre!("Enclave rejected its configuration: {e}")),
+        Ok(_) => Err(eyre::eyre!(
+            "Received an unexpected message from enclave in response to `Configure`"
+        )),
+        Err(e) => Err(eyre::eyre!("Error receiving message from enclave: {e}")),
+    }
+}
+
 /// Perform the next batch of work for fuzzy-message detection.
 fn handle_fmd(enclave_conn: &mut Tcp, db: &mut DB) {
-    enclave_conn.write(MsgFromHost::RequiredBlocks);
+    // Fix the watermark for this round so that the enclave plans
+    // with the same height it is later given flags up to.
+    let synced_to = db.synced_to();
+    enclave_conn.write(MsgFromHost::RequiredBlocks {
+        synced_to,
+        now: unix_time(),
+    });
     // Ask enclave what block heights to pass in
     let heights = match enclave_conn.read() {
-        Ok(MsgToHost::BlockRequests(mut ranges)) => {
+        Ok(MsgToHost::BlockRequests {
+            heights: mut ranges,
+            expired,
+        }) => {
+            if !expired.is_empty() {
+                info!(
+                    "Removing {} registrations with expired leases",
+                    expired.len()
+                );
+                if let Err(e) = db.remove_indices(&expired) {
+                    error!("{e}");
+                }
+            }
             ranges.sort();
             ranges.dedup();
             ranges
@@ -244,7 +551,7 @@ fn handle_fmd(enclave_conn: &mut Tcp, db: &mut DB) {
             return;
         }
         Err(e) => {
-            error!("Error receiving message from enclave: {e}");
+            resync(enclave_conn, e);
             return;
         }
     };
@@ -257,21 +564,43 @@ fn handle_fmd(enclave_conn: &mut Tcp, db: &mut DB) {
         .flat_map(|h| db.get_height(h).unwrap())
         .collect();
 
-    let synced_to = db.synced_to();
     enclave_conn.write(MsgFromHost::RequestedFlags { synced_to, flags });
 
-    let results = match enclave_conn.read() {
-        Ok(MsgToHost::FmdResults(ranges)) => ranges,
+    let (results, checkpoints) = match enclave_conn.read() {
+        Ok(MsgToHost::FmdResults {
+            results,
+            checkpoints,
+        }) => (results, checkpoints),
         Ok(_) => {
             error!("Received an unexpected message from enclave in response to `RequestedFlags`");
             return;
         }
         Err(e) => {
-            error!("Error receiving message from enclave: {e}");
+            resync(enclave_conn, e);
             return;
         }
     };
     db.update_indices(results).unwrap();
+    if let Err(e) = db.update_checkpoints(checkpoints) {
+        error!("{e}");
+    }
+}
+
+/// Log an error reading from the enclave and realign with it, so that
+/// the reply to an abandoned request is not read in place of a later one.
+fn resync(enclave_conn: &mut Tcp, e: MsgError) {
+    error!("Error receiving message from enclave: {e}");
+    if let Err(e) = enclave_conn.resync() {
+        error!("Failed to resync with the enclave: {e}");
+    }
+}
+
+/// The current time in seconds since the Unix epoch
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
 }
 
 fn init_logging() {
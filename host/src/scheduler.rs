@@ -7,24 +7,33 @@ use std::time::Duration;
 
 use futures::future::{BoxFuture, FutureExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::time::Sleep;
 
+use crate::PendingAck;
 use crate::db::InterruptFlag;
 
 /// A struct for creating biased combined futures
-/// for interrupts, incoming connections, and background work.
+/// for interrupts, client replies to pending handshakes,
+/// incoming connections, and background work.
 /// This will act as an event scheduler for the host
 pub struct EventScheduler {
     listener: TcpListener,
     interrupt_flag: InterruptFlag,
+    acks: UnboundedReceiver<PendingAck>,
 }
 
 impl EventScheduler {
     /// Create a new event scheduler
-    pub fn new(listener: TcpListener, interrupt_flag: InterruptFlag) -> Self {
+    pub fn new(
+        listener: TcpListener,
+        interrupt_flag: InterruptFlag,
+        acks: UnboundedReceiver<PendingAck>,
+    ) -> Self {
         Self {
             listener,
             interrupt_flag,
+            acks,
         }
     }
 
@@ -33,6 +42,7 @@ impl EventScheduler {
         NextQuery {
             accept: self.listener.accept().boxed(),
             dropped: self.interrupt_flag.dropped().boxed(),
+            ack: self.acks.recv().boxed(),
             timeout: Box::pin(tokio::time::sleep(Duration::from_millis(10))),
         }
     }
@@ -42,6 +52,8 @@ impl EventScheduler {
 pub enum NextEvent {
     /// An interrupt request was received
     Interrupt,
+    /// A client replied to a pending handshake
+    Ack(PendingAck),
     /// A client request was received
     Accept(TcpStream),
     /// Updated registered keys against latest MASP txs.
@@ -50,34 +62,46 @@ pub enum NextEvent {
     PerformFmd,
 }
 
-/// A future which first checks for an interrupt, then
-/// checks for an incoming client, then defaults to performing
+/// A future which first checks for an interrupt, then for
+/// replies to pending handshakes, then checks for an
+/// incoming client, then defaults to performing
 /// FMD. The default is spaced out with a small sleep to
 /// prevent starving the other futures.
-pub struct NextQuery<'f1, 'f2> {
+pub struct NextQuery<'f1, 'f2, 'f3> {
     accept: BoxFuture<'f1, std::io::Result<(TcpStream, SocketAddr)>>,
     dropped: BoxFuture<'f2, bool>,
+    ack: BoxFuture<'f3, Option<PendingAck>>,
     timeout: Pin<Box<Sleep>>,
 }
 
-impl Future for NextQuery<'_, '_> {
+impl Future for NextQuery<'_, '_, '_> {
     type Output = NextEvent;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         match self.dropped.as_mut().poll(cx) {
             Poll::Ready(_) => Poll::Ready(NextEvent::Interrupt),
-            Poll::Pending => match self.accept.as_mut().poll(cx) {
-                Poll::Ready(Ok((stream, _))) => Poll::Ready(NextEvent::Accept(stream)),
-                Poll::Ready(Err(e)) => {
-                    tracing::error!(
-                        "Encountered unexpected error while listening for new connections: {e}"
-                    );
-                    Poll::Ready(NextEvent::PerformFmd)
-                }
-                _ => match self.timeout.as_mut().poll(cx) {
-                    Poll::Ready(_) => Poll::Ready(NextEvent::PerformFmd),
-                    Poll::Pending => Poll::Pending,
-                },
+            Poll::Pending => match self.ack.as_mut().poll(cx) {
+                Poll::Ready(Some(ack)) => Poll::Ready(NextEvent::Ack(ack)),
+                _ => self.poll_accept(cx),
+            },
+        }
+    }
+}
+
+impl NextQuery<'_, '_, '_> {
+    /// Check for an incoming client, then default to performing FMD
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<NextEvent> {
+        match self.accept.as_mut().poll(cx) {
+            Poll::Ready(Ok((stream, _))) => Poll::Ready(NextEvent::Accept(stream)),
+            Poll::Ready(Err(e)) => {
+                tracing::error!(
+                    "Encountered unexpected error while listening for new connections: {e}"
+                );
+                Poll::Ready(NextEvent::PerformFmd)
+            }
+            _ => match self.timeout.as_mut().poll(cx) {
+                Poll::Ready(_) => Poll::Ready(NextEvent::PerformFmd),
+                Poll::Pending => Poll::Pending,
             },
         }
     }
@@ -0,0 +1,57 @@
+//! Throughput of reading large frames over the buffered TCP channel
+//! between the host and enclave.
+
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use kassandra_shared::tcp::Tcp;
+use kassandra_shared::{FramedBytes, MsgFromHost};
+
+/// The sizes of the frames read, in MiB
+const FRAME_SIZES: [usize; 3] = [1, 4, 16];
+
+/// A connected pair of channels over loopback
+fn channel() -> (Tcp, Tcp) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Bench failed");
+    let writer =
+        TcpStream::connect(listener.local_addr().expect("Bench failed")).expect("Bench failed");
+    let (reader, _) = listener.accept().expect("Bench failed");
+    (Tcp::new(writer), Tcp::new(reader))
+}
+
+fn read_frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_frame");
+    group.sample_size(10);
+    for size in FRAME_SIZES {
+        let msg = MsgFromHost::Basic("a".repeat(size << 20));
+        group.throughput(Throughput::Bytes((size << 20) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{size}MiB")),
+            &msg,
+            |b, msg| {
+                b.iter_custom(|iters| {
+                    let (mut writer, mut reader) = channel();
+                    let msg = msg.clone();
+                    let handle = thread::spawn(move || {
+                        for _ in 0..iters {
+                            writer.write_frame(&msg).expect("Bench failed");
+                        }
+                    });
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        reader.get_frame().expect("Bench failed");
+                    }
+                    let elapsed: Duration = start.elapsed();
+                    handle.join().expect("Bench failed");
+                    elapsed
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, read_frames);
+criterion_main!(benches);
@@ -0,0 +1,11 @@
+#![no_main]
+
+use kassandra_shared::{ClientMsg, EnclaveReply, HostRequest, ServerMsg};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = serde_cbor::from_slice::<HostRequest>(data);
+    let _ = serde_cbor::from_slice::<EnclaveReply>(data);
+    let _ = serde_cbor::from_slice::<ClientMsg>(data);
+    let _ = serde_cbor::from_slice::<ServerMsg>(data);
+});
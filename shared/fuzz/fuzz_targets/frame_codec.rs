@@ -0,0 +1,17 @@
+#![no_main]
+
+use kassandra_shared::codec::HostCodec;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    // Feed the input in two chunks to exercise resuming a partial scan
+    let (first, second) = data.split_at(data.first().map_or(0, |b| *b as usize % (data.len() + 1)));
+    let mut codec = HostCodec::new(1 << 10);
+    let mut buf = BytesMut::from(first);
+    for chunk in [&[][..], second] {
+        buf.extend_from_slice(chunk);
+        while let Ok(Some(_)) = codec.decode(&mut buf) {}
+    }
+});
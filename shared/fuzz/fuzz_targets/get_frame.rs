@@ -0,0 +1,32 @@
+#![no_main]
+
+use std::collections::VecDeque;
+
+use kassandra_shared::{FramedBytes, HostRequest, MsgError, ReadWriteByte};
+use libfuzzer_sys::fuzz_target;
+
+/// A channel which yields the fuzzer input and then disconnects
+struct Input(VecDeque<u8>);
+
+impl ReadWriteByte for Input {
+    fn read_byte(&mut self) -> Result<u8, MsgError> {
+        self.0.pop_front().ok_or(MsgError::Disconnected)
+    }
+
+    fn write_bytes(&mut self, _: &[u8]) -> Result<(), MsgError> {
+        Ok(())
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut channel = Input(data.iter().copied().collect());
+    loop {
+        match channel.get_frame() {
+            Ok(frame) => {
+                let _ = frame.deserialize::<HostRequest>();
+            }
+            Err(e) if e.is_disconnect() => break,
+            Err(_) => {}
+        }
+    }
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use kassandra_shared::HexBytes;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = serde_cbor::from_slice::<HexBytes<32>>(data);
+    let _ = serde_cbor::from_slice::<HexBytes<64>>(data);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use kassandra_shared::IndexList;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = IndexList::try_from_bytes(data);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use kassandra_shared::ratls::TlsCiphertext;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = serde_cbor::from_slice::<TlsCiphertext>(data);
+});
@@ -0,0 +1,160 @@
+//! Checkpoints are statements signed by the enclave about the flags it
+//! was given for a block height. The signing key is generated inside the
+//! enclave and bound into the report data of its RA-TLS handshakes, so
+//! clients can verify checkpoints served by an untrusted host. Comparing
+//! the digests of checkpoints across providers reveals a host that
+//! withholds txs from its enclave.
+
+use alloc::vec::Vec;
+
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use fmd::fmd2_compact::FlagCiphertexts;
+use rand_core::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use thiserror::Error;
+
+use crate::HexBytes;
+use crate::db::Index;
+
+/// Domain separator for checkpoint signatures
+const CHECKPOINT_DOMAIN: &[u8] = b"Kassandra checkpoint";
+/// Domain separator for digests of flags
+const FLAGS_DOMAIN: &[u8] = b"Kassandra flags";
+
+/// The number of bytes of the RA-TLS report data binding the
+/// checkpoint key
+pub const KEY_BINDING_LEN: usize = 24;
+
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("Checkpoint key is not a valid Ed25519 public key")]
+    InvalidKey,
+    #[error("Checkpoint key does not match the one attested to")]
+    UnattestedKey,
+    #[error("Signature of checkpoint at height {0} is invalid")]
+    InvalidSignature(u64),
+}
+
+/// The enclave's key for signing checkpoints
+#[derive(Clone)]
+pub struct CheckpointKey(SigningKey);
+
+impl CheckpointKey {
+    pub fn generate<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        Self(SigningKey::generate(rng))
+    }
+
+    /// The public key clients verify checkpoints with
+    pub fn verifying_key(&self) -> HexBytes<32> {
+        self.0.verifying_key().to_bytes().into()
+    }
+
+    /// Sign the digest of the flags of a block height
+    pub fn sign(&self, height: u64, digest: [u8; 32]) -> Checkpoint {
+        let signature = self.0.sign(&signed_message(height, &digest));
+        Checkpoint {
+            height,
+            digest: digest.into(),
+            signature: signature.to_bytes().into(),
+        }
+    }
+}
+
+/// The part of the RA-TLS report data that binds a checkpoint key
+/// to the attested enclave
+pub fn key_binding(key: &HexBytes<32>) -> [u8; KEY_BINDING_LEN] {
+    let hash: [u8; 32] = sha2::Sha256::digest(key.0).into();
+    let mut binding = [0u8; KEY_BINDING_LEN];
+    binding.copy_from_slice(&hash[..KEY_BINDING_LEN]);
+    binding
+}
+
+/// Check that a checkpoint key is the one bound into the report data
+/// of an attestation.
+pub fn verify_key_binding(
+    key: &HexBytes<32>,
+    report_data: &[u8; 64],
+) -> Result<(), CheckpointError> {
+    VerifyingKey::from_bytes(&key.0).map_err(|_| CheckpointError::InvalidKey)?;
+    if report_data[64 - KEY_BINDING_LEN..] == key_binding(key) {
+        Ok(())
+    } else {
+        Err(CheckpointError::UnattestedKey)
+    }
+}
+
+/// A digest of the flags of a block height. Flags are hashed in order
+/// of their index so that all providers given the same flags agree.
+pub fn flags_digest(height: u64, flags: &[(Index, Option<FlagCiphertexts>)]) -> [u8; 32] {
+    let mut sorted: Vec<_> = flags.iter().collect();
+    sorted.sort_by_key(|(ix, _)| *ix);
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(FLAGS_DOMAIN);
+    hasher.update(height.to_le_bytes());
+    for (ix, flag) in sorted {
+        hasher.update(ix.as_bytes());
+        match flag {
+            None => hasher.update([0]),
+            Some(flag) => {
+                hasher.update([1]);
+                hasher.update(serde_cbor::to_vec(flag).unwrap());
+            }
+        }
+    }
+    hasher.finalize().into()
+}
+
+fn signed_message(height: u64, digest: &[u8; 32]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(CHECKPOINT_DOMAIN.len() + 40);
+    msg.extend_from_slice(CHECKPOINT_DOMAIN);
+    msg.extend_from_slice(&height.to_le_bytes());
+    msg.extend_from_slice(digest);
+    msg
+}
+
+/// A statement by the enclave that it was given flags with the
+/// contained digest for a block height
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub height: u64,
+    pub digest: HexBytes<32>,
+    pub signature: HexBytes<64>,
+}
+
+impl Checkpoint {
+    /// Verify the checkpoint was signed by the given key
+    pub fn verify(&self, key: &HexBytes<32>) -> Result<(), CheckpointError> {
+        let key = VerifyingKey::from_bytes(&key.0).map_err(|_| CheckpointError::InvalidKey)?;
+        let signature = ed25519_dalek::Signature::from_bytes(&self.signature.0);
+        key.verify(&signed_message(self.height, &self.digest.0), &signature)
+            .map_err(|_| CheckpointError::InvalidSignature(self.height))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test that checkpoints verify against the key that signed them,
+    /// and only if they are unaltered.
+    #[test]
+    fn test_verify_checkpoint() {
+        let key = CheckpointKey(SigningKey::from_bytes(&[1; 32]));
+        let pk = key.verifying_key();
+        let digest = flags_digest(5, &[(Index { height: 5, tx: 0 }, None)]);
+        let checkpoint = key.sign(5, digest);
+        assert!(checkpoint.verify(&pk).is_ok());
+
+        let mut altered = checkpoint;
+        altered.height = 6;
+        assert!(altered.verify(&pk).is_err());
+        let other = CheckpointKey(SigningKey::from_bytes(&[2; 32])).verifying_key();
+        assert!(checkpoint.verify(&other).is_err());
+
+        let mut report_data = [0u8; 64];
+        report_data[64 - KEY_BINDING_LEN..].copy_from_slice(&key_binding(&pk));
+        assert!(verify_key_binding(&pk, &report_data).is_ok());
+        assert!(verify_key_binding(&other, &report_data).is_err());
+    }
+}
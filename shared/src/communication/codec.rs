@@ -0,0 +1,106 @@
+//! An async codec framing messages the same way as [`FramedBytes`],
+//! for use with tokio streams. Reads and writes are then cancellable,
+//! so timeouts interrupt them rather than waiting on a blocked read.
+//!
+//! [`FramedBytes`]: crate::FramedBytes
+
+use core::marker::PhantomData;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio_util::bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{decode_frame, encode_frame, max_encoded_len};
+use crate::{ClientMsg, MsgError, ServerMsg};
+
+/// The codec a host uses to talk with clients
+pub type HostCodec = FrameCodec<ClientMsg, ServerMsg>;
+/// The codec a client uses to talk with a host
+pub type ClientCodec = FrameCodec<ServerMsg, ClientMsg>;
+
+/// Decodes frames into messages of type `In` and encodes messages
+/// of type `Out` into frames.
+pub struct FrameCodec<In, Out> {
+    /// The largest frame, excluding its checksum, accepted
+    max_frame_size: usize,
+    /// How far the buffer has been searched for the end of a frame
+    scanned: usize,
+    _msgs: PhantomData<fn(Out) -> In>,
+}
+
+impl<In, Out> FrameCodec<In, Out> {
+    /// Create a codec accepting frames of at most `max_frame_size` bytes
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size,
+            scanned: 0,
+            _msgs: PhantomData,
+        }
+    }
+}
+
+impl<In: DeserializeOwned, Out> Decoder for FrameCodec<In, Out> {
+    type Item = In;
+    type Error = MsgError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<In>, MsgError> {
+        let Some(end) = src[self.scanned..].iter().position(|b| *b == 0) else {
+            self.scanned = src.len();
+            if self.scanned > max_encoded_len(self.max_frame_size) {
+                return Err(MsgError::FrameTooLarge(self.max_frame_size));
+            }
+            return Ok(None);
+        };
+        let end = self.scanned + end;
+        self.scanned = 0;
+        let bytes = src.split_to(end).to_vec();
+        src.advance(1);
+        decode_frame(bytes, self.max_frame_size)?
+            .deserialize()
+            .map(Some)
+    }
+}
+
+impl<In, Out: Serialize> Encoder<Out> for FrameCodec<In, Out> {
+    type Error = MsgError;
+
+    fn encode(&mut self, msg: Out, dst: &mut BytesMut) -> Result<(), MsgError> {
+        dst.extend_from_slice(&encode_frame(&msg));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    /// Test that frames split across reads are decoded once complete
+    #[test]
+    fn test_partial_frames() {
+        let mut written = BytesMut::new();
+        let mut host = HostCodec::new(1024);
+        host.encode(ServerMsg::Error("Test".to_string()), &mut written)
+            .expect("Test failed");
+        host.encode(ServerMsg::KeyRegSuccess, &mut written)
+            .expect("Test failed");
+
+        let mut codec = ClientCodec::new(1024);
+        let mut src = BytesMut::new();
+        let (first, rest) = written.split_at(5);
+        src.extend_from_slice(first);
+        assert!(codec.decode(&mut src).expect("Test failed").is_none());
+        src.extend_from_slice(rest);
+        let Some(ServerMsg::Error(err)) = codec.decode(&mut src).expect("Test failed") else {
+            panic!("Test failed");
+        };
+        assert_eq!(err, "Test");
+        assert!(matches!(
+            codec.decode(&mut src),
+            Ok(Some(ServerMsg::KeyRegSuccess))
+        ));
+        assert!(src.is_empty());
+    }
+}
@@ -3,11 +3,14 @@
 //! a host environment and an enclave as enclaves may be resource constrained, making
 //! higher level abstractions unavailable.
 
+#[cfg(feature = "tokio")]
+pub mod codec;
+#[cfg(test)]
+mod proptests;
 #[cfg(feature = "std")]
 pub mod tcp;
 
 use alloc::string::String;
-use alloc::vec;
 use alloc::vec::Vec;
 
 use fmd::fmd2_compact::FlagCiphertexts;
@@ -15,10 +18,15 @@ use serde::de::{DeserializeOwned, Error};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use thiserror::Error;
 
+use crate::checkpoint::Checkpoint;
+use crate::config::EnclaveConfig;
 use crate::db::{EncryptedResponse, Index};
-use crate::ratls::TlsCiphertext;
+use crate::lease::LeaseRenewal;
+use crate::ratls::{HandshakeVersion, TlsCiphertext};
+use crate::status::EnclaveStatus;
+use crate::update::KeyUpdate;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct HexBytes<const N: usize>(pub [u8; N]);
 
 impl<const N: usize> From<[u8; N]> for HexBytes<N> {
@@ -55,6 +63,7 @@ macro_rules! impl_serde {
     };
 }
 
+impl_serde!(12);
 impl_serde!(32);
 impl_serde!(64);
 
@@ -64,40 +73,143 @@ pub enum MsgToHost {
     Basic(String),
     Error(String),
     ErrorForClient(String),
-    RATLS { report: Vec<u8> },
+    /// The attestation report of an RA-TLS handshake along with the
+    /// enclave's checkpoint key, which the report data binds.
+    RATLS {
+        session_id: u64,
+        report: Vec<u8>,
+        checkpoint_key: HexBytes<32>,
+        /// The enclave's key confirmation, from handshake version 2
+        #[serde(default)]
+        confirm: Option<HexBytes<32>>,
+    },
     Report(Vec<u8>),
     KeyRegSuccess,
-    BlockRequests(Vec<u64>),
-    FmdResults(Vec<EncryptedResponse>),
+    /// The block heights needed for the next round of FMD along
+    /// with the owners of registrations whose leases expired.
+    BlockRequests {
+        heights: Vec<u64>,
+        expired: Vec<String>,
+    },
+    /// The results of a round of FMD along with checkpoints of the
+    /// block heights whose flags were consumed.
+    FmdResults {
+        results: Vec<EncryptedResponse>,
+        checkpoints: Vec<Checkpoint>,
+    },
+    Configured,
+    LeaseRenewed,
+    /// A registration was updated. If its encryption key was rotated,
+    /// results stored under the previous owner are stale.
+    KeyUpdated {
+        stale: Option<String>,
+    },
+    /// The reply to a [`MsgFromHost::Ping`], echoing its nonce
+    Pong(u64),
+    Status(EnclaveStatus),
+    /// The reply to a [`MsgFromHost::Resync`]
+    Resynced,
+    /// The enclave's TLS handshake records in reply to a
+    /// [`MsgFromHost::TlsHello`], along with its checkpoint key, which
+    /// the quote in its certificate binds.
+    TlsHandshake {
+        session_id: u64,
+        records: Vec<u8>,
+        checkpoint_key: HexBytes<32>,
+    },
+    /// An encrypted [`crate::ratls::SessionReply`] within an established
+    /// session. The session remains open for further requests.
+    SessionReply {
+        session_id: u64,
+        cipher: TlsCiphertext,
+    },
 }
 
 /// Messages from host environment to the enclave
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MsgFromHost {
     Basic(String),
+    /// Start an RA-TLS handshake. The session id is chosen by the
+    /// host and names the handshake until it is acknowledged.
     RegisterKey {
+        session_id: u64,
         nonce: u64,
         pk: HexBytes<32>,
+        #[serde(default)]
+        version: HandshakeVersion,
     },
     RequestReport {
         user_data: HexBytes<64>,
     },
-    RATLSAck(AckType),
-    RequiredBlocks,
+    /// Check that the enclave is responsive. It replies with the
+    /// same nonce.
+    Ping(u64),
+    /// Request aggregate, non-secret statistics about the enclave
+    RequestStatus,
+    /// A barrier for the host to realign with the enclave. Every reply
+    /// to earlier requests precedes the reply to this one.
+    Resync,
+    /// The client's acknowledgement of the handshake with the given id
+    RATLSAck {
+        session_id: u64,
+        ack: AckType,
+    },
+    /// Set the runtime parameters of the enclave. The current time, in
+    /// seconds since the Unix epoch, is given so that leases granted before
+    /// the first round of FMD are timed correctly.
+    Configure {
+        config: EnclaveConfig,
+        now: u64,
+    },
+    /// Ask which blocks the enclave wants to process next given
+    /// the height the host is fully synced to and the current time
+    /// in seconds since the Unix epoch.
+    RequiredBlocks {
+        synced_to: u64,
+        now: u64,
+    },
     RequestedFlags {
         synced_to: u64,
         flags: Vec<(Index, Option<FlagCiphertexts>)>,
     },
+    RenewLease(LeaseRenewal),
+    UpdateKey(KeyUpdate),
+    /// Start a TLS 1.3 handshake with the client's challenge nonce and
+    /// its first records. The session id is chosen by the host as for
+    /// [`MsgFromHost::RegisterKey`].
+    TlsHello {
+        session_id: u64,
+        nonce: u64,
+        records: Vec<u8>,
+    },
+    /// The client's remaining TLS records for the session with the given id
+    TlsRecords {
+        session_id: u64,
+        records: Vec<u8>,
+    },
+    /// A further encrypted [`crate::ratls::SessionRequest`] within an
+    /// established session
+    SessionRequest {
+        session_id: u64,
+        cipher: TlsCiphertext,
+    },
+    /// The client has left an established session. There is no reply.
+    CloseSession {
+        session_id: u64,
+    },
 }
 
 /// Messages from clients to hosts
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientMsg {
     /// Gives the clients public part of the shared key
-    /// and requests the enclaves part.
+    /// and requests the enclaves part. Clients predating
+    /// handshake versions use version 1.
     RegisterKey {
         nonce: u64,
         pk: HexBytes<32>,
+        #[serde(default)]
+        version: HandshakeVersion,
     },
     RequestReport {
         user_data: HexBytes<64>,
@@ -108,25 +220,84 @@ pub enum ClientMsg {
     RequestIndices {
         key_hash: String,
     },
+    /// Request the enclave's checkpoints for the block heights
+    /// in the inclusive range
+    RequestCheckpoints {
+        from: u64,
+        to: u64,
+    },
+    /// Extend the lease of a registered key
+    RenewLease(LeaseRenewal),
+    /// Change the keys of a registration
+    UpdateKey(KeyUpdate),
+    /// Start a TLS 1.3 handshake with the enclave. The nonce is a
+    /// challenge the quote in the enclave's certificate must contain.
+    TlsHello {
+        nonce: u64,
+        records: Vec<u8>,
+    },
+    /// Finish a TLS 1.3 handshake, along with any encrypted messages
+    TlsRecords(Vec<u8>),
+    /// A further encrypted request within an established session
+    SessionRequest(TlsCiphertext),
+    /// Leave an established session
+    CloseSession,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum AckType {
+    /// The encrypted message of a version 1 handshake
     Success(TlsCiphertext),
     Fail,
+    /// The client's key confirmation and the first encrypted
+    /// [`crate::ratls::SessionRequest`] of a version 2 handshake
+    Confirmed {
+        confirm: HexBytes<32>,
+        cipher: TlsCiphertext,
+    },
 }
 
 /// Messages from hosts to clients
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerMsg {
-    /// The raw report bytes
+    /// The raw report bytes along with the enclave's checkpoint key
+    /// and, from handshake version 2, its key confirmation
     RATLS {
         report: Vec<u8>,
+        checkpoint_key: HexBytes<32>,
+        #[serde(default)]
+        confirm: Option<HexBytes<32>>,
     },
     Error(String),
     KeyRegSuccess,
     UUID(String),
     IndicesResponse(EncryptedResponse),
+    Checkpoints(Vec<Checkpoint>),
+    LeaseRenewed,
+    KeyUpdated,
+    /// The enclave's TLS handshake records along with its checkpoint key
+    TlsHandshake {
+        records: Vec<u8>,
+        checkpoint_key: HexBytes<32>,
+    },
+    /// The enclave's encrypted reply within an established session
+    SessionReply(TlsCiphertext),
+}
+
+/// A message from the host tagged with an id. Ids increase with
+/// each request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostRequest {
+    pub id: u64,
+    pub msg: MsgFromHost,
+}
+
+/// A message from the enclave echoing the id of the request it answers.
+/// The id is missing if the request could not be read.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnclaveReply {
+    pub id: Option<u64>,
+    pub msg: MsgToHost,
 }
 
 impl<'a> TryFrom<&'a ClientMsg> for MsgFromHost {
@@ -134,14 +305,11 @@ impl<'a> TryFrom<&'a ClientMsg> for MsgFromHost {
 
     fn try_from(msg: &'a ClientMsg) -> Result<Self, Self::Error> {
         match msg {
-            ClientMsg::RegisterKey { nonce, pk } => Ok(MsgFromHost::RegisterKey {
-                nonce: *nonce,
-                pk: *pk,
-            }),
             ClientMsg::RequestReport { user_data } => Ok(MsgFromHost::RequestReport {
                 user_data: *user_data,
             }),
-            ClientMsg::RATLSAck(v) => Ok(MsgFromHost::RATLSAck(v.clone())),
+            ClientMsg::RenewLease(renewal) => Ok(MsgFromHost::RenewLease(renewal.clone())),
+            ClientMsg::UpdateKey(update) => Ok(MsgFromHost::UpdateKey(update.clone())),
             _ => Err("Message not intended for enclave"),
         }
     }
@@ -152,9 +320,32 @@ impl TryFrom<MsgToHost> for ServerMsg {
 
     fn try_from(msg: MsgToHost) -> Result<Self, &'static str> {
         match msg {
-            MsgToHost::RATLS { report } => Ok(ServerMsg::RATLS { report }),
-            MsgToHost::ErrorForClient(err) => Ok(ServerMsg::Error(err)),
+            MsgToHost::RATLS {
+                report,
+                checkpoint_key,
+                confirm,
+                ..
+            } => Ok(ServerMsg::RATLS {
+                report,
+                checkpoint_key,
+                confirm,
+            }),
+            // Errors replying to a client's request are relayed to it,
+            // even if the enclave meant them for the host, so that the
+            // client does not just see a disconnect.
+            MsgToHost::Error(err) | MsgToHost::ErrorForClient(err) => Ok(ServerMsg::Error(err)),
             MsgToHost::KeyRegSuccess => Ok(ServerMsg::KeyRegSuccess),
+            MsgToHost::LeaseRenewed => Ok(ServerMsg::LeaseRenewed),
+            MsgToHost::KeyUpdated { .. } => Ok(ServerMsg::KeyUpdated),
+            MsgToHost::TlsHandshake {
+                records,
+                checkpoint_key,
+                ..
+            } => Ok(ServerMsg::TlsHandshake {
+                records,
+                checkpoint_key,
+            }),
+            MsgToHost::SessionReply { cipher, .. } => Ok(ServerMsg::SessionReply(cipher)),
             _ => Err("Message not intended for client"),
         }
     }
@@ -168,6 +359,32 @@ pub enum MsgError {
     Deserialize(serde_cbor::Error),
     #[error("Input bytes were not valid utf-8: {0:?}")]
     Utf8(Vec<u8>),
+    #[error("Frame exceeds the maximum size of {0} bytes")]
+    FrameTooLarge(usize),
+    #[error("Frame of {0} bytes is too short to hold a checksum")]
+    Truncated(usize),
+    #[error("Frame checksum {computed:#010x} does not match the expected {expected:#010x}")]
+    Checksum { expected: u32, computed: u32 },
+    #[error("Received a reply to request {received} while awaiting request {expected}")]
+    OutOfOrder { expected: u64, received: u64 },
+    #[cfg(feature = "std")]
+    #[error("I/O error on the channel: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The peer closed the channel")]
+    Disconnected,
+}
+
+impl MsgError {
+    /// Whether the channel can no longer be used, as opposed to a
+    /// single message being malformed
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            Self::Disconnected => true,
+            #[cfg(feature = "std")]
+            Self::Io(_) => true,
+            _ => false,
+        }
+    }
 }
 
 pub struct Frame {
@@ -180,69 +397,106 @@ impl Frame {
     }
 }
 
+/// The length of the checksum trailing the payload of each frame
+const CHECKSUM_LEN: usize = 4;
+/// The largest frame, in bytes, a channel accepts by default
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1 << 24;
+/// The largest frame, in bytes, exchanged between the host and enclave.
+/// These carry the flags and results of whole rounds of FMD.
+pub const ENCLAVE_MAX_FRAME_SIZE: usize = 1 << 26;
+/// The largest request, in bytes, the host accepts from a client
+pub const CLIENT_MAX_FRAME_SIZE: usize = 1 << 20;
+
 /// A trait for getting the next byte in a byte stream
 pub trait ReadWriteByte {
+    /// The initial capacity of the buffer frames are read into
     const FRAME_BUF_SIZE: usize = 1024;
-    fn read_byte(&mut self) -> u8;
+    /// The largest frame, excluding its checksum, this channel accepts
+    const MAX_FRAME_SIZE: usize = DEFAULT_MAX_FRAME_SIZE;
 
-    fn write_bytes(&mut self, buf: &[u8]);
+    /// Block until a byte is read. Returns [`MsgError::Disconnected`]
+    /// once the peer has closed the channel.
+    fn read_byte(&mut self) -> Result<u8, MsgError>;
+
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<(), MsgError>;
 }
 
 /// A trait for reading / writing framed data from a byte stream.
 /// This trait should not be implemented directly, but rely on
 /// the default implementation.
+///
+/// Each frame is a CBOR payload followed by the little-endian CRC-32
+/// of the payload, COBS encoded and terminated by a zero byte.
 pub trait FramedBytes: ReadWriteByte {
     /// Blocking method that reads a frame
     ///
-    /// Uses an initial buffer with 1Kb in size. Dynamically increases the
-    /// size of the frame buffer by 1Kb until either the message is decoded
-    /// or an error occurs.
+    /// Bytes are buffered until the end of the frame and then decoded in
+    /// place. Frames longer than [`ReadWriteByte::MAX_FRAME_SIZE`] are
+    /// skipped without being buffered and frames whose checksum does not
+    /// match are rejected.
     ///
     /// Returns the raw framed bytes
     fn get_frame(&mut self) -> Result<Frame, MsgError> {
-        // initial buffer size for the frame
-        let mut buf_size = Self::FRAME_BUF_SIZE;
-        // keep track of bytes processed so far incase we need to increase
-        // buffer size
-        let mut read_bytes = Vec::<u8>::with_capacity(buf_size);
-        // continue trying to populate the frame buffer until
-        // a successful frame decoding or a decode error occurs.
+        let max_encoded = max_encoded_len(Self::MAX_FRAME_SIZE);
+        let mut bytes = Vec::<u8>::with_capacity(Self::FRAME_BUF_SIZE);
         loop {
-            // initial buffer
-            let mut frame_buf = vec![0u8; buf_size];
-            let mut decoder = cobs::CobsDecoder::new(&mut frame_buf);
-            decoder
-                .push(&read_bytes)
-                .expect("Previously read bytes should not produce a frame error.");
-
-            loop {
-                let b = self.read_byte();
-                read_bytes.push(b);
-                match decoder.feed(b) {
-                    Ok(None) => continue,
-                    Ok(Some(len)) => {
-                        frame_buf.truncate(len);
-                        return Ok(Frame { bytes: frame_buf });
-                    }
-                    Err(cobs::DecodeError::TargetBufTooSmall) => {
-                        // increase the buffer size ny 1Kb
-                        buf_size += Self::FRAME_BUF_SIZE;
-                        break;
-                    }
-                    Err(e) => return Err(MsgError::Decode(e)),
+            match self.read_byte()? {
+                0 => break,
+                _ if bytes.len() == max_encoded => {
+                    // skip the rest of the frame so that the next read
+                    // starts at a frame boundary
+                    while self.read_byte()? != 0 {}
+                    return Err(MsgError::FrameTooLarge(Self::MAX_FRAME_SIZE));
                 }
+                b => bytes.push(b),
             }
         }
+        decode_frame(bytes, Self::MAX_FRAME_SIZE)
     }
 
     /// Write a serializable message out to the serial port in CBOR,
-    /// framed with COBS.
-    fn write_frame<T: Serialize>(&mut self, msg: &T) {
-        let data = serde_cbor::to_vec(&msg).unwrap();
-        let mut encoded = cobs::encode_vec_with_sentinel(&data, 0);
-        encoded.push(0);
-        self.write_bytes(&encoded);
+    /// followed by its checksum and framed with COBS.
+    fn write_frame<T: Serialize>(&mut self, msg: &T) -> Result<(), MsgError> {
+        self.write_bytes(&encode_frame(msg))
+    }
+}
+
+/// The most encoded bytes a frame of at most `max_frame_size` bytes
+/// occupies, excluding the terminating zero byte
+fn max_encoded_len(max_frame_size: usize) -> usize {
+    cobs::max_encoding_length(max_frame_size + CHECKSUM_LEN)
+}
+
+/// Decode the bytes of a frame, excluding the terminating zero byte,
+/// in place and check its checksum.
+fn decode_frame(mut bytes: Vec<u8>, max_frame_size: usize) -> Result<Frame, MsgError> {
+    let len = cobs::decode_in_place(&mut bytes).map_err(MsgError::Decode)?;
+    let Some(payload_len) = len.checked_sub(CHECKSUM_LEN) else {
+        return Err(MsgError::Truncated(len));
+    };
+    if payload_len > max_frame_size {
+        return Err(MsgError::FrameTooLarge(max_frame_size));
+    }
+    let mut checksum = [0u8; CHECKSUM_LEN];
+    checksum.copy_from_slice(&bytes[payload_len..len]);
+    bytes.truncate(payload_len);
+    let expected = u32::from_le_bytes(checksum);
+    let computed = crc32fast::hash(&bytes);
+    if expected != computed {
+        return Err(MsgError::Checksum { expected, computed });
     }
+    Ok(Frame { bytes })
+}
+
+/// Serialize a message to CBOR, append its checksum, and frame it
+/// with COBS.
+fn encode_frame<T: Serialize>(msg: &T) -> Vec<u8> {
+    let mut data = serde_cbor::to_vec(&msg).unwrap();
+    let checksum = crc32fast::hash(&data);
+    data.extend_from_slice(&checksum.to_le_bytes());
+    let mut encoded = cobs::encode_vec_with_sentinel(&data, 0);
+    encoded.push(0);
+    encoded
 }
 
 impl<T: ReadWriteByte> FramedBytes for T {}
@@ -251,34 +505,102 @@ impl<T: ReadWriteByte> FramedBytes for T {}
 mod tests {
     use super::*;
     use alloc::string::ToString;
+    use alloc::vec;
 
     struct MockChannel(Vec<u8>);
 
     impl ReadWriteByte for MockChannel {
         const FRAME_BUF_SIZE: usize = 10;
-        fn read_byte(&mut self) -> u8 {
-            self.0.remove(0)
+        fn read_byte(&mut self) -> Result<u8, MsgError> {
+            if self.0.is_empty() {
+                return Err(MsgError::Disconnected);
+            }
+            Ok(self.0.remove(0))
         }
 
-        fn write_bytes(&mut self, buf: &[u8]) {
+        fn write_bytes(&mut self, buf: &[u8]) -> Result<(), MsgError> {
             self.0.extend_from_slice(buf);
+            Ok(())
         }
     }
 
-    /// Test that if the data we are decoding does not initially
-    /// fit into the frame buffer, we dynamically resize it until the
-    /// data fits and decoding is successful.
+    /// Test that frames longer than the initial buffer are read whole
+    /// and round trip through the channel.
     #[test]
-    fn test_dynamic_frame_resizing() {
-        let msg = MsgFromHost::Basic("Test".to_string());
-        let data = serde_cbor::to_vec(&msg).expect("Test failed");
-        let mut encoded = cobs::encode_vec_with_sentinel(&data, 0);
-        encoded.push(0);
-        let mut channel = MockChannel(encoded);
+    fn test_frame_round_trip() {
+        let mut channel = MockChannel(vec![]);
+        channel
+            .write_frame(&MsgFromHost::Basic("Test".to_string()))
+            .expect("Test failed");
         let frame = channel.get_frame().expect("Test failed");
         let Ok(MsgFromHost::Basic(str)) = frame.deserialize() else {
             panic!("Test failed");
         };
         assert_eq!(str, "Test");
+        assert!(channel.0.is_empty());
+    }
+
+    /// Test that oversized frames are skipped without desynchronizing
+    /// the channel, that corrupted frames are detected, and that a peer
+    /// disconnecting mid-frame is reported.
+    #[test]
+    fn test_frame_errors() {
+        struct SmallChannel(MockChannel);
+
+        impl ReadWriteByte for SmallChannel {
+            const MAX_FRAME_SIZE: usize = 16;
+            fn read_byte(&mut self) -> Result<u8, MsgError> {
+                self.0.read_byte()
+            }
+
+            fn write_bytes(&mut self, buf: &[u8]) -> Result<(), MsgError> {
+                self.0.write_bytes(buf)
+            }
+        }
+
+        let mut channel = SmallChannel(MockChannel(vec![]));
+        channel
+            .write_frame(&MsgFromHost::Basic(
+                "A much longer test message".to_string(),
+            ))
+            .expect("Test failed");
+        channel
+            .write_frame(&MsgFromHost::Basic("Test".to_string()))
+            .expect("Test failed");
+        assert!(matches!(
+            channel.get_frame(),
+            Err(MsgError::FrameTooLarge(16))
+        ));
+        let frame = channel.get_frame().expect("Test failed");
+        assert!(matches!(frame.deserialize(), Ok(MsgFromHost::Basic(_))));
+
+        let mut channel = MockChannel(vec![]);
+        channel
+            .write_frame(&MsgFromHost::Basic("Test".to_string()))
+            .expect("Test failed");
+        channel.0[3] ^= 1;
+        assert!(matches!(
+            channel.get_frame(),
+            Err(MsgError::Checksum { .. })
+        ));
+
+        let mut channel = MockChannel(vec![]);
+        channel
+            .write_frame(&MsgFromHost::Basic("Test".to_string()))
+            .expect("Test failed");
+        channel.0.pop();
+        assert!(matches!(channel.get_frame(), Err(MsgError::Disconnected)));
+    }
+
+    /// Test that errors in reply to a client's request reach the client
+    #[test]
+    fn test_relay_errors() {
+        for msg in [
+            MsgToHost::Error("Session id is already in use".to_string()),
+            MsgToHost::ErrorForClient("Too many open sessions".to_string()),
+        ] {
+            assert!(matches!(ServerMsg::try_from(msg), Ok(ServerMsg::Error(_))));
+        }
+        assert!(ServerMsg::try_from(MsgToHost::Configured).is_err());
     }
 }
@@ -0,0 +1,429 @@
+//! Property tests that every message survives being framed, sent and
+//! decoded, and that decoding arbitrary bytes fails without panicking.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::*;
+use crate::config::{
+    FalsePositiveBounds, PaddingPolicy, ScheduleConfig, SchedulePolicy, SessionLimits,
+};
+use crate::lease::Lease;
+use crate::ratls::{HandshakeVersion, TlsCiphertext};
+use crate::status::{EnclaveStatus, SyncedTo};
+
+/// A channel writing into and reading from a buffer
+struct Buffer(alloc::collections::VecDeque<u8>);
+
+impl ReadWriteByte for Buffer {
+    fn read_byte(&mut self) -> Result<u8, MsgError> {
+        self.0.pop_front().ok_or(MsgError::Disconnected)
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<(), MsgError> {
+        self.0.extend(buf);
+        Ok(())
+    }
+}
+
+/// Check that a message is decoded to one serializing identically
+fn round_trip<T: Serialize + DeserializeOwned>(msg: &T) -> Result<(), TestCaseError> {
+    let mut channel = Buffer(Default::default());
+    channel.write_frame(msg).expect("Test failed");
+    let decoded: T = channel
+        .get_frame()
+        .and_then(Frame::deserialize)
+        .map_err(|e| TestCaseError::fail(e.to_string()))?;
+    prop_assert_eq!(
+        serde_cbor::to_vec(&decoded).expect("Test failed"),
+        serde_cbor::to_vec(msg).expect("Test failed")
+    );
+    prop_assert!(channel.0.is_empty());
+    Ok(())
+}
+
+fn hex32() -> impl Strategy<Value = HexBytes<32>> {
+    any::<[u8; 32]>().prop_map(HexBytes)
+}
+
+fn hex64() -> impl Strategy<Value = HexBytes<64>> {
+    (any::<[u8; 32]>(), any::<[u8; 32]>()).prop_map(|(a, b)| {
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(&a);
+        bytes[32..].copy_from_slice(&b);
+        HexBytes(bytes)
+    })
+}
+
+fn bytes() -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..64)
+}
+
+fn cipher() -> impl Strategy<Value = TlsCiphertext> {
+    #[derive(Serialize)]
+    struct SimplifiedCiphertext {
+        payload: Vec<u8>,
+        nonce: Vec<u8>,
+    }
+    (bytes(), any::<[u8; 12]>()).prop_map(|(payload, nonce)| {
+        let cipher = serde_cbor::to_vec(&SimplifiedCiphertext {
+            payload,
+            nonce: nonce.to_vec(),
+        })
+        .expect("Test failed");
+        serde_cbor::from_slice(&cipher).expect("Test failed")
+    })
+}
+
+fn ack() -> impl Strategy<Value = AckType> {
+    prop_oneof![
+        Just(AckType::Fail),
+        cipher().prop_map(AckType::Success),
+        (hex32(), cipher()).prop_map(|(confirm, cipher)| AckType::Confirmed { confirm, cipher }),
+    ]
+}
+
+fn version() -> impl Strategy<Value = HandshakeVersion> {
+    prop_oneof![Just(HandshakeVersion::V1), Just(HandshakeVersion::V2)]
+}
+
+fn lease() -> impl Strategy<Value = Lease> {
+    prop_oneof![
+        any::<u64>().prop_map(Lease::Height),
+        any::<u64>().prop_map(Lease::Duration),
+    ]
+}
+
+fn config() -> impl Strategy<Value = EnclaveConfig> {
+    let schedule = (any::<bool>(), any::<u64>(), any::<usize>(), any::<u64>()).prop_map(
+        |(uniform, live_window, backfill_keys, backfill_blocks)| ScheduleConfig {
+            policy: if uniform {
+                SchedulePolicy::Uniform
+            } else {
+                SchedulePolicy::LivePriority
+            },
+            live_window,
+            backfill_keys,
+            backfill_blocks,
+        },
+    );
+    let padding = prop_oneof![
+        Just(PaddingPolicy::None),
+        any::<usize>().prop_map(PaddingPolicy::Multiple),
+        any::<usize>().prop_map(PaddingPolicy::PowerOfTwo),
+    ];
+    (
+        schedule,
+        any::<(usize, usize, usize, usize)>(),
+        proptest::option::of(lease()),
+        padding,
+        any::<(u64, usize)>(),
+    )
+        .prop_map(
+            |(
+                schedule,
+                (min_subkeys, max_subkeys, max_gamma, max_param_sets),
+                default_lease,
+                padding,
+                (timeout, max_open),
+            )| {
+                EnclaveConfig {
+                    schedule,
+                    fp_rates: FalsePositiveBounds {
+                        min_subkeys,
+                        max_subkeys,
+                    },
+                    max_gamma,
+                    max_param_sets,
+                    default_lease,
+                    padding,
+                    sessions: SessionLimits { timeout, max_open },
+                }
+            },
+        )
+}
+
+fn index() -> impl Strategy<Value = Index> {
+    any::<(u64, u32)>().prop_map(|(height, tx)| Index { height, tx })
+}
+
+fn renewal() -> impl Strategy<Value = LeaseRenewal> {
+    (any::<String>(), any::<[u8; 12]>(), bytes()).prop_map(|(owner, nonce, payload)| LeaseRenewal {
+        owner,
+        nonce,
+        payload,
+    })
+}
+
+fn update() -> impl Strategy<Value = KeyUpdate> {
+    (any::<String>(), any::<[u8; 12]>(), bytes()).prop_map(|(owner, nonce, payload)| KeyUpdate {
+        owner,
+        nonce,
+        payload,
+    })
+}
+
+fn response() -> impl Strategy<Value = EncryptedResponse> {
+    (
+        any::<String>(),
+        any::<[u8; 12]>(),
+        bytes(),
+        any::<(u64, u64, u8)>(),
+    )
+        .prop_map(
+            |(owner, nonce, indices, (height, sequence, version))| EncryptedResponse {
+                owner,
+                nonce,
+                indices,
+                height,
+                sequence,
+                version,
+            },
+        )
+}
+
+fn checkpoint() -> impl Strategy<Value = Checkpoint> {
+    (any::<u64>(), hex32(), hex64()).prop_map(|(height, digest, signature)| Checkpoint {
+        height,
+        digest,
+        signature,
+    })
+}
+
+fn status() -> impl Strategy<Value = EnclaveStatus> {
+    (
+        any::<String>(),
+        any::<usize>(),
+        proptest::option::of(any::<(u64, u64, u64)>()),
+        any::<usize>(),
+        config(),
+    )
+        .prop_map(
+            |(version, registered_keys, synced_to, pending_sessions, config)| EnclaveStatus {
+                version,
+                registered_keys,
+                synced_to: synced_to.map(|(min, median, max)| SyncedTo { min, median, max }),
+                pending_sessions,
+                config,
+            },
+        )
+}
+
+fn msg_from_host() -> impl Strategy<Value = MsgFromHost> {
+    prop_oneof![
+        any::<String>().prop_map(MsgFromHost::Basic),
+        (any::<(u64, u64)>(), hex32(), version()).prop_map(|((session_id, nonce), pk, version)| {
+            MsgFromHost::RegisterKey {
+                session_id,
+                nonce,
+                pk,
+                version,
+            }
+        }),
+        hex64().prop_map(|user_data| MsgFromHost::RequestReport { user_data }),
+        any::<u64>().prop_map(MsgFromHost::Ping),
+        Just(MsgFromHost::RequestStatus),
+        Just(MsgFromHost::Resync),
+        (any::<u64>(), ack())
+            .prop_map(|(session_id, ack)| MsgFromHost::RATLSAck { session_id, ack }),
+        (config(), any::<u64>()).prop_map(|(config, now)| MsgFromHost::Configure { config, now }),
+        any::<(u64, u64)>()
+            .prop_map(|(synced_to, now)| MsgFromHost::RequiredBlocks { synced_to, now }),
+        (any::<u64>(), vec(index(), 0..8)).prop_map(|(synced_to, indices)| {
+            MsgFromHost::RequestedFlags {
+                synced_to,
+                flags: indices.into_iter().map(|ix| (ix, None)).collect(),
+            }
+        }),
+        renewal().prop_map(MsgFromHost::RenewLease),
+        update().prop_map(MsgFromHost::UpdateKey),
+        (any::<(u64, u64)>(), bytes()).prop_map(|((session_id, nonce), records)| {
+            MsgFromHost::TlsHello {
+                session_id,
+                nonce,
+                records,
+            }
+        }),
+        (any::<u64>(), bytes()).prop_map(|(session_id, records)| MsgFromHost::TlsRecords {
+            session_id,
+            records
+        }),
+        (any::<u64>(), cipher())
+            .prop_map(|(session_id, cipher)| MsgFromHost::SessionRequest { session_id, cipher }),
+        any::<u64>().prop_map(|session_id| MsgFromHost::CloseSession { session_id }),
+    ]
+}
+
+fn msg_to_host() -> impl Strategy<Value = MsgToHost> {
+    prop_oneof![
+        any::<String>().prop_map(MsgToHost::Basic),
+        any::<String>().prop_map(MsgToHost::Error),
+        any::<String>().prop_map(MsgToHost::ErrorForClient),
+        (
+            any::<u64>(),
+            bytes(),
+            hex32(),
+            proptest::option::of(hex32())
+        )
+            .prop_map(|(session_id, report, checkpoint_key, confirm)| {
+                MsgToHost::RATLS {
+                    session_id,
+                    report,
+                    checkpoint_key,
+                    confirm,
+                }
+            }),
+        bytes().prop_map(MsgToHost::Report),
+        Just(MsgToHost::KeyRegSuccess),
+        (vec(any::<u64>(), 0..8), vec(any::<String>(), 0..4))
+            .prop_map(|(heights, expired)| MsgToHost::BlockRequests { heights, expired }),
+        (vec(response(), 0..4), vec(checkpoint(), 0..4)).prop_map(|(results, checkpoints)| {
+            MsgToHost::FmdResults {
+                results,
+                checkpoints,
+            }
+        }),
+        Just(MsgToHost::Configured),
+        Just(MsgToHost::LeaseRenewed),
+        proptest::option::of(any::<String>()).prop_map(|stale| MsgToHost::KeyUpdated { stale }),
+        any::<u64>().prop_map(MsgToHost::Pong),
+        status().prop_map(MsgToHost::Status),
+        Just(MsgToHost::Resynced),
+        (any::<u64>(), bytes(), hex32()).prop_map(|(session_id, records, checkpoint_key)| {
+            MsgToHost::TlsHandshake {
+                session_id,
+                records,
+                checkpoint_key,
+            }
+        }),
+        (any::<u64>(), cipher())
+            .prop_map(|(session_id, cipher)| MsgToHost::SessionReply { session_id, cipher }),
+    ]
+}
+
+fn client_msg() -> impl Strategy<Value = ClientMsg> {
+    prop_oneof![
+        (any::<u64>(), hex32(), version())
+            .prop_map(|(nonce, pk, version)| { ClientMsg::RegisterKey { nonce, pk, version } }),
+        hex64().prop_map(|user_data| ClientMsg::RequestReport { user_data }),
+        ack().prop_map(ClientMsg::RATLSAck),
+        Just(ClientMsg::RequestUUID),
+        any::<String>().prop_map(|key_hash| ClientMsg::RequestIndices { key_hash }),
+        any::<(u64, u64)>().prop_map(|(from, to)| ClientMsg::RequestCheckpoints { from, to }),
+        renewal().prop_map(ClientMsg::RenewLease),
+        update().prop_map(ClientMsg::UpdateKey),
+        (any::<u64>(), bytes()).prop_map(|(nonce, records)| ClientMsg::TlsHello { nonce, records }),
+        bytes().prop_map(ClientMsg::TlsRecords),
+        cipher().prop_map(ClientMsg::SessionRequest),
+        Just(ClientMsg::CloseSession),
+    ]
+}
+
+fn server_msg() -> impl Strategy<Value = ServerMsg> {
+    prop_oneof![
+        (bytes(), hex32(), proptest::option::of(hex32())).prop_map(
+            |(report, checkpoint_key, confirm)| ServerMsg::RATLS {
+                report,
+                checkpoint_key,
+                confirm,
+            }
+        ),
+        any::<String>().prop_map(ServerMsg::Error),
+        Just(ServerMsg::KeyRegSuccess),
+        any::<String>().prop_map(ServerMsg::UUID),
+        response().prop_map(ServerMsg::IndicesResponse),
+        vec(checkpoint(), 0..4).prop_map(ServerMsg::Checkpoints),
+        Just(ServerMsg::LeaseRenewed),
+        Just(ServerMsg::KeyUpdated),
+        (bytes(), hex32()).prop_map(|(records, checkpoint_key)| ServerMsg::TlsHandshake {
+            records,
+            checkpoint_key
+        }),
+        cipher().prop_map(ServerMsg::SessionReply),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn test_host_request_round_trip(id: u64, msg in msg_from_host()) {
+        round_trip(&HostRequest { id, msg })?;
+    }
+
+    #[test]
+    fn test_enclave_reply_round_trip(id: Option<u64>, msg in msg_to_host()) {
+        round_trip(&EnclaveReply { id, msg })?;
+    }
+
+    #[test]
+    fn test_client_msg_round_trip(msg in client_msg()) {
+        round_trip(&msg)?;
+    }
+
+    #[test]
+    fn test_server_msg_round_trip(msg in server_msg()) {
+        round_trip(&msg)?;
+    }
+
+    /// Decoding arbitrary bytes returns errors rather than panicking
+    #[test]
+    fn test_decode_arbitrary_bytes(bytes in vec(any::<u8>(), 0..256)) {
+        let mut channel = Buffer(bytes.iter().copied().collect());
+        while let Err(e) | Ok(Err(e)) = channel
+            .get_frame()
+            .map(|frame| frame.deserialize::<HostRequest>().map(|_| ()))
+        {
+            if e.is_disconnect() {
+                break;
+            }
+        }
+        let _ = serde_cbor::from_slice::<HostRequest>(&bytes);
+        let _ = serde_cbor::from_slice::<ClientMsg>(&bytes);
+        let _ = crate::IndexList::try_from_bytes(&bytes);
+    }
+}
+
+/// Handshakes from clients predating versioning are version 1
+#[test]
+fn test_legacy_handshake() {
+    #[derive(Serialize)]
+    enum LegacyMsg {
+        RegisterKey { nonce: u64, pk: HexBytes<32> },
+    }
+    let legacy = serde_cbor::to_vec(&LegacyMsg::RegisterKey {
+        nonce: 7,
+        pk: HexBytes([1; 32]),
+    })
+    .expect("Test failed");
+    let ClientMsg::RegisterKey { nonce, version, .. } =
+        serde_cbor::from_slice(&legacy).expect("Test failed")
+    else {
+        panic!("Test failed");
+    };
+    assert_eq!(nonce, 7);
+    assert_eq!(version, HandshakeVersion::V1);
+}
+
+/// A ciphertext whose nonce has the wrong length is rejected instead of
+/// panicking when converted to a ChaCha20 nonce
+#[test]
+fn test_malformed_nonce() {
+    #[derive(Serialize)]
+    struct SimplifiedCiphertext {
+        payload: Vec<u8>,
+        nonce: Vec<u8>,
+    }
+    for len in [0, 11, 13] {
+        let cipher = serde_cbor::to_vec(&SimplifiedCiphertext {
+            payload: vec![1, 2, 3],
+            nonce: vec![0; len],
+        })
+        .expect("Test failed");
+        assert!(serde_cbor::from_slice::<crate::ratls::TlsCiphertext>(&cipher).is_err());
+    }
+}
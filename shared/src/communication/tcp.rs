@@ -1,79 +1,75 @@
 //! Communication primitives for talking with hosts
 
-use std::io::ErrorKind;
+use std::io;
 use std::io::prelude::*;
+use std::io::{BufReader, ErrorKind};
 use std::net::{TcpListener, TcpStream};
-use std::prelude::rust_2024::{String, Vec};
-use std::{io, vec};
+use std::prelude::rust_2024::String;
 
-use crate::ReadWriteByte;
 use crate::tee::EnclaveComm;
+use crate::{ENCLAVE_MAX_FRAME_SIZE, MsgError, ReadWriteByte};
 use once_cell::sync::OnceCell;
 
 pub const DEFAULT_ENCLAVE_ADDRESS: &str = "0.0.0.0:12345";
 
+/// The capacity of the buffer bytes are read into from the stream
+const READ_BUF_SIZE: usize = 1 << 16;
+
 /// The TCP address for the host-enclave channel
 pub static ENCLAVE_ADDRESS: OnceCell<String> = OnceCell::new();
 
-/// A TCP stream connected with the host
+/// A TCP stream connected with the host. Reads are buffered, so
+/// bytes are read from the stream in large chunks.
 /// **NOT THREAD SAFE**
 pub struct Tcp {
-    pub raw: TcpStream,
-    buffered: Vec<u8>,
+    reader: BufReader<TcpStream>,
 }
 
 impl Tcp {
     /// Create a new connection from a stream
     pub fn new(stream: TcpStream) -> Self {
         Self {
-            raw: stream,
-            buffered: vec![],
+            reader: BufReader::with_capacity(READ_BUF_SIZE, stream),
         }
     }
+
     /// Listen for a connection request from the host. Once
     /// received, return the stream.
     pub fn connect(url: &str) -> io::Result<Self> {
         let listener = TcpListener::bind(url)?;
         loop {
             if let Some(Ok(stream)) = listener.incoming().next() {
-                break Ok(Self {
-                    raw: stream,
-                    buffered: Default::default(),
-                });
+                break Ok(Self::new(stream));
             }
         }
     }
-
-    /// Read data from the stream into an internal buffer.
-    /// The buffer is a stack, so the bytes are stored in
-    /// reverse order that they are received.
-    fn buffered_read(&mut self) -> io::Result<()> {
-        let mut buffered = vec![0; 10];
-        let len = self.raw.read(&mut buffered)?;
-        buffered.truncate(len);
-        self.buffered = buffered;
-        Ok(())
-    }
 }
 
 impl ReadWriteByte for Tcp {
-    fn read_byte(&mut self) -> u8 {
-        // block until data is read into
-        // internal buffer
-        while self.buffered.is_empty() {
-            match self.buffered_read() {
-                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
-                Ok(()) => {}
-                Err(e) => panic!("{e}"),
+    const MAX_FRAME_SIZE: usize = ENCLAVE_MAX_FRAME_SIZE;
+
+    fn read_byte(&mut self) -> Result<u8, MsgError> {
+        // blocks until data is read into the internal
+        // buffer if it is empty
+        loop {
+            match self.reader.fill_buf() {
+                Ok([]) => return Err(MsgError::Disconnected),
+                Ok(buf) => {
+                    let byte = buf[0];
+                    self.reader.consume(1);
+                    return Ok(byte);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(MsgError::Io(e)),
             }
-            core::hint::spin_loop();
         }
-        self.buffered.remove(0)
     }
 
-    fn write_bytes(&mut self, buf: &[u8]) {
-        self.raw.write_all(buf).unwrap();
-        self.raw.flush().unwrap();
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<(), MsgError> {
+        let stream = self.reader.get_mut();
+        stream.write_all(buf)?;
+        stream.flush()?;
+        Ok(())
     }
 }
 
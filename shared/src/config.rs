@@ -0,0 +1,439 @@
+//! Runtime parameters the host passes to the enclave. These govern
+//! how the enclave apportions its work, but never anything that would
+//! let the host learn secret data.
+
+use alloc::collections::BTreeSet;
+use core::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::lease::Lease;
+use crate::ratls::{FmdKeyRegistration, FmdParams};
+
+/// How many blocks behind the host's watermark a key may be
+/// and still be considered live.
+const LIVE_WINDOW: u64 = 5;
+/// Maximum number of catching-up keys processed per round.
+const BACKFILL_KEYS: usize = 8;
+/// Maximum number of blocks a catching-up key advances per round.
+const BACKFILL_BLOCKS: u64 = 50;
+/// The largest gamma of registered keys by default
+const MAX_GAMMA: usize = 64;
+/// The most distinct FMD parameter sets of registered keys by default
+const MAX_PARAM_SETS: usize = 16;
+/// The fewest subkeys a detection key may have by default
+const MIN_SUBKEYS: usize = 1;
+/// The most subkeys a detection key may have by default
+const MAX_SUBKEYS: usize = 16;
+/// The fewest indices a padded response holds by default
+const MIN_PADDED_INDICES: usize = 16;
+/// How long, in seconds, an RA-TLS handshake may wait on its client by default
+const SESSION_TIMEOUT: u64 = 60;
+/// The most RA-TLS sessions that may be open at once by default
+const MAX_SESSIONS: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Invalid FMD parameters: gamma = {0}, threshold = {1}")]
+    InvalidParams(usize, usize),
+    #[error("Could not determine the subkeys of the detection key")]
+    MalformedKey,
+    #[error("Detection key has subkeys outside of the range of gamma = {0}")]
+    SubkeyOutOfRange(usize),
+    #[error(
+        "Detection key has a false-positive rate of 2^-{0}, the accepted range is 2^-{1} to 2^-{2}"
+    )]
+    FalsePositiveRate(usize, usize, usize),
+    #[error("Keys with {0} distinct FMD parameter sets are already registered")]
+    TooManyParamSets(usize),
+}
+
+/// Parameters the enclave needs from its host to do its job.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnclaveConfig {
+    /// How FMD work is scheduled between registered keys
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    /// The false-positive rates of detection keys this provider accepts
+    #[serde(default)]
+    pub fp_rates: FalsePositiveBounds,
+    /// The largest gamma of keys this provider accepts. The cost of
+    /// detection grows with gamma, so it must be bounded.
+    #[serde(default = "max_gamma")]
+    pub max_gamma: usize,
+    /// The most distinct FMD parameter sets of registered keys. Each
+    /// needs an FMD scheme of its own, so they must be bounded.
+    #[serde(default = "max_param_sets")]
+    pub max_param_sets: usize,
+    /// The lease of registrations that do not specify one. If not
+    /// given, such registrations never expire.
+    #[serde(default)]
+    pub default_lease: Option<Lease>,
+    /// How encrypted index sets are padded to hide their size from the host
+    #[serde(default)]
+    pub padding: PaddingPolicy,
+    /// Limits on pending RA-TLS handshakes
+    #[serde(default)]
+    pub sessions: SessionLimits,
+}
+
+impl Default for EnclaveConfig {
+    fn default() -> Self {
+        Self {
+            schedule: ScheduleConfig::default(),
+            fp_rates: FalsePositiveBounds::default(),
+            max_gamma: MAX_GAMMA,
+            max_param_sets: MAX_PARAM_SETS,
+            default_lease: None,
+            padding: PaddingPolicy::default(),
+            sessions: SessionLimits::default(),
+        }
+    }
+}
+
+fn max_gamma() -> usize {
+    MAX_GAMMA
+}
+
+fn max_param_sets() -> usize {
+    MAX_PARAM_SETS
+}
+
+impl EnclaveConfig {
+    /// Check that the FMD parameters of a registration are well-formed and
+    /// acceptable to this provider.
+    pub fn validate(&self, key: &FmdKeyRegistration) -> Result<(), ConfigError> {
+        let params = key.params;
+        // A threshold of zero gives every subkey the same value
+        if params.gamma == 0
+            || params.gamma > self.max_gamma
+            || params.threshold == 0
+            || params.threshold > params.gamma
+        {
+            return Err(ConfigError::InvalidParams(params.gamma, params.threshold));
+        }
+        let indices = key.subkey_indices().ok_or(ConfigError::MalformedKey)?;
+        if indices.iter().any(|ix| *ix >= params.gamma) {
+            return Err(ConfigError::SubkeyOutOfRange(params.gamma));
+        }
+        let FalsePositiveBounds {
+            min_subkeys,
+            max_subkeys,
+        } = self.fp_rates;
+        if !(min_subkeys..=max_subkeys).contains(&indices.len()) {
+            return Err(ConfigError::FalsePositiveRate(
+                indices.len(),
+                max_subkeys,
+                min_subkeys,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check that a key with the given parameters may be registered
+    /// alongside keys with the `registered` parameters without exceeding
+    /// the number of distinct parameter sets this provider accepts.
+    pub fn validate_param_sets(
+        &self,
+        params: FmdParams,
+        registered: impl IntoIterator<Item = FmdParams>,
+    ) -> Result<(), ConfigError> {
+        let sets: BTreeSet<_> = registered.into_iter().collect();
+        if !sets.contains(&params) && sets.len() >= self.max_param_sets {
+            return Err(ConfigError::TooManyParamSets(sets.len()));
+        }
+        Ok(())
+    }
+}
+
+/// Bounds on the false-positive rate of registered detection keys. A
+/// detection key with `n` subkeys has a false-positive rate of 2^-n, so
+/// the bounds are given in terms of subkeys.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FalsePositiveBounds {
+    /// Keys with fewer subkeys have too high a false-positive rate, making
+    /// their results needlessly large.
+    pub min_subkeys: usize,
+    /// Keys with more subkeys have too low a false-positive rate to
+    /// provide their owner any cover traffic.
+    pub max_subkeys: usize,
+}
+
+impl Default for FalsePositiveBounds {
+    fn default() -> Self {
+        Self {
+            min_subkeys: MIN_SUBKEYS,
+            max_subkeys: MAX_SUBKEYS,
+        }
+    }
+}
+
+/// Limits on the RA-TLS sessions the enclave keeps open while waiting
+/// on clients. Sessions are interleaved with FMD, so these only bound
+/// the memory a slow or malicious client can tie up.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionLimits {
+    /// Seconds after which an idle session is discarded
+    pub timeout: u64,
+    /// The most sessions that may be open at once
+    pub max_open: usize,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self {
+            timeout: SESSION_TIMEOUT,
+            max_open: MAX_SESSIONS,
+        }
+    }
+}
+
+/// The policy for padding encrypted index sets. Without padding, the
+/// length of a ciphertext reveals to the host exactly how many detections
+/// its owner has and when they occur.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PaddingPolicy {
+    /// Encrypt exactly the indices found
+    None,
+    /// Pad to the next multiple of this many indices
+    Multiple(usize),
+    /// Pad to the next power of two number of indices, but at least
+    /// this many
+    PowerOfTwo(usize),
+}
+
+impl Default for PaddingPolicy {
+    fn default() -> Self {
+        Self::PowerOfTwo(MIN_PADDED_INDICES)
+    }
+}
+
+impl PaddingPolicy {
+    /// Check that the policy pads to a positive number of indices
+    pub fn is_valid(&self) -> bool {
+        !matches!(self, Self::Multiple(0))
+    }
+
+    /// The number of indices a set of `count` indices is padded to.
+    /// The policy must be valid.
+    pub fn padded_count(&self, count: usize) -> usize {
+        match *self {
+            Self::None => count,
+            Self::Multiple(bucket) => count.div_ceil(bucket) * bucket,
+            Self::PowerOfTwo(min) => count.max(min).next_power_of_two(),
+        }
+    }
+}
+
+impl FromStr for PaddingPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const EXPECTED: &str =
+            "Unknown padding policy, expected one of [ none, multiple:<n>, power-of-two[:<min>] ]";
+        match s.split_once(':') {
+            None if s == "none" => Ok(Self::None),
+            None if s == "power-of-two" => Ok(Self::PowerOfTwo(MIN_PADDED_INDICES)),
+            Some(("multiple", n)) => match n.parse() {
+                Ok(0) => Err("Padding to a multiple of 0 indices is not possible"),
+                Ok(n) => Ok(Self::Multiple(n)),
+                Err(_) => Err(EXPECTED),
+            },
+            Some(("power-of-two", min)) => min.parse().map(Self::PowerOfTwo).or(Err(EXPECTED)),
+            _ => Err(EXPECTED),
+        }
+    }
+}
+
+/// The policy for choosing which keys are processed in each
+/// round of FMD.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SchedulePolicy {
+    /// Every key behind the host's watermark advances one block
+    /// per round.
+    Uniform,
+    /// Keys at the tip are brought up to the watermark every round.
+    /// Keys still catching up share a bounded budget, served round-robin.
+    #[default]
+    LivePriority,
+}
+
+impl FromStr for SchedulePolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(Self::Uniform),
+            "live-priority" => Ok(Self::LivePriority),
+            _ => Err("Unknown schedule policy, expected one of [ uniform, live-priority ]"),
+        }
+    }
+}
+
+/// The scheduling policy along with its budgets.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleConfig {
+    pub policy: SchedulePolicy,
+    /// Keys synced to within this many blocks of the host's
+    /// watermark are considered live.
+    pub live_window: u64,
+    /// The maximum number of catching-up keys processed per round
+    pub backfill_keys: usize,
+    /// The maximum number of blocks a catching-up key advances per round
+    pub backfill_blocks: u64,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            policy: SchedulePolicy::default(),
+            live_window: LIVE_WINDOW,
+            backfill_keys: BACKFILL_KEYS,
+            backfill_blocks: BACKFILL_BLOCKS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fmd::fmd2_compact::{CompactSecretKey, MultiFmd2CompactScheme};
+    use fmd::{KeyExpansion, MultiFmdScheme};
+
+    use super::*;
+
+    fn registration(params: FmdParams, subkeys: usize) -> FmdKeyRegistration {
+        let csk = CompactSecretKey::derive_from_xof_stream(params.threshold, |buf| buf.fill(7));
+        let mut scheme = MultiFmd2CompactScheme::new(params.gamma, params.threshold);
+        let (sk, _) = scheme.expand_keypair(&csk, &csk.master_public_key());
+        let fmd_key = scheme
+            .multi_extract(&sk, 1, 1, subkeys, subkeys)
+            .expect("Test failed")
+            .remove(0);
+        FmdKeyRegistration {
+            fmd_key,
+            params,
+            enc_key: chacha20poly1305::Key::from([0u8; 32]).into(),
+            birthday: None,
+            lease: None,
+            replace: false,
+        }
+    }
+
+    /// Test that registrations are checked against the configured
+    /// false-positive rates and their own parameters.
+    #[test]
+    fn test_validate_registration() {
+        let config = EnclaveConfig {
+            fp_rates: FalsePositiveBounds {
+                min_subkeys: 2,
+                max_subkeys: 4,
+            },
+            ..Default::default()
+        };
+        let params = FmdParams::default();
+        let key = registration(params, 3);
+        assert_eq!(key.subkey_indices().expect("Test failed"), [0, 1, 2]);
+        assert!(config.validate(&key).is_ok());
+        assert!(matches!(
+            config.validate(&registration(params, 1)),
+            Err(ConfigError::FalsePositiveRate(1, 4, 2))
+        ));
+        assert!(matches!(
+            config.validate(&registration(params, 5)),
+            Err(ConfigError::FalsePositiveRate(5, 4, 2))
+        ));
+
+        let mut key = registration(params, 3);
+        key.params.gamma = 2;
+        assert!(matches!(
+            config.validate(&key),
+            Err(ConfigError::SubkeyOutOfRange(2))
+        ));
+        key.params.gamma = 0;
+        assert!(matches!(
+            config.validate(&key),
+            Err(ConfigError::InvalidParams(0, 1))
+        ));
+        key.params.gamma = params.gamma;
+        key.params.threshold = 0;
+        assert!(matches!(
+            config.validate(&key),
+            Err(ConfigError::InvalidParams(_, 0))
+        ));
+    }
+
+    /// Test that keys with a gamma above the configured bound are
+    /// rejected before any scheme is built for them.
+    #[test]
+    fn test_validate_max_gamma() {
+        let config = EnclaveConfig {
+            max_gamma: 24,
+            ..Default::default()
+        };
+        let mut key = registration(FmdParams::default(), 3);
+        key.params.gamma = 24;
+        assert!(config.validate(&key).is_ok());
+        key.params.gamma = 25;
+        assert!(matches!(
+            config.validate(&key),
+            Err(ConfigError::InvalidParams(25, 1))
+        ));
+        key.params.gamma = usize::MAX;
+        assert!(matches!(
+            config.validate(&key),
+            Err(ConfigError::InvalidParams(usize::MAX, 1))
+        ));
+    }
+
+    /// Test that keys with new parameter sets are only accepted until
+    /// the configured number of distinct sets is reached.
+    #[test]
+    fn test_validate_param_sets() {
+        let config = EnclaveConfig {
+            max_param_sets: 2,
+            ..Default::default()
+        };
+        let params = |gamma| FmdParams {
+            gamma,
+            threshold: 1,
+        };
+        assert!(config.validate_param_sets(params(1), []).is_ok());
+        assert!(
+            config
+                .validate_param_sets(params(3), [params(1), params(2), params(1)])
+                .is_err()
+        );
+        assert!(matches!(
+            config.validate_param_sets(params(3), [params(1), params(2)]),
+            Err(ConfigError::TooManyParamSets(2))
+        ));
+        assert!(
+            config
+                .validate_param_sets(params(2), [params(1), params(2)])
+                .is_ok()
+        );
+        assert!(config.validate_param_sets(params(3), [params(1)]).is_ok());
+    }
+
+    #[test]
+    fn test_padding_policy() {
+        assert_eq!(PaddingPolicy::None.padded_count(5), 5);
+        assert_eq!(PaddingPolicy::Multiple(4).padded_count(0), 0);
+        assert_eq!(PaddingPolicy::Multiple(4).padded_count(5), 8);
+        assert_eq!(PaddingPolicy::PowerOfTwo(16).padded_count(0), 16);
+        assert_eq!(PaddingPolicy::PowerOfTwo(16).padded_count(17), 32);
+        assert_eq!(
+            PaddingPolicy::from_str("multiple:4"),
+            Ok(PaddingPolicy::Multiple(4))
+        );
+        assert_eq!(
+            PaddingPolicy::from_str("power-of-two"),
+            Ok(PaddingPolicy::PowerOfTwo(MIN_PADDED_INDICES))
+        );
+        assert!(PaddingPolicy::from_str("multiple").is_err());
+        assert!(PaddingPolicy::from_str("multiple:0").is_err());
+        assert!(!PaddingPolicy::Multiple(0).is_valid());
+        assert!(PaddingPolicy::PowerOfTwo(0).is_valid());
+    }
+}
@@ -1,12 +1,18 @@
 //! Shared types to be stored in the host databases
 
+use alloc::vec::Vec;
+
 use borsh::{BorshDeserialize, BorshSerialize};
-use chacha20poly1305::Key;
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{AeadCore, ChaCha20Poly1305, Key, KeyInit, Nonce};
 use core::fmt::Formatter;
-use serde::de::{Error, Visitor};
+use rand_core::{CryptoRng, RngCore};
+use serde::de::{Error, SeqAccess, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use zeroize::Zeroize;
 
+use crate::config::PaddingPolicy;
+
 /// A wrapper around a ChaCha key
 ///
 /// Used to encrypted enclave responses for users
@@ -22,6 +28,37 @@ impl EncKey {
         let hash: [u8; 32] = hasher.finalize().into();
         hex::encode(hash)
     }
+
+    /// Encrypt and authenticate a message for the holder of this key.
+    /// Returns the nonce along with the ciphertext.
+    pub fn seal<R: CryptoRng + RngCore>(
+        &self,
+        msg: &[u8],
+        aad: &[u8],
+        rng: R,
+    ) -> ([u8; 12], Vec<u8>) {
+        let cipher = ChaCha20Poly1305::new(&self.0);
+        let nonce = ChaCha20Poly1305::generate_nonce(rng);
+        let payload = cipher
+            .encrypt(&nonce, Payload { msg, aad })
+            .expect("Encryption should not fail");
+        (nonce.into(), payload)
+    }
+
+    /// Decrypt a message produced by [`Self::seal`]. Returns `None`
+    /// if it was not sealed with this key and associated data.
+    pub fn open(&self, nonce: &[u8; 12], payload: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        let cipher = ChaCha20Poly1305::new(&self.0);
+        cipher
+            .decrypt(&Nonce::from(*nonce), Payload { msg: payload, aad })
+            .ok()
+    }
+}
+
+/// An encryption key of repeated `byte`s, for tests
+#[cfg(any(test, feature = "testing"))]
+pub fn test_enc_key(byte: u8) -> EncKey {
+    EncKey(Key::from([byte; 32]))
 }
 
 impl From<Key> for EncKey {
@@ -51,7 +88,7 @@ impl<'de> Deserialize<'de> for EncKey {
         D: Deserializer<'de>,
     {
         struct EncKeyVisitor;
-        impl Visitor<'_> for EncKeyVisitor {
+        impl<'de> Visitor<'de> for EncKeyVisitor {
             type Value = EncKey;
 
             fn expecting(&self, formatter: &mut Formatter) -> core::fmt::Result {
@@ -67,6 +104,22 @@ impl<'de> Deserialize<'de> for EncKey {
                     .map_err(|_| Error::custom("Unexpected length of encryption key"))?;
                 Ok(EncKey(*Key::from_slice(&bytes)))
             }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut bytes = [0u8; 32];
+                for (ix, byte) in bytes.iter_mut().enumerate() {
+                    *byte = seq
+                        .next_element()?
+                        .ok_or_else(|| Error::invalid_length(ix, &self))?;
+                }
+                if seq.next_element::<u8>()?.is_some() {
+                    return Err(Error::custom("Unexpected length of encryption key"));
+                }
+                Ok(EncKey(*Key::from_slice(&bytes)))
+            }
         }
 
         deserializer.deserialize_bytes(EncKeyVisitor)
@@ -144,19 +197,36 @@ impl Index {
 pub struct IndexList(alloc::vec::Vec<Index>);
 
 impl IndexList {
-    /// Try to parse bytes into a list of indices
+    /// Encode indices as bytes, padded according to the given policy.
+    ///
+    /// The encoding is the number of indices as a little-endian `u32`,
+    /// followed by the indices and then zeroes up to the padded length.
+    pub fn to_padded_bytes(indices: &[Index], policy: &PaddingPolicy) -> Vec<u8> {
+        let padded = policy.padded_count(indices.len());
+        let mut bytes = Vec::with_capacity(4 + 12 * padded);
+        bytes.extend_from_slice(&(indices.len() as u32).to_le_bytes());
+        bytes.extend(indices.iter().flat_map(|ix| ix.as_bytes()));
+        bytes.resize(4 + 12 * padded, 0);
+        bytes
+    }
+
+    /// Try to parse bytes produced by [`Self::to_padded_bytes`] into a
+    /// list of indices
     pub fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
-        if 12 * (bytes.len() / 12) != bytes.len() {
+        let (len, rest) = bytes.split_first_chunk::<4>()?;
+        let len = u32::from_le_bytes(*len) as usize;
+        if rest.len() % 12 != 0 || rest.len() / 12 < len {
             return None;
         }
-        let len = bytes.len() / 12;
-        let indices: alloc::vec::Vec<_> =
-            bytes.chunks(12).filter_map(Index::try_from_bytes).collect();
-        if indices.len() != len {
-            None
-        } else {
-            Some(Self(indices))
+        let (indices, padding) = rest.split_at(12 * len);
+        if padding.iter().any(|b| *b != 0) {
+            return None;
         }
+        let indices: Vec<_> = indices
+            .chunks(12)
+            .filter_map(Index::try_from_bytes)
+            .collect();
+        Some(Self(indices))
     }
 
     /// Given two index sets, produce a new index set
@@ -166,6 +236,10 @@ impl IndexList {
     /// the indices up to the common block height is kept along with all
     /// indices of `self` with block height greater than `other`'s maximum
     /// block height.
+    ///
+    /// The block heights are inferred from the last index of each set,
+    /// which understates how far a set is synced if it has no recent
+    /// indices. Use [`CombinedIndices`] if the synced heights are known.
     pub fn combine(&mut self, mut other: Self) {
         if self.0.is_empty() {
             *self = other;
@@ -243,6 +317,92 @@ impl FromIterator<Index> for IndexList {
     }
 }
 
+/// An index set along with the block height FMD was performed up to
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct SyncedIndices {
+    pub indices: IndexList,
+    pub height: u64,
+}
+
+/// The index sets of multiple services for the same key, combined
+/// according to how far each service has synced.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct CombinedIndices {
+    /// The intersection of all index sets up to [`Self::height`]
+    pub indices: IndexList,
+    /// The minimum height the services have synced to
+    pub height: u64,
+    /// The indices of each service above [`Self::height`], in the
+    /// order the services were given
+    pub tails: Vec<SyncedIndices>,
+}
+
+impl CombinedIndices {
+    /// Combine the index sets of several services. Up to the minimum
+    /// synced height all services have performed FMD, so only the
+    /// intersection of their indices is kept.
+    pub fn combine(sets: impl IntoIterator<Item = SyncedIndices>) -> Self {
+        let mut sets: Vec<_> = sets
+            .into_iter()
+            .map(|mut set| {
+                set.indices.0.sort();
+                set.indices.0.dedup();
+                set
+            })
+            .collect();
+        let Some(height) = sets.iter().map(|set| set.height).min() else {
+            return Self::default();
+        };
+        let mut indices = IndexList::default();
+        if let Some((first, rest)) = sets.split_first() {
+            indices = first
+                .indices
+                .iter()
+                .filter(|ix| ix.height <= height)
+                .filter(|ix| rest.iter().all(|set| set.indices.contains(ix)))
+                .copied()
+                .collect();
+        }
+        for set in sets.iter_mut() {
+            set.indices.retain(|ix| ix.height > height);
+        }
+        Self {
+            indices,
+            height,
+            tails: sets,
+        }
+    }
+
+    /// The maximum height any service has synced to
+    pub fn synced_to(&self) -> u64 {
+        self.tails
+            .iter()
+            .map(|set| set.height)
+            .max()
+            .unwrap_or(self.height)
+    }
+
+    /// The indices a client should fetch and trial-decrypt. Above
+    /// [`Self::height`], an index is kept if every service that has
+    /// synced to its height flagged it.
+    pub fn candidates(&self) -> IndexList {
+        let mut candidates = self.indices.clone();
+        for (ix, set) in self.tails.iter().enumerate() {
+            candidates.0.extend(set.indices.iter().filter(|index| {
+                self.tails.iter().enumerate().all(|(other_ix, other)| {
+                    other_ix == ix || other.height < index.height || other.indices.contains(index)
+                })
+            }));
+        }
+        candidates.0.sort();
+        candidates.0.dedup();
+        candidates
+    }
+}
+
+/// The current format of [`EncryptedResponse`]
+pub const RESPONSE_VERSION: u8 = 1;
+
 /// The response from the enclave for performing
 /// FMD for a particular uses
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -256,6 +416,27 @@ pub struct EncryptedResponse {
     pub indices: alloc::vec::Vec<u8>,
     /// The last height FMD was performed at
     pub height: u64,
+    /// Increases with every response produced for the owner so
+    /// that clients can detect old responses being replayed.
+    #[serde(default)]
+    pub sequence: u64,
+    /// The format of the response
+    #[serde(default)]
+    pub version: u8,
+}
+
+impl EncryptedResponse {
+    /// The associated data the indices are encrypted with. This binds
+    /// the plaintext fields to the ciphertext so that the host cannot
+    /// change them.
+    pub fn associated_data(&self) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(1 + self.owner.len() + 16);
+        aad.push(self.version);
+        aad.extend_from_slice(self.owner.as_bytes());
+        aad.extend_from_slice(&self.height.to_le_bytes());
+        aad.extend_from_slice(&self.sequence.to_le_bytes());
+        aad
+    }
 }
 
 #[cfg(test)]
@@ -293,4 +474,94 @@ mod tests {
         third.combine(IndexList::default());
         assert_eq!(third, a);
     }
+
+    /// Test that index sets are only intersected up to the height
+    /// each service has synced to, even without recent indices.
+    #[test]
+    fn test_combined_indices() {
+        let a = SyncedIndices {
+            indices: IndexList(Vec::from([
+                Index { height: 1, tx: 0 },
+                Index { height: 2, tx: 0 },
+                Index { height: 6, tx: 0 },
+                Index { height: 9, tx: 1 },
+            ])),
+            height: 10,
+        };
+        let b = SyncedIndices {
+            indices: IndexList(Vec::from([
+                Index { height: 1, tx: 0 },
+                Index { height: 3, tx: 0 },
+            ])),
+            height: 5,
+        };
+        let c = SyncedIndices {
+            indices: IndexList(Vec::from([
+                Index { height: 1, tx: 0 },
+                Index { height: 2, tx: 0 },
+                Index { height: 6, tx: 0 },
+                Index { height: 7, tx: 2 },
+            ])),
+            height: 8,
+        };
+        let combined = CombinedIndices::combine([a.clone(), b.clone(), c]);
+        assert_eq!(
+            combined.indices,
+            IndexList(Vec::from([Index { height: 1, tx: 0 }]))
+        );
+        assert_eq!(combined.height, 5);
+        assert_eq!(combined.synced_to(), 10);
+        assert_eq!(
+            combined.tails[0].indices,
+            IndexList(Vec::from([
+                Index { height: 6, tx: 0 },
+                Index { height: 9, tx: 1 },
+            ]))
+        );
+        assert_eq!(combined.tails[1].indices, IndexList::default());
+        assert_eq!(combined.tails[2].height, 8);
+        assert_eq!(
+            combined.candidates(),
+            IndexList(Vec::from([
+                Index { height: 1, tx: 0 },
+                Index { height: 6, tx: 0 },
+                Index { height: 9, tx: 1 },
+            ]))
+        );
+
+        // `IndexList::combine` would wrongly take `b` to be synced to 3
+        let combined = CombinedIndices::combine([a, b]);
+        assert!(!combined.indices.contains(&Index { height: 2, tx: 0 }));
+        assert!(combined.candidates().contains(&Index { height: 6, tx: 0 }));
+        assert_eq!(CombinedIndices::combine([]), CombinedIndices::default());
+    }
+
+    /// Test that padded index sets hide their size and parse back
+    /// to the original indices.
+    #[test]
+    fn test_padded_indices() {
+        let policy = PaddingPolicy::PowerOfTwo(4);
+        let one = [Index { height: 1, tx: 2 }];
+        let three = [
+            Index { height: 1, tx: 2 },
+            Index { height: 3, tx: 0 },
+            Index { height: 5, tx: 1 },
+        ];
+        let one_bytes = IndexList::to_padded_bytes(&one, &policy);
+        let three_bytes = IndexList::to_padded_bytes(&three, &policy);
+        assert_eq!(one_bytes.len(), three_bytes.len());
+        assert_eq!(
+            IndexList::try_from_bytes(&one_bytes),
+            Some(IndexList(Vec::from(one)))
+        );
+        assert_eq!(
+            IndexList::try_from_bytes(&three_bytes),
+            Some(IndexList(Vec::from(three)))
+        );
+
+        let mut tampered = three_bytes.clone();
+        *tampered.last_mut().unwrap() = 1;
+        assert_eq!(IndexList::try_from_bytes(&tampered), None);
+        assert_eq!(IndexList::try_from_bytes(&three_bytes[..20]), None);
+    }
 }
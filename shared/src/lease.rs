@@ -0,0 +1,121 @@
+//! Leases bound how long a provider performs FMD for a registered key.
+//! Clients renew them with a small message authenticated by the
+//! encryption key they shared with the enclave at registration, so no
+//! new RA-TLS handshake is needed.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use rand_core::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use zeroize::Zeroize;
+
+use crate::db::EncKey;
+
+/// Associated data separating renewals from anything else encrypted
+/// under a user's encryption key.
+const RENEWAL_AAD: &[u8] = b"Kassandra lease renewal";
+
+#[derive(Error, Debug)]
+pub enum LeaseError {
+    #[error("Lease renewal is not for the given key")]
+    WrongOwner,
+    #[error("Could not authenticate lease renewal")]
+    Decryption,
+    #[error("Failed to deserialize lease renewal with: {0}")]
+    Deserialize(serde_cbor::Error),
+}
+
+/// How long a registration remains active
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Zeroize)]
+pub enum Lease {
+    /// Expires once FMD has been performed up to this block height
+    Height(u64),
+    /// Expires this many seconds after it was granted
+    Duration(u64),
+}
+
+/// The authenticated contents of a lease renewal
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Renewal {
+    /// The new lease, replacing the current one
+    pub lease: Lease,
+    /// Must increase with every renewal or key update so that old
+    /// renewals cannot be replayed.
+    pub sequence: u64,
+}
+
+/// A lease renewal as sent over the wire
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaseRenewal {
+    /// Hash of the encryption key of the registration to renew
+    pub owner: String,
+    pub nonce: [u8; 12],
+    /// The encrypted [`Renewal`]
+    pub payload: Vec<u8>,
+}
+
+impl LeaseRenewal {
+    /// Authenticate a renewal with the registration's encryption key
+    pub fn seal<R: CryptoRng + RngCore>(renewal: &Renewal, enc_key: &EncKey, rng: R) -> Self {
+        let msg = serde_cbor::to_vec(renewal).unwrap();
+        let (nonce, payload) = enc_key.seal(&msg, RENEWAL_AAD, rng);
+        Self {
+            owner: enc_key.hash(),
+            nonce,
+            payload,
+        }
+    }
+
+    /// Check that a renewal was produced by the holder of the
+    /// encryption key and return its contents.
+    pub fn open(&self, enc_key: &EncKey) -> Result<Renewal, LeaseError> {
+        if self.owner != enc_key.hash() {
+            return Err(LeaseError::WrongOwner);
+        }
+        let msg = enc_key
+            .open(&self.nonce, &self.payload, RENEWAL_AAD)
+            .ok_or(LeaseError::Decryption)?;
+        serde_cbor::from_slice(&msg).map_err(LeaseError::Deserialize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::OsRng;
+
+    use super::*;
+    use crate::db::test_enc_key;
+
+    /// Test that renewals only open with the key they were sealed with,
+    /// and only if unaltered.
+    #[test]
+    fn test_seal_renewal() {
+        let renewal = Renewal {
+            lease: Lease::Duration(60),
+            sequence: 3,
+        };
+        let sealed = LeaseRenewal::seal(&renewal, &test_enc_key(0), OsRng);
+        let opened = sealed.open(&test_enc_key(0)).expect("Test failed");
+        assert_eq!(opened.lease, renewal.lease);
+        assert_eq!(opened.sequence, renewal.sequence);
+
+        assert!(matches!(
+            sealed.open(&test_enc_key(1)),
+            Err(LeaseError::WrongOwner)
+        ));
+        let mut claimed = sealed.clone();
+        claimed.owner = test_enc_key(1).hash();
+        assert!(matches!(
+            claimed.open(&test_enc_key(1)),
+            Err(LeaseError::Decryption)
+        ));
+        let mut altered = sealed;
+        altered.payload[0] ^= 1;
+        assert!(matches!(
+            altered.open(&test_enc_key(0)),
+            Err(LeaseError::Decryption)
+        ));
+    }
+}
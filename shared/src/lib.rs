@@ -3,10 +3,17 @@ extern crate alloc;
 #[cfg(feature = "std")]
 extern crate std;
 
+pub mod checkpoint;
 pub mod communication;
+pub mod config;
 pub mod db;
+pub mod lease;
 pub mod ratls;
+pub mod status;
 pub mod tee;
+#[cfg(feature = "rustls")]
+pub mod tls;
+pub mod update;
 
 pub use communication::*;
 pub use db::{Index, IndexList};
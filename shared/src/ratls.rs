@@ -1,22 +1,36 @@
 //! A highly simplified version of RA-TLS. This performs a Diffie-Hellman
 //! key exchange using a hardcoded cryptographic suits as well as remote
-//! attestation. If successful, a single encrypted message containing an
-//! FMD key is sent and the connection is terminated. This means that
-//! we do not need to maintain a list of active sessions or session ids.
+//! attestation. The enclave only keeps a session, named by an id chosen
+//! by the host, until the client acknowledges the handshake.
+//!
+//! The handshake is versioned. In version 1, the Diffie-Hellman output is
+//! the session key and a single encrypted message containing an FMD key
+//! is sent before the connection is terminated. Version 2 derives a key
+//! per direction with HKDF over a hash of the transcript (both public keys,
+//! the client's nonce, the attestation report and the checkpoint key) and
+//! each side sends a key confirmation proving it derived the same keys.
+//! The result is a [`SecureSession`] carrying any number of
+//! [`SessionRequest`]s and their [`SessionReply`]s, numbered so that none
+//! can be replayed, dropped or reordered. Enclaves accept both versions.
 
+use alloc::string::String;
 use alloc::vec::Vec;
 
 use chacha20poly1305::aead::Aead;
 use chacha20poly1305::{AeadCore, ChaCha20Poly1305, Key, KeyInit, Nonce};
 use fmd::DetectionKey;
+use hkdf::Hkdf;
 use rand_core::{CryptoRng, RngCore};
-use serde::de::DeserializeOwned;
+use serde::de::{DeserializeOwned, Error as _};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 use zeroize::Zeroize;
 
+use crate::checkpoint::key_binding;
 use crate::db::EncKey;
-use crate::{ClientMsg, MsgToHost};
+use crate::lease::Lease;
+use crate::{ClientMsg, HexBytes, MsgToHost};
 
 #[derive(Error, Debug)]
 pub enum RatlsError {
@@ -30,8 +44,19 @@ pub enum RatlsError {
     Decryption,
     #[error("Failed to deserialize message with: {0}")]
     Deserialize(serde_cbor::Error),
+    #[error(
+        "The peer's key confirmation did not match. The handshake may have been tampered with."
+    )]
+    KeyConfirmation,
+    #[error("Expected message {expected} of the session, but received another")]
+    OutOfOrder { expected: u64 },
+    #[error("The session has sent all the messages it can")]
+    Exhausted,
 }
 
+/// The length of a ChaCha20Poly1305 nonce
+const NONCE_LEN: usize = 12;
+
 /// A ChaCha20 encrypted payload with nonce
 #[derive(Debug, Clone)]
 pub struct TlsCiphertext {
@@ -39,17 +64,72 @@ pub struct TlsCiphertext {
     nonce: Nonce,
 }
 
+/// The number of subkeys a master FMD secret key has unless
+/// otherwise specified.
+pub const DEFAULT_GAMMA: usize = 20;
+
+/// The parameters of the FMD scheme a detection key was
+/// extracted under.
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize, Zeroize,
+)]
+pub struct FmdParams {
+    /// The number of subkeys of the master secret key
+    pub gamma: usize,
+    /// The degree of the polynomial of the compact secret key
+    pub threshold: usize,
+}
+
+impl Default for FmdParams {
+    fn default() -> Self {
+        Self {
+            gamma: DEFAULT_GAMMA,
+            threshold: 1,
+        }
+    }
+}
+
 /// The data needed to register a user's key with the
 /// Kassandra service.
 #[derive(Deserialize, Serialize, Zeroize)]
 pub struct FmdKeyRegistration {
     /// The secret detection key for FMD
     pub fmd_key: DetectionKey,
+    /// The parameters the detection key was extracted under. Registrations
+    /// predating this field used the defaults.
+    #[serde(default)]
+    pub params: FmdParams,
     /// A symmetric encryption key for storing encrypted results for users
     /// in a transparent database
     pub enc_key: EncKey,
     /// An optional block height to start detecting from
     pub birthday: Option<u64>,
+    /// How long the registration should remain active. If not given,
+    /// the provider's default applies.
+    #[serde(default)]
+    pub lease: Option<Lease>,
+    /// Replace the detection key, birthday and lease of an existing
+    /// registration with the same encryption key. Otherwise, registering
+    /// an existing key again only succeeds if nothing changed.
+    #[serde(default)]
+    pub replace: bool,
+}
+
+impl FmdKeyRegistration {
+    /// The positions of the master key's subkeys that make up the
+    /// detection key. A detection key with `n` subkeys has a false-positive
+    /// rate of 2^-n.
+    pub fn subkey_indices(&self) -> Option<Vec<usize>> {
+        // The detection key does not expose its indices, but does serialize them
+        #[derive(Deserialize)]
+        struct Indices {
+            indices: Vec<usize>,
+        }
+        let bytes = serde_cbor::to_vec(&self.fmd_key).ok()?;
+        serde_cbor::from_slice::<Indices>(&bytes)
+            .ok()
+            .map(|ixs| ixs.indices)
+    }
 }
 
 impl Serialize for TlsCiphertext {
@@ -81,6 +161,9 @@ impl<'de> Deserialize<'de> for TlsCiphertext {
             nonce: Vec<u8>,
         }
         let simplified = SimplifiedCiphertext::deserialize(deserializer)?;
+        if simplified.nonce.len() != NONCE_LEN {
+            return Err(D::Error::custom("Nonce was of wrong size"));
+        }
         Ok(Self {
             payload: simplified.payload,
             nonce: *Nonce::from_slice(&simplified.nonce),
@@ -88,6 +171,80 @@ impl<'de> Deserialize<'de> for TlsCiphertext {
     }
 }
 
+/// The report data an enclave's quote signs over in a handshake: its
+/// public key for the session, the client's challenge nonce and a
+/// binding of its checkpoint key.
+pub fn report_data(pk: &[u8; 32], nonce: u64, checkpoint_key: &HexBytes<32>) -> [u8; 64] {
+    let mut report_data = [0u8; 64];
+    report_data[..32].copy_from_slice(pk);
+    report_data[32..40].copy_from_slice(&nonce.to_le_bytes());
+    report_data[40..].copy_from_slice(&key_binding(checkpoint_key));
+    report_data
+}
+
+/// The version of the bespoke RA-TLS handshake
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HandshakeVersion {
+    /// The Diffie-Hellman output is the session key and is not confirmed.
+    /// Messages predating versioning use this.
+    #[default]
+    V1,
+    /// The session key is bound to the transcript and confirmed by
+    /// both sides.
+    V2,
+}
+
+/// Separates the transcript hash from other uses of SHA-256
+const TRANSCRIPT_DOMAIN: &[u8] = b"kassandra-ratls-v2";
+
+/// The public values of a version 2 handshake, which its session key
+/// is bound to
+pub struct Transcript<'a> {
+    pub client_pk: &'a [u8; 32],
+    pub nonce: u64,
+    pub enclave_pk: &'a [u8; 32],
+    /// The enclave's attestation report
+    pub report: &'a [u8],
+    pub checkpoint_key: &'a HexBytes<32>,
+}
+
+impl Transcript<'_> {
+    pub fn hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(TRANSCRIPT_DOMAIN);
+        hasher.update(self.client_pk);
+        hasher.update(self.nonce.to_le_bytes());
+        hasher.update(self.enclave_pk);
+        hasher.update((self.report.len() as u64).to_le_bytes());
+        hasher.update(self.report);
+        hasher.update(self.checkpoint_key.0);
+        hasher.finalize().into()
+    }
+}
+
+/// The values each side of a version 2 handshake sends to prove
+/// that it derived the same session key
+pub struct KeyConfirmation {
+    pub client: HexBytes<32>,
+    pub enclave: HexBytes<32>,
+}
+
+impl KeyConfirmation {
+    /// Check a confirmation received from the peer in constant time
+    pub fn check(expected: &HexBytes<32>, received: &HexBytes<32>) -> Result<(), RatlsError> {
+        let diff = expected
+            .0
+            .iter()
+            .zip(received.0.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff == 0 {
+            Ok(())
+        } else {
+            Err(RatlsError::KeyConfirmation)
+        }
+    }
+}
+
 /// A simplified, bespoke RA-TLS connection
 /// It can be in two possible states:
 ///
@@ -111,42 +268,113 @@ impl Connection {
         }
     }
 
-    /// The client side sends its ephemeral public key
-    pub fn client_send(&self, nonce: u64) -> Result<ClientMsg, RatlsError> {
+    /// The ephemeral public key of a connection still in its handshake
+    pub fn public_key(&self) -> Result<x25519_dalek::PublicKey, RatlsError> {
         match &self {
-            Self::Handshake { ephemeral_key } => Ok(ClientMsg::RegisterKey {
-                nonce,
-                pk: x25519_dalek::PublicKey::from(ephemeral_key)
-                    .to_bytes()
-                    .into(),
-            }),
+            Self::Handshake { ephemeral_key } => Ok(x25519_dalek::PublicKey::from(ephemeral_key)),
             Self::Initialized { .. } => Err(RatlsError::AlreadyInitialized),
         }
     }
 
+    /// The client side sends its ephemeral public key, requesting
+    /// the latest handshake version
+    pub fn client_send(&self, nonce: u64) -> Result<ClientMsg, RatlsError> {
+        Ok(ClientMsg::RegisterKey {
+            nonce,
+            pk: self.public_key()?.to_bytes().into(),
+            version: HandshakeVersion::V2,
+        })
+    }
+
     /// The enclave replies with its Attestation report, which contains
-    /// its ephemeral public key and a session id.
-    pub fn enclave_reply(&self, report: Vec<u8>) -> Result<MsgToHost, RatlsError> {
+    /// its ephemeral public key and a session id, along with its
+    /// checkpoint key and, from version 2, its key confirmation.
+    pub fn enclave_reply(
+        &self,
+        session_id: u64,
+        report: Vec<u8>,
+        checkpoint_key: HexBytes<32>,
+        confirm: Option<HexBytes<32>>,
+    ) -> Result<MsgToHost, RatlsError> {
         match &self {
-            Self::Handshake { .. } => Ok(MsgToHost::RATLS { report }),
+            Self::Handshake { .. } => Ok(MsgToHost::RATLS {
+                session_id,
+                report,
+                checkpoint_key,
+                confirm,
+            }),
             Self::Initialized { .. } => Err(RatlsError::AlreadyInitialized),
         }
     }
 
-    /// Compute the shared ChaCha20 public key for the connection.
-    pub fn initialize(self, pk: x25519_dalek::PublicKey) -> Result<Self, RatlsError> {
+    /// Perform Diffie-Hellman with the peer's public key
+    fn shared_secret(
+        self,
+        pk: &x25519_dalek::PublicKey,
+    ) -> Result<x25519_dalek::SharedSecret, RatlsError> {
         let Self::Handshake { ephemeral_key } = self else {
             return Err(RatlsError::AlreadyInitialized);
         };
-        let shared_secret = ephemeral_key.diffie_hellman(&pk);
-        let shared_key = if shared_secret.was_contributory() {
-            ChaCha20Poly1305::new(Key::from_slice(shared_secret.as_bytes()))
+        let shared_secret = ephemeral_key.diffie_hellman(pk);
+        if shared_secret.was_contributory() {
+            Ok(shared_secret)
         } else {
-            return Err(RatlsError::NonContributory);
-        };
+            Err(RatlsError::NonContributory)
+        }
+    }
+
+    /// Compute the shared ChaCha20 public key for a version 1 connection.
+    pub fn initialize(self, pk: x25519_dalek::PublicKey) -> Result<Self, RatlsError> {
+        let shared_secret = self.shared_secret(&pk)?;
+        let shared_key = ChaCha20Poly1305::new(Key::from_slice(shared_secret.as_bytes()));
         Ok(Self::Initialized { shared_key })
     }
 
+    /// Derive the keys of a version 2 session from the shared secret and
+    /// the transcript, along with the key confirmations. The role decides
+    /// which of the directional keys is used for sending.
+    pub fn initialize_with_transcript(
+        self,
+        pk: x25519_dalek::PublicKey,
+        transcript: &Transcript,
+        role: Role,
+    ) -> Result<(SecureSession, KeyConfirmation), RatlsError> {
+        let shared_secret = self.shared_secret(&pk)?;
+        let hk = Hkdf::<Sha256>::new(Some(&transcript.hash()), shared_secret.as_bytes());
+        let mut to_enclave = [0u8; 32];
+        let mut to_client = [0u8; 32];
+        let mut client = [0u8; 32];
+        let mut enclave = [0u8; 32];
+        for (label, okm) in [
+            (&b"client to enclave key"[..], &mut to_enclave),
+            (b"enclave to client key", &mut to_client),
+            (b"client confirmation", &mut client),
+            (b"enclave confirmation", &mut enclave),
+        ] {
+            hk.expand(label, okm)
+                .expect("32 bytes is a valid HKDF output length");
+        }
+        let (send, recv) = match role {
+            Role::Client => (&to_enclave, &to_client),
+            Role::Enclave => (&to_client, &to_enclave),
+        };
+        let session = SecureSession {
+            send: ChaCha20Poly1305::new(Key::from_slice(send)),
+            recv: ChaCha20Poly1305::new(Key::from_slice(recv)),
+            sent: 0,
+            received: 0,
+        };
+        to_enclave.zeroize();
+        to_client.zeroize();
+        Ok((
+            session,
+            KeyConfirmation {
+                client: HexBytes(client),
+                enclave: HexBytes(enclave),
+            },
+        ))
+    }
+
     /// Encrypt a message with the session key
     pub fn encrypt_msg<T: CryptoRng + RngCore>(
         &self,
@@ -176,3 +404,201 @@ impl Connection {
         }
     }
 }
+
+/// Which end of a version 2 session a party is
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Enclave,
+}
+
+/// An established version 2 session. Each direction has its own key and
+/// numbers its messages, the number serving as the nonce. A message is
+/// only accepted if it is the next one expected.
+pub struct SecureSession {
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+    /// The number of messages sent
+    sent: u64,
+    /// The number of messages received
+    received: u64,
+}
+
+impl SecureSession {
+    /// The nonce of the message with the given number
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[..8].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+
+    /// Serialize and encrypt the next message to the peer
+    pub fn seal<T: Serialize>(&mut self, msg: &T) -> Result<TlsCiphertext, RatlsError> {
+        if self.sent == u64::MAX {
+            return Err(RatlsError::Exhausted);
+        }
+        let nonce = Self::nonce(self.sent);
+        let mut payload = serde_cbor::to_vec(msg).expect("Serializing to a vector cannot fail");
+        let cipher = self.send.encrypt(&nonce, payload.as_slice()).unwrap();
+        payload.zeroize();
+        self.sent += 1;
+        Ok(TlsCiphertext {
+            payload: cipher,
+            nonce,
+        })
+    }
+
+    /// Decrypt and deserialize the next message from the peer
+    pub fn open<T: DeserializeOwned>(&mut self, msg: &TlsCiphertext) -> Result<T, RatlsError> {
+        let nonce = Self::nonce(self.received);
+        if msg.nonce != nonce {
+            return Err(RatlsError::OutOfOrder {
+                expected: self.received,
+            });
+        }
+        let mut plaintext = self
+            .recv
+            .decrypt(&nonce, &*msg.payload)
+            .or(Err(RatlsError::Decryption))?;
+        self.received += 1;
+        let msg = serde_cbor::from_slice(&plaintext).map_err(RatlsError::Deserialize);
+        plaintext.zeroize();
+        msg
+    }
+}
+
+/// A request from a client to the enclave within a [`SecureSession`]
+#[derive(Deserialize, Serialize)]
+pub enum SessionRequest {
+    /// Register an FMD key
+    Register(FmdKeyRegistration),
+    /// Get the state of a registration made earlier in the session,
+    /// named by its owner
+    Stats { owner: String },
+}
+
+/// The enclave's reply to a [`SessionRequest`]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum SessionReply {
+    Registered(RegistrationReceipt),
+    Stats(RegistrationReceipt),
+    /// The request was rejected. The session remains usable.
+    Error(String),
+}
+
+/// The state of a registration as reported to its owner
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RegistrationReceipt {
+    /// The hash of the registration's encryption key
+    pub owner: String,
+    /// The block height FMD has been performed up to
+    pub synced_to: u64,
+    /// The number of detected indices not yet consumed
+    pub detected: usize,
+    /// The block height the lease runs out at, if bounded by height
+    pub expires_at_height: Option<u64>,
+    /// The time the lease runs out, in seconds since the Unix epoch,
+    /// if bounded by time
+    pub expires_at_time: Option<u64>,
+    /// The sequence number of the last result produced for the
+    /// registration. It is 0 if the registration is new to the enclave
+    /// and otherwise kept when registering again.
+    #[serde(default)]
+    pub sequence: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::OsRng;
+
+    use super::*;
+
+    #[test]
+    fn test_transcript_bound_keys() {
+        let client = Connection::new(OsRng);
+        let enclave = Connection::new(OsRng);
+        let client_pk = client.public_key().unwrap();
+        let enclave_pk = enclave.public_key().unwrap();
+        let checkpoint_key = HexBytes([3; 32]);
+        let transcript = |report| Transcript {
+            client_pk: client_pk.as_bytes(),
+            nonce: 7,
+            enclave_pk: enclave_pk.as_bytes(),
+            report,
+            checkpoint_key: &checkpoint_key,
+        };
+
+        let (mut enclave, enclave_conf) = enclave
+            .initialize_with_transcript(client_pk, &transcript(b"report"), Role::Enclave)
+            .unwrap();
+        let (mut client, client_conf) = client
+            .initialize_with_transcript(enclave_pk, &transcript(b"report"), Role::Client)
+            .unwrap();
+        KeyConfirmation::check(&client_conf.enclave, &enclave_conf.enclave).unwrap();
+        KeyConfirmation::check(&enclave_conf.client, &client_conf.client).unwrap();
+        assert!(KeyConfirmation::check(&client_conf.client, &client_conf.enclave).is_err());
+        let cipher = client.seal(&1u8).unwrap();
+        assert_eq!(enclave.open::<u8>(&cipher).unwrap(), 1);
+
+        // keys are bound to the report each side saw
+        let client = Connection::new(OsRng);
+        let enclave = Connection::new(OsRng);
+        let (client_pk, enclave_pk) = (client.public_key().unwrap(), enclave.public_key().unwrap());
+        let transcript = |report| Transcript {
+            client_pk: client_pk.as_bytes(),
+            nonce: 7,
+            enclave_pk: enclave_pk.as_bytes(),
+            report,
+            checkpoint_key: &checkpoint_key,
+        };
+        let (mut enclave, enclave_conf) = enclave
+            .initialize_with_transcript(client_pk, &transcript(b"report"), Role::Enclave)
+            .unwrap();
+        let (mut client, client_conf) = client
+            .initialize_with_transcript(enclave_pk, &transcript(b"forged"), Role::Client)
+            .unwrap();
+        assert!(KeyConfirmation::check(&client_conf.enclave, &enclave_conf.enclave).is_err());
+        let cipher = client.seal(&1u8).unwrap();
+        assert!(enclave.open::<u8>(&cipher).is_err());
+    }
+
+    #[test]
+    fn test_session_ordering() {
+        let client = Connection::new(OsRng);
+        let enclave = Connection::new(OsRng);
+        let client_pk = client.public_key().unwrap();
+        let enclave_pk = enclave.public_key().unwrap();
+        let checkpoint_key = HexBytes([3; 32]);
+        let transcript = Transcript {
+            client_pk: client_pk.as_bytes(),
+            nonce: 7,
+            enclave_pk: enclave_pk.as_bytes(),
+            report: b"report",
+            checkpoint_key: &checkpoint_key,
+        };
+        let (mut enclave, _) = enclave
+            .initialize_with_transcript(client_pk, &transcript, Role::Enclave)
+            .unwrap();
+        let (mut client, _) = client
+            .initialize_with_transcript(enclave_pk, &transcript, Role::Client)
+            .unwrap();
+
+        // each direction has its own key
+        let first = client.seal(&1u8).unwrap();
+        assert!(client.open::<u8>(&first).is_err());
+        assert_eq!(enclave.open::<u8>(&first).unwrap(), 1);
+        let reply = enclave.seal(&2u8).unwrap();
+        assert_eq!(client.open::<u8>(&reply).unwrap(), 2);
+
+        // replayed and reordered messages are rejected
+        assert!(matches!(
+            enclave.open::<u8>(&first),
+            Err(RatlsError::OutOfOrder { expected: 1 })
+        ));
+        let second = client.seal(&3u8).unwrap();
+        let third = client.seal(&4u8).unwrap();
+        assert!(enclave.open::<u8>(&third).is_err());
+        assert_eq!(enclave.open::<u8>(&second).unwrap(), 3);
+        assert_eq!(enclave.open::<u8>(&third).unwrap(), 4);
+    }
+}
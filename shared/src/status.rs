@@ -0,0 +1,65 @@
+//! Aggregate statistics the enclave reports about itself. Nothing here
+//! reveals more about any single registration than the host already
+//! learns from the blocks it is asked for.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::EnclaveConfig;
+
+/// The runtime state of the enclave
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnclaveStatus {
+    /// The version of the enclave build
+    pub version: String,
+    /// The number of registered keys
+    pub registered_keys: usize,
+    /// The heights registered keys have been synced to, if any
+    /// keys are registered
+    pub synced_to: Option<SyncedTo>,
+    /// The number of RA-TLS sessions awaiting their clients
+    pub pending_sessions: usize,
+    /// The parameters the enclave was configured with
+    pub config: EnclaveConfig,
+}
+
+/// A summary of the heights registered keys have been synced to
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncedTo {
+    pub min: u64,
+    pub median: u64,
+    pub max: u64,
+}
+
+impl SyncedTo {
+    /// Summarize the given heights. Returns `None` if there are none.
+    pub fn from_heights(heights: impl IntoIterator<Item = u64>) -> Option<Self> {
+        let mut heights: Vec<u64> = heights.into_iter().collect();
+        heights.sort_unstable();
+        Some(Self {
+            min: *heights.first()?,
+            median: heights[heights.len() / 2],
+            max: *heights.last()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_synced_to() {
+        assert_eq!(SyncedTo::from_heights([]), None);
+        assert_eq!(
+            SyncedTo::from_heights([7, 3, 20, 5]),
+            Some(SyncedTo {
+                min: 3,
+                median: 7,
+                max: 20,
+            })
+        );
+    }
+}
@@ -1,9 +1,8 @@
 //! Traits to abstract away particular TEE implementations
 
-use alloc::string::ToString;
 use rand_core::{CryptoRng, RngCore};
 
-use crate::{FramedBytes, MsgError, MsgFromHost, MsgToHost};
+use crate::{EnclaveReply, FramedBytes, HostRequest, MsgError};
 
 /// Logic for clients to verify enclave reports and extract data
 /// from them
@@ -28,27 +27,15 @@ pub trait EnclaveComm: FramedBytes {
     /// Instantiate the communication channel
     fn init() -> Self;
 
-    /// Read a message from the host
-    fn read(&mut self) -> Result<MsgFromHost, MsgError> {
+    /// Read a request from the host
+    fn read(&mut self) -> Result<HostRequest, MsgError> {
         let frame = self.get_frame()?;
         frame.deserialize()
     }
 
-    /// Write a message to the host
-    fn write(&mut self, msg: &MsgToHost) {
-        self.write_frame(msg)
-    }
-
-    /// A factory function for writing errors back
-    /// to the host.
-    fn write_err(&mut self, err: &str) {
-        self.write(&MsgToHost::Error(err.to_string()))
-    }
-
-    /// A factory function for writing errors back
-    /// to a client.
-    fn write_client_err(&mut self, err: &str) {
-        self.write(&MsgToHost::ErrorForClient(err.to_string()))
+    /// Write a reply to the host
+    fn write(&mut self, reply: &EnclaveReply) -> Result<(), MsgError> {
+        self.write_frame(reply)
     }
 }
 
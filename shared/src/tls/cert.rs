@@ -0,0 +1,118 @@
+//! Self-signed enclave certificates carrying an attestation quote
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::str::FromStr;
+use core::time::Duration;
+
+use ed25519_dalek::pkcs8::EncodePrivateKey;
+use ed25519_dalek::{Signer, SigningKey};
+use rand_core::{CryptoRng, RngCore};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use x509_cert::certificate::{Certificate, TbsCertificate, Version};
+use x509_cert::der::asn1::{BitString, GeneralizedTime, ObjectIdentifier, OctetString};
+use x509_cert::der::{Decode, Encode};
+use x509_cert::ext::Extension;
+use x509_cert::name::Name;
+use x509_cert::serial_number::SerialNumber;
+use x509_cert::spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned};
+use x509_cert::time::{Time, Validity};
+
+use super::TlsError;
+use crate::HexBytes;
+use crate::ratls::report_data;
+use crate::tee::RemoteAttestation;
+
+/// The OID of the certificate extension holding the attestation quote.
+/// This is the one used by Gramine's RA-TLS.
+pub const QUOTE_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113741.1337.6");
+
+/// The OID of Ed25519 keys and signatures
+const ED25519_OID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+/// Certificates are only used for a single handshake
+const CERT_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+const SUBJECT: &str = "CN=Kassandra enclave";
+
+/// Generate a key and a self-signed certificate whose extension holds
+/// a quote over the key, the client's nonce and the checkpoint key.
+pub(super) fn attested_certificate<RA: RemoteAttestation>(
+    ra: &RA,
+    mut rng: impl CryptoRng + RngCore,
+    nonce: u64,
+    checkpoint_key: &HexBytes<32>,
+    now: u64,
+) -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>), TlsError> {
+    let key = SigningKey::generate(&mut rng);
+    let pk = key.verifying_key().to_bytes();
+    let quote = ra.get_quote(report_data(&pk, nonce, checkpoint_key));
+
+    let algorithm = AlgorithmIdentifierOwned {
+        oid: ED25519_OID,
+        parameters: None,
+    };
+    let name = Name::from_str(SUBJECT)?;
+    let not_before = Duration::from_secs(now);
+    let tbs_certificate = TbsCertificate {
+        version: Version::V3,
+        serial_number: SerialNumber::new(&[1])?,
+        signature: algorithm.clone(),
+        issuer: name.clone(),
+        validity: Validity {
+            not_before: Time::GeneralTime(GeneralizedTime::from_unix_duration(not_before)?),
+            not_after: Time::GeneralTime(GeneralizedTime::from_unix_duration(
+                not_before + CERT_LIFETIME,
+            )?),
+        },
+        subject: name,
+        subject_public_key_info: SubjectPublicKeyInfoOwned {
+            algorithm: algorithm.clone(),
+            subject_public_key: BitString::from_bytes(&pk)?,
+        },
+        issuer_unique_id: None,
+        subject_unique_id: None,
+        extensions: Some(vec![Extension {
+            extn_id: QUOTE_OID,
+            critical: false,
+            extn_value: OctetString::new(quote)?,
+        }]),
+    };
+    let signature = key.sign(&tbs_certificate.to_der()?);
+    let cert = Certificate {
+        tbs_certificate,
+        signature_algorithm: algorithm,
+        signature: BitString::from_bytes(&signature.to_bytes())?,
+    };
+    let key = key
+        .to_pkcs8_der()
+        .map_err(|_| TlsError::Encode("Could not encode the certificate key".into()))?;
+    Ok((
+        CertificateDer::from(cert.to_der()?),
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.as_bytes().to_vec())),
+    ))
+}
+
+/// Get the public key and attestation quote of an enclave certificate
+pub(super) fn parse_certificate(cert: &[u8]) -> Result<([u8; 32], Vec<u8>), TlsError> {
+    let tbs = Certificate::from_der(cert)?.tbs_certificate;
+    let spki = tbs.subject_public_key_info;
+    if spki.algorithm.oid != ED25519_OID {
+        return Err(TlsError::UnsupportedKey);
+    }
+    let pk = spki
+        .subject_public_key
+        .as_bytes()
+        .and_then(|pk| pk.try_into().ok())
+        .ok_or(TlsError::UnsupportedKey)?;
+    let quote = tbs
+        .extensions
+        .iter()
+        .flatten()
+        .find(|ext| ext.extn_id == QUOTE_OID)
+        .ok_or(TlsError::MissingQuote)?
+        .extn_value
+        .as_bytes()
+        .to_vec();
+    Ok((pk, quote))
+}
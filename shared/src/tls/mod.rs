@@ -0,0 +1,415 @@
+//! RA-TLS built on TLS 1.3 with rustls, as a standards-based alternative to
+//! the bespoke handshake in [`crate::ratls`].
+//!
+//! For each session, the enclave generates an Ed25519 key and presents a
+//! self-signed certificate carrying an attestation quote as an extension.
+//! The quote's report data binds the certificate's public key, the client's
+//! challenge nonce and the enclave's checkpoint key, in the same layout as
+//! the bespoke handshake. Clients check the quote in a custom certificate
+//! verifier instead of a chain of trust.
+//!
+//! Neither end owns a socket. TLS records go in and out as bytes so that the
+//! host can relay them inside its usual messages.
+
+mod cert;
+mod verify;
+
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use rand_core::{CryptoRng, RngCore};
+use rustls::client::{Resumption, UnbufferedClientConnection};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{ServerName, UnixTime};
+use rustls::server::UnbufferedServerConnection;
+use rustls::time_provider::TimeProvider;
+use rustls::unbuffered::{
+    ConnectionState, EncodeError, EncryptError, UnbufferedConnectionCommon, UnbufferedStatus,
+};
+use rustls::version::TLS13;
+use rustls::{ClientConfig, ServerConfig};
+use thiserror::Error;
+
+pub use cert::QUOTE_OID;
+pub use verify::AttestationVerifier;
+
+use crate::HexBytes;
+use crate::tee::{EnclaveClient, RemoteAttestation};
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("TLS error: {0}")]
+    Rustls(rustls::Error),
+    #[error("Failed to encode TLS records: {0}")]
+    Encode(String),
+    #[error("Malformed certificate: {0}")]
+    Certificate(x509_cert::der::Error),
+    #[error("Certificate does not have an Ed25519 public key")]
+    UnsupportedKey,
+    #[error("Certificate does not contain an attestation quote")]
+    MissingQuote,
+    #[error("Attestation quote verification failed: {0}")]
+    Attestation(String),
+    #[error("Expected nonce {expected} in the attestation quote, received {received}")]
+    Nonce { expected: u64, received: u64 },
+    #[error("The certificate's public key is not the one attested in its quote")]
+    UnattestedKey,
+    #[error("The TLS handshake has not completed")]
+    HandshakeIncomplete,
+    #[error("The TLS connection reached an unsupported state")]
+    UnexpectedState,
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(err: rustls::Error) -> Self {
+        Self::Rustls(err)
+    }
+}
+
+impl From<x509_cert::der::Error> for TlsError {
+    fn from(err: x509_cert::der::Error) -> Self {
+        Self::Certificate(err)
+    }
+}
+
+/// The name clients give the enclave's server. Certificates are
+/// verified by their attestation, so it is never checked.
+pub const ENCLAVE_SERVER_NAME: &str = "enclave.kassandra";
+
+/// The time in seconds since the Unix epoch, as given by the host
+/// or the client when the connection was made. The enclave has no
+/// clock of its own.
+#[derive(Debug)]
+struct FixedTime(u64);
+
+impl TimeProvider for FixedTime {
+    fn current_time(&self) -> Option<UnixTime> {
+        Some(UnixTime::since_unix_epoch(Duration::from_secs(self.0)))
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Either side of an unbuffered rustls connection
+pub trait UnbufferedConnection {
+    type Data;
+
+    fn process_tls_records<'c, 'i>(
+        &'c mut self,
+        incoming_tls: &'i mut [u8],
+    ) -> UnbufferedStatus<'c, 'i, Self::Data>;
+
+    fn common(&self) -> &UnbufferedConnectionCommon<Self::Data>;
+}
+
+macro_rules! impl_unbuffered {
+    ($conn:ty, $data:ty) => {
+        impl UnbufferedConnection for $conn {
+            type Data = $data;
+
+            fn process_tls_records<'c, 'i>(
+                &'c mut self,
+                incoming_tls: &'i mut [u8],
+            ) -> UnbufferedStatus<'c, 'i, Self::Data> {
+                UnbufferedConnectionCommon::<$data>::process_tls_records(self, incoming_tls)
+            }
+
+            fn common(&self) -> &UnbufferedConnectionCommon<Self::Data> {
+                self
+            }
+        }
+    };
+}
+
+impl_unbuffered!(
+    UnbufferedServerConnection,
+    rustls::server::ServerConnectionData
+);
+impl_unbuffered!(
+    UnbufferedClientConnection,
+    rustls::client::ClientConnectionData
+);
+
+/// The outcome of passing records received from the peer to a
+/// [`TlsEndpoint`]
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// TLS records to be sent to the peer
+    pub records: Vec<u8>,
+    /// Decrypted application data
+    pub plaintext: Vec<u8>,
+    /// Whether the handshake has completed
+    pub established: bool,
+}
+
+/// One end of an RA-TLS connection. Records from the peer are
+/// buffered until they can be processed.
+pub struct TlsEndpoint<C> {
+    conn: C,
+    incoming: Vec<u8>,
+}
+
+/// The enclave's end of an RA-TLS connection
+pub type TlsServer = TlsEndpoint<UnbufferedServerConnection>;
+
+/// The client's end of an RA-TLS connection
+pub type TlsClient = TlsEndpoint<UnbufferedClientConnection>;
+
+impl<C: UnbufferedConnection> TlsEndpoint<C> {
+    fn new(conn: C) -> Self {
+        Self {
+            conn,
+            incoming: vec![],
+        }
+    }
+
+    /// Process records received from the peer. This returns the records
+    /// to send back, if any, and the application data received.
+    pub fn process(&mut self, records: &[u8]) -> Result<Progress, TlsError> {
+        self.incoming.extend_from_slice(records);
+        let mut progress = Progress::default();
+        loop {
+            let UnbufferedStatus { mut discard, state } =
+                self.conn.process_tls_records(&mut self.incoming);
+            let done = match state? {
+                ConnectionState::ReadTraffic(mut traffic) => {
+                    while let Some(record) = traffic.next_record() {
+                        let record = record?;
+                        discard += record.discard;
+                        progress.plaintext.extend_from_slice(record.payload);
+                    }
+                    false
+                }
+                ConnectionState::EncodeTlsData(mut data) => {
+                    let start = progress.records.len();
+                    match data.encode(&mut []) {
+                        Ok(_) => {}
+                        Err(EncodeError::InsufficientSize(err)) => {
+                            progress.records.resize(start + err.required_size, 0);
+                            data.encode(&mut progress.records[start..])
+                                .map_err(|e| TlsError::Encode(e.to_string()))?;
+                        }
+                        Err(e) => return Err(TlsError::Encode(e.to_string())),
+                    }
+                    false
+                }
+                // The caller transmits the records of `progress`
+                ConnectionState::TransmitTlsData(data) => {
+                    data.done();
+                    false
+                }
+                ConnectionState::BlockedHandshake => true,
+                ConnectionState::WriteTraffic(_) => {
+                    progress.established = true;
+                    true
+                }
+                ConnectionState::PeerClosed => false,
+                ConnectionState::Closed => true,
+                _ => return Err(TlsError::UnexpectedState),
+            };
+            self.incoming.drain(..discard);
+            if done {
+                return Ok(progress);
+            }
+        }
+    }
+
+    /// Encrypt application data into records for the peer. The handshake
+    /// must have completed.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, TlsError> {
+        let UnbufferedStatus { state, .. } = self.conn.process_tls_records(&mut []);
+        let ConnectionState::WriteTraffic(mut traffic) = state? else {
+            return Err(TlsError::HandshakeIncomplete);
+        };
+        let required = match traffic.encrypt(plaintext, &mut []) {
+            Ok(_) => return Ok(vec![]),
+            Err(EncryptError::InsufficientSize(err)) => err.required_size,
+            Err(e) => return Err(TlsError::Encode(e.to_string())),
+        };
+        let mut records = vec![0; required];
+        let len = traffic
+            .encrypt(plaintext, &mut records)
+            .map_err(|e| TlsError::Encode(e.to_string()))?;
+        records.truncate(len);
+        Ok(records)
+    }
+}
+
+impl TlsServer {
+    /// Start the enclave's end of a handshake. It presents a new certificate
+    /// whose quote binds the client's nonce and the checkpoint key. The
+    /// current time is in seconds since the Unix epoch.
+    pub fn new_enclave<RA: RemoteAttestation>(
+        ra: &RA,
+        rng: impl CryptoRng + RngCore,
+        nonce: u64,
+        checkpoint_key: &HexBytes<32>,
+        now: u64,
+    ) -> Result<Self, TlsError> {
+        let (cert, key) = cert::attested_certificate(ra, rng, nonce, checkpoint_key, now)?;
+        let mut config = ServerConfig::builder_with_details(provider(), Arc::new(FixedTime(now)))
+            .with_protocol_versions(&[&TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)?;
+        // sessions are never resumed
+        config.send_tls13_tickets = 0;
+        Ok(Self::new(UnbufferedServerConnection::new(Arc::new(
+            config,
+        ))?))
+    }
+}
+
+impl TlsClient {
+    /// Start the client's end of a handshake, accepting only a certificate
+    /// with a quote verified by `C` and containing the nonce. Returns the
+    /// client and its first records. The current time is in seconds since
+    /// the Unix epoch.
+    pub fn new_client<C: EnclaveClient + 'static>(
+        nonce: u64,
+        now: u64,
+    ) -> Result<(Self, Vec<u8>), TlsError> {
+        let provider = provider();
+        let verifier = AttestationVerifier::<C>::new(nonce, &provider);
+        let mut config = ClientConfig::builder_with_details(provider, Arc::new(FixedTime(now)))
+            .with_protocol_versions(&[&TLS13])?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        config.resumption = Resumption::disabled();
+        let name = ServerName::try_from(ENCLAVE_SERVER_NAME).expect("The server name is valid");
+        let mut client = Self::new(UnbufferedClientConnection::new(Arc::new(config), name)?);
+        let hello = client.process(&[])?;
+        Ok((client, hello.records))
+    }
+
+    /// The report data of the enclave's attestation, once the handshake
+    /// has completed.
+    pub fn report_data<C: EnclaveClient>(&self, nonce: u64) -> Result<[u8; 64], TlsError> {
+        let cert = self
+            .conn
+            .common()
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .ok_or(TlsError::HandshakeIncomplete)?;
+        verify::verify_certificate::<C>(cert, nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use rand_core::OsRng;
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::checkpoint::verify_key_binding;
+
+    /// Attestation that signs nothing, as in the transparent backend
+    #[derive(Clone)]
+    struct Transparent;
+
+    impl RemoteAttestation for Transparent {
+        fn init() -> Self {
+            Self
+        }
+
+        fn get_quote(&self, report_data: [u8; 64]) -> Vec<u8> {
+            report_data.to_vec()
+        }
+    }
+
+    struct TClient;
+
+    impl EnclaveClient for TClient {
+        type Error = TlsError;
+
+        fn verify_quote(report: &[u8], _: u64) -> Result<[u8; 64], Self::Error> {
+            report
+                .try_into()
+                .map_err(|_| TlsError::Attestation("User data was not 64 bytes".into()))
+        }
+    }
+
+    /// A client which rejects every quote
+    struct Untrusting;
+
+    impl EnclaveClient for Untrusting {
+        type Error = TlsError;
+
+        fn verify_quote(_: &[u8], _: u64) -> Result<[u8; 64], Self::Error> {
+            Err(TlsError::Attestation("Unknown measurement".into()))
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Msg(String);
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn test_handshake() {
+        let checkpoint_key = HexBytes(
+            ed25519_dalek::SigningKey::generate(&mut OsRng)
+                .verifying_key()
+                .to_bytes(),
+        );
+        let (mut client, hello) = TlsClient::new_client::<TClient>(7, NOW).expect("Test failed");
+        let mut server = TlsServer::new_enclave(&Transparent, OsRng, 7, &checkpoint_key, NOW)
+            .expect("Test failed");
+
+        // ServerHello up to the server's Finished
+        let flight = server.process(&hello).expect("Test failed");
+        assert!(!flight.established);
+        assert!(!flight.records.is_empty());
+
+        // the client's Finished followed by an encrypted message
+        let progress = client.process(&flight.records).expect("Test failed");
+        assert!(progress.established);
+        let report_data = client.report_data::<TClient>(7).expect("Test failed");
+        verify_key_binding(&checkpoint_key, &report_data).expect("Test failed");
+        let mut records = progress.records;
+        let msg = Msg("registration".into());
+        records.extend(
+            client
+                .encrypt(&serde_cbor::to_vec(&msg).unwrap())
+                .expect("Test failed"),
+        );
+
+        let progress = server.process(&records).expect("Test failed");
+        assert!(progress.established);
+        let received: Msg = serde_cbor::from_slice(&progress.plaintext).expect("Test failed");
+        assert_eq!(received, msg);
+
+        // and back again
+        let reply = server.encrypt(b"receipt").expect("Test failed");
+        let progress = client.process(&reply).expect("Test failed");
+        assert_eq!(progress.plaintext, b"receipt");
+    }
+
+    #[test]
+    fn test_rejected_attestation() {
+        let checkpoint_key = HexBytes([1; 32]);
+        let mut server = TlsServer::new_enclave(&Transparent, OsRng, 7, &checkpoint_key, NOW)
+            .expect("Test failed");
+
+        // the wrong nonce
+        let (mut client, hello) = TlsClient::new_client::<TClient>(8, NOW).expect("Test failed");
+        let flight = server.process(&hello).expect("Test failed");
+        let err = client.process(&flight.records).unwrap_err();
+        assert!(err.to_string().contains("nonce"), "{err}");
+
+        // a quote the client does not trust
+        let mut server = TlsServer::new_enclave(&Transparent, OsRng, 7, &checkpoint_key, NOW)
+            .expect("Test failed");
+        let (mut client, hello) = TlsClient::new_client::<Untrusting>(7, NOW).expect("Test failed");
+        let flight = server.process(&hello).expect("Test failed");
+        assert!(client.process(&flight.records).is_err());
+        assert!(client.encrypt(b"registration").is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! Verifying enclave certificates by their attestation
+
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::marker::PhantomData;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, WebPkiSupportedAlgorithms, verify_tls13_signature};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+
+use super::TlsError;
+use super::cert::parse_certificate;
+use crate::tee::EnclaveClient;
+
+/// Accepts an enclave's certificate if it carries a quote verified by
+/// `C` which attests the certificate's key and the client's nonce. There
+/// is no chain of trust to check.
+pub struct AttestationVerifier<C> {
+    nonce: u64,
+    algorithms: WebPkiSupportedAlgorithms,
+    _client: PhantomData<fn() -> C>,
+}
+
+impl<C> AttestationVerifier<C> {
+    pub fn new(nonce: u64, provider: &Arc<CryptoProvider>) -> Self {
+        Self {
+            nonce,
+            algorithms: provider.signature_verification_algorithms,
+            _client: PhantomData,
+        }
+    }
+}
+
+impl<C> fmt::Debug for AttestationVerifier<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AttestationVerifier")
+            .field("nonce", &self.nonce)
+            .finish()
+    }
+}
+
+/// Verify the quote of an enclave certificate and return its report data
+pub(super) fn verify_certificate<C: EnclaveClient>(
+    cert: &CertificateDer<'_>,
+    nonce: u64,
+) -> Result<[u8; 64], TlsError> {
+    let (pk, quote) = parse_certificate(cert)?;
+    let report_data =
+        C::verify_quote(&quote, nonce).map_err(|e| TlsError::Attestation(e.to_string()))?;
+    let received = u64::from_le_bytes(report_data[32..40].try_into().unwrap());
+    if received != nonce {
+        return Err(TlsError::Nonce {
+            expected: nonce,
+            received,
+        });
+    }
+    if report_data[..32] != pk {
+        return Err(TlsError::UnattestedKey);
+    }
+    Ok(report_data)
+}
+
+impl<C: EnclaveClient> ServerCertVerifier for AttestationVerifier<C> {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
+        _: &[u8],
+        _: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        verify_certificate::<C>(end_entity, self.nonce)
+            .map(|_| ServerCertVerified::assertion())
+            .map_err(|e| rustls::Error::General(e.to_string()))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _: &[u8],
+        _: &CertificateDer<'_>,
+        _: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Err(rustls::Error::General(
+            "Only TLS 1.3 is supported".to_string(),
+        ))
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
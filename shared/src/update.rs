@@ -0,0 +1,80 @@
+//! Changing an active registration without registering it anew. Updates
+//! are sealed with the encryption key of the registration they change,
+//! which both authenticates them and keeps any replacement keys they
+//! carry confidential.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use fmd::DetectionKey;
+use rand_core::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::db::EncKey;
+use crate::ratls::FmdParams;
+
+/// Associated data separating updates from anything else encrypted
+/// under a user's encryption key.
+const UPDATE_AAD: &[u8] = b"Kassandra key update";
+
+#[derive(Error, Debug)]
+pub enum UpdateError {
+    #[error("Key update is not for the given key")]
+    WrongOwner,
+    #[error("Could not authenticate key update")]
+    Decryption,
+    #[error("Failed to deserialize key update with: {0}")]
+    Deserialize(serde_cbor::Error),
+}
+
+/// The authenticated contents of a key update
+#[derive(Serialize, Deserialize)]
+pub struct Update {
+    /// An encryption key to replace the current one
+    pub enc_key: Option<EncKey>,
+    /// A detection key to replace the current one, e.g. to change
+    /// the false-positive rate, along with its parameters
+    pub detection_key: Option<(DetectionKey, FmdParams)>,
+    /// Discard the existing index set and detect again from the
+    /// registration's birthday
+    pub reset: bool,
+    /// Must increase with every update or lease renewal so that old
+    /// updates cannot be replayed.
+    pub sequence: u64,
+}
+
+/// A key update as sent over the wire
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyUpdate {
+    /// Hash of the encryption key of the registration to update
+    pub owner: String,
+    pub nonce: [u8; 12],
+    /// The encrypted [`Update`]
+    pub payload: Vec<u8>,
+}
+
+impl KeyUpdate {
+    /// Seal an update with the registration's current encryption key
+    pub fn seal<R: CryptoRng + RngCore>(update: &Update, enc_key: &EncKey, rng: R) -> Self {
+        let msg = serde_cbor::to_vec(update).unwrap();
+        let (nonce, payload) = enc_key.seal(&msg, UPDATE_AAD, rng);
+        Self {
+            owner: enc_key.hash(),
+            nonce,
+            payload,
+        }
+    }
+
+    /// Check that an update was produced by the holder of the
+    /// encryption key and return its contents.
+    pub fn open(&self, enc_key: &EncKey) -> Result<Update, UpdateError> {
+        if self.owner != enc_key.hash() {
+            return Err(UpdateError::WrongOwner);
+        }
+        let msg = enc_key
+            .open(&self.nonce, &self.payload, UPDATE_AAD)
+            .ok_or(UpdateError::Decryption)?;
+        serde_cbor::from_slice(&msg).map_err(UpdateError::Deserialize)
+    }
+}
@@ -3,7 +3,9 @@ use alloc::vec;
 
 use ostd::arch::x86::device::serial::SerialPort;
 use ostd::sync::Mutex;
-use shared::{Frame, FramedBytes, MsgError, MsgFromHost, MsgToHost, ReadWriteByte};
+use shared::{
+    ENCLAVE_MAX_FRAME_SIZE, EnclaveReply, Frame, FramedBytes, HostRequest, MsgError, ReadWriteByte,
+};
 use shared::tee::EnclaveComm;
 
 /// A serial port for communicating with the host.
@@ -60,12 +62,15 @@ impl HostCom {
 }
 
 impl ReadWriteByte for HostCom {
-    fn read_byte(&mut self) -> u8 {
-        Self::read_byte()
+    const MAX_FRAME_SIZE: usize = ENCLAVE_MAX_FRAME_SIZE;
+
+    fn read_byte(&mut self) -> Result<u8, MsgError> {
+        Ok(Self::read_byte())
     }
 
-    fn write_bytes(&mut self, buf: &[u8]) {
-        Self::write_bytes(buf)
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<(), MsgError> {
+        Self::write_bytes(buf);
+        Ok(())
     }
 }
 
@@ -75,13 +80,13 @@ impl EnclaveComm for HostCom {
         Self
     }
 
-    fn read(&mut self) -> Result<MsgFromHost, MsgError> {
+    fn read(&mut self) -> Result<HostRequest, MsgError> {
         let frame = Self::get_frame()?;
         frame.deserialize()
     }
 
-    fn write(&mut self, msg: &MsgToHost) {
+    fn write(&mut self, reply: &EnclaveReply) -> Result<(), MsgError> {
         let mut com = Self;
-        com.write_frame(&msg);
+        com.write_frame(reply)
     }
 }